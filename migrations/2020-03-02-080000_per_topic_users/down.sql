@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS user_transactions;
+
+DELETE FROM users a USING users b
+WHERE a.user_address = b.user_address AND a.updated_at < b.updated_at;
+ALTER TABLE users DROP CONSTRAINT users_pkey;
+ALTER TABLE users ADD PRIMARY KEY (user_address);
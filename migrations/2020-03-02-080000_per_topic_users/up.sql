@@ -0,0 +1,16 @@
+ALTER TABLE users DROP CONSTRAINT users_pkey;
+ALTER TABLE users ADD PRIMARY KEY (topic, user_address);
+
+CREATE TABLE user_transactions (
+    id SERIAL PRIMARY KEY,
+    topic VARCHAR NOT NULL,
+    user_address CHAR(40) NOT NULL,
+    status CHAR(10) NOT NULL,
+    tx_id CHAR(64) NOT NULL,
+    created_at timestamp NOT NULL default current_timestamp,
+    UNIQUE (topic, user_address, tx_id)
+);
+CREATE INDEX idx_user_transactions_topic_user_address ON user_transactions(topic, user_address);
+
+INSERT INTO user_transactions (topic, user_address, status, tx_id, created_at)
+SELECT topic, user_address, status, tx_id, updated_at FROM users;
@@ -0,0 +1,8 @@
+CREATE TABLE pending_transactions (
+    trx_id VARCHAR NOT NULL PRIMARY KEY,
+    block_num bigint NOT NULL,
+    topic VARCHAR NOT NULL,
+    data VARCHAR NOT NULL,
+    created_at timestamp NOT NULL default current_timestamp
+);
+CREATE INDEX idx_pending_transactions_topic_block_num ON pending_transactions(topic, block_num);
@@ -0,0 +1,2 @@
+ALTER TABLE posts ADD COLUMN uris VARCHAR NOT NULL DEFAULT '';
+UPDATE posts SET uris = json_build_array(url)::text;
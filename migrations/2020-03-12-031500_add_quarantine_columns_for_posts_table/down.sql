@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_posts_quarantined;
+
+ALTER TABLE posts DROP COLUMN IF EXISTS quarantined_at;
+ALTER TABLE posts DROP COLUMN IF EXISTS observed_hash;
+ALTER TABLE posts DROP COLUMN IF EXISTS quarantined;
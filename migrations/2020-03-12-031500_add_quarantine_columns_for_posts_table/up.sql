@@ -0,0 +1,5 @@
+ALTER TABLE posts ADD COLUMN quarantined BOOLEAN NOT NULL DEFAULT 'f';
+ALTER TABLE posts ADD COLUMN observed_hash VARCHAR;
+ALTER TABLE posts ADD COLUMN quarantined_at timestamp;
+
+CREATE INDEX idx_posts_quarantined ON posts(quarantined);
@@ -0,0 +1,6 @@
+DROP TABLE webhook_logs;
+
+DROP INDEX IF EXISTS idx_notifies_dead;
+DROP INDEX IF EXISTS idx_notifies_next_attempt_at;
+ALTER TABLE notifies DROP COLUMN IF EXISTS dead;
+ALTER TABLE notifies DROP COLUMN IF EXISTS next_attempt_at;
@@ -0,0 +1,18 @@
+ALTER TABLE notifies ADD COLUMN next_attempt_at timestamp;
+ALTER TABLE notifies ADD COLUMN dead BOOLEAN NOT NULL DEFAULT 'f';
+CREATE INDEX idx_notifies_next_attempt_at ON notifies(next_attempt_at);
+CREATE INDEX idx_notifies_dead ON notifies(dead);
+
+CREATE TABLE webhook_logs (
+    id SERIAL PRIMARY KEY,
+    data_id VARCHAR NOT NULL,
+    topic VARCHAR NOT NULL,
+    url VARCHAR NOT NULL,
+    attempt int NOT NULL,
+    status_code int,
+    response_body TEXT NOT NULL DEFAULT '',
+    error TEXT,
+    duration_ms bigint NOT NULL DEFAULT 0,
+    created_at timestamp NOT NULL default current_timestamp
+);
+CREATE INDEX idx_webhook_logs_data_id ON webhook_logs(data_id);
@@ -0,0 +1,10 @@
+DROP INDEX IF EXISTS idx_webhook_logs_notify_id;
+ALTER TABLE webhook_logs DROP COLUMN IF EXISTS notify_id;
+
+DELETE FROM notifies WHERE event <> 'post_published';
+ALTER TABLE notifies DROP CONSTRAINT unique_notifies_data_id_event;
+ALTER TABLE notifies DROP COLUMN IF EXISTS extra;
+ALTER TABLE notifies DROP COLUMN IF EXISTS event;
+ALTER TABLE notifies DROP COLUMN IF EXISTS id;
+ALTER TABLE notifies ADD CONSTRAINT unique_notifies_trx_id UNIQUE (trx_id);
+ALTER TABLE notifies ADD PRIMARY KEY (data_id);
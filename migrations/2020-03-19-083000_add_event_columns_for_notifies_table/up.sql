@@ -0,0 +1,10 @@
+ALTER TABLE notifies DROP CONSTRAINT notifies_pkey;
+ALTER TABLE notifies DROP CONSTRAINT unique_notifies_trx_id;
+ALTER TABLE notifies ADD COLUMN id SERIAL PRIMARY KEY;
+ALTER TABLE notifies ADD COLUMN event VARCHAR NOT NULL DEFAULT 'post_published';
+ALTER TABLE notifies ADD COLUMN extra VARCHAR NOT NULL DEFAULT '{}';
+ALTER TABLE notifies ADD CONSTRAINT unique_notifies_data_id_event UNIQUE (data_id, event);
+
+ALTER TABLE webhook_logs ADD COLUMN notify_id int NOT NULL DEFAULT 0;
+UPDATE webhook_logs SET notify_id = notifies.id FROM notifies WHERE notifies.data_id = webhook_logs.data_id;
+CREATE INDEX idx_webhook_logs_notify_id ON webhook_logs(notify_id);
@@ -0,0 +1,4 @@
+DELETE FROM notifies WHERE subscription <> 'default';
+ALTER TABLE notifies DROP CONSTRAINT unique_notifies_data_id_event_subscription;
+ALTER TABLE notifies ADD CONSTRAINT unique_notifies_data_id_event UNIQUE (data_id, event);
+ALTER TABLE notifies DROP COLUMN IF EXISTS subscription;
@@ -0,0 +1,3 @@
+ALTER TABLE notifies ADD COLUMN subscription VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE notifies DROP CONSTRAINT unique_notifies_data_id_event;
+ALTER TABLE notifies ADD CONSTRAINT unique_notifies_data_id_event_subscription UNIQUE (data_id, event, subscription);
@@ -0,0 +1,18 @@
+CREATE TABLE post_meta (
+    file_hash CHAR(64) PRIMARY KEY,
+    title VARCHAR NOT NULL DEFAULT '',
+    author VARCHAR NOT NULL DEFAULT '',
+    avatar VARCHAR NOT NULL DEFAULT '',
+    published_at timestamp,
+    tags TEXT[] NOT NULL DEFAULT '{}',
+    summary TEXT NOT NULL DEFAULT '',
+    cover VARCHAR NOT NULL DEFAULT '',
+    lang VARCHAR NOT NULL DEFAULT '',
+    extra TEXT NOT NULL DEFAULT '{}',
+    parse_error TEXT,
+    created_at timestamp NOT NULL default current_timestamp,
+    updated_at timestamp NOT NULL default current_timestamp
+);
+CREATE INDEX idx_post_meta_author ON post_meta(author);
+CREATE INDEX idx_post_meta_published_at ON post_meta(published_at);
+CREATE INDEX idx_post_meta_tags ON post_meta USING GIN(tags);
@@ -0,0 +1,10 @@
+UPDATE contents SET deleted = true
+FROM posts
+WHERE posts.file_hash = contents.file_hash
+AND posts.superseded_by IS NOT NULL;
+
+UPDATE posts SET deleted = true WHERE superseded_by IS NOT NULL;
+
+DROP INDEX IF EXISTS idx_posts_updated_tx_id;
+ALTER TABLE posts DROP COLUMN superseded_at;
+ALTER TABLE posts DROP COLUMN superseded_by;
@@ -0,0 +1,18 @@
+ALTER TABLE posts ADD COLUMN superseded_by CHAR(64);
+ALTER TABLE posts ADD COLUMN superseded_at timestamp;
+CREATE INDEX idx_posts_updated_tx_id ON posts(updated_tx_id);
+
+-- updates used to mark the replaced post and its content deleted, keep them
+-- as revisions instead
+UPDATE posts SET superseded_by = newer.publish_tx_id, superseded_at = newer.updated_at, deleted = false
+FROM posts newer
+WHERE newer.updated_tx_id = posts.publish_tx_id
+AND newer.user_address = posts.user_address
+AND newer.topic = posts.topic
+AND newer.fetched = true
+AND posts.deleted = true;
+
+UPDATE contents SET deleted = false
+FROM posts
+WHERE posts.file_hash = contents.file_hash
+AND posts.superseded_by IS NOT NULL;
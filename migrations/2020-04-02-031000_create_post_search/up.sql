@@ -0,0 +1,17 @@
+-- kept out of src/db/schema.rs by diesel.toml, diesel has no tsvector type
+CREATE TABLE post_search (
+    file_hash CHAR(64) NOT NULL PRIMARY KEY,
+    search_config VARCHAR NOT NULL,
+    search_vector tsvector NOT NULL,
+    updated_at timestamp NOT NULL default current_timestamp
+);
+CREATE INDEX idx_post_search_search_vector ON post_search USING GIN (search_vector);
+
+INSERT INTO post_search (file_hash, search_config, search_vector)
+SELECT post_meta.file_hash, 'simple',
+    setweight(to_tsvector('simple', post_meta.title), 'A')
+    || setweight(to_tsvector('simple', post_meta.author || ' ' || array_to_string(post_meta.tags, ' ')), 'B')
+    || setweight(to_tsvector('simple', post_meta.summary), 'C')
+    || setweight(to_tsvector('simple', contents.content), 'D')
+FROM post_meta
+JOIN contents ON contents.file_hash = post_meta.file_hash;
@@ -0,0 +1,8 @@
+CREATE TABLE topic_workers (
+    topic VARCHAR PRIMARY KEY,
+    state VARCHAR NOT NULL,
+    block_num BIGINT,
+    last_synced_at timestamp,
+    last_error TEXT,
+    updated_at timestamp NOT NULL default current_timestamp
+);
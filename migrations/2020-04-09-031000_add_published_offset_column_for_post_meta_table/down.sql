@@ -0,0 +1 @@
+ALTER TABLE post_meta DROP COLUMN published_offset;
@@ -0,0 +1,2 @@
+-- seconds east of UTC of the frontmatter `published`, published_at is in UTC
+ALTER TABLE post_meta ADD COLUMN published_offset INTEGER;
@@ -1,28 +1,43 @@
 extern crate chrono;
 
-use chrono::prelude::Utc;
-use diesel::pg::PgConnection;
+use chrono::prelude::{NaiveDateTime, Utc};
+use diesel::deserialize::QueryableByName;
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
 use std::time::Duration;
 
 pub mod models;
 pub mod schema;
 use super::prs;
+use crate::frontmatter::MarkdownAttrs;
+use crate::metrics;
+use crate::settings::Settings;
 
 use self::models::{Content, NewContent};
 use self::models::{LastStatus, NewLastStatus};
 use self::models::{NewNotify, Notify, NotifyPartial};
+use self::models::{NewPendingTrx, PendingTrx};
 use self::models::{NewPost, Post, PostJson, PostPartial};
+use self::models::{NewPostMeta, PostMeta, SearchResult};
+use self::models::{NewTopicWorker, TopicWorker};
 use self::models::{NewTrx, Trx};
-use self::models::{NewUser, User, UserList};
+use self::models::{NewUser, NewUserTransaction, User, UserList, UserTransaction};
+use self::models::{NewWebhookLog, WebhookLog};
 use super::SETTINGS;
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 pub type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
 
-fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
-    let pool_size = 2; // FIXME: hardcode
+// connections of a pool when atom.db_pool_size is not set
+const DEFAULT_POOL_SIZE: u32 = 2;
+
+pub fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
+    init_pool_with_size(database_url, DEFAULT_POOL_SIZE)
+}
+
+pub fn init_pool_with_size(database_url: &str, pool_size: u32) -> Result<PgPool, PoolError> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     Pool::builder()
         .connection_timeout(Duration::from_millis(10 * 1000))
@@ -32,7 +47,80 @@ fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
 }
 
 pub fn establish_connection_pool() -> PgPool {
-    init_pool(&SETTINGS.atom.db_url).expect("create database pool failed")
+    establish_connection_pool_with_size(get_pool_size())
+}
+
+/// A pool with a connection for each of `pool_size` worker threads.
+pub fn establish_connection_pool_with_size(pool_size: u32) -> PgPool {
+    init_pool_with_size(&SETTINGS.get().atom.db_url, std::cmp::max(pool_size, 1))
+        .expect("create database pool failed")
+}
+
+fn get_pool_size() -> u32 {
+    SETTINGS
+        .get()
+        .atom
+        .db_pool_size
+        .unwrap_or(DEFAULT_POOL_SIZE)
+}
+
+/// A pool of `atom.db_pool_size` connections for a long running loop, built
+/// again with the new size or `db_url` after the settings are reloaded. Its
+/// usage is reported in the metrics as `name`.
+pub struct ConfiguredPool {
+    name: String,
+    size_of: fn(&Settings) -> u32,
+    db_url: String,
+    size: u32,
+    pool: PgPool,
+}
+
+impl ConfiguredPool {
+    pub fn from_settings(name: &str) -> ConfiguredPool {
+        ConfiguredPool::with_size(name, |settings| {
+            settings.atom.db_pool_size.unwrap_or(DEFAULT_POOL_SIZE)
+        })
+    }
+
+    /// A pool whose size is read from the settings by `size_of`.
+    pub fn with_size(name: &str, size_of: fn(&Settings) -> u32) -> ConfiguredPool {
+        let settings = SETTINGS.get();
+        let size = std::cmp::max(size_of(&settings), 1);
+        let pool =
+            init_pool_with_size(&settings.atom.db_url, size).expect("create database pool failed");
+        metrics::register_pool(name, &pool);
+        ConfiguredPool {
+            name: name.to_string(),
+            size_of,
+            db_url: settings.atom.db_url.clone(),
+            size,
+            pool,
+        }
+    }
+
+    pub fn pool(&mut self) -> &PgPool {
+        let settings = SETTINGS.get();
+        let size = std::cmp::max((self.size_of)(&settings), 1);
+        if size != self.size || settings.atom.db_url != self.db_url {
+            match init_pool_with_size(&settings.atom.db_url, size) {
+                Ok(pool) => {
+                    info!(
+                        "database pool {} rebuilt, size changed from {} to {}",
+                        self.name, self.size, size
+                    );
+                    metrics::register_pool(&self.name, &pool);
+                    self.db_url = settings.atom.db_url.clone();
+                    self.size = size;
+                    self.pool = pool;
+                }
+                Err(e) => error!(
+                    "create database pool {} of size = {} failed: {}",
+                    self.name, size, e
+                ),
+            }
+        }
+        &self.pool
+    }
 }
 
 pub fn save_user<'a>(
@@ -43,7 +131,7 @@ pub fn save_user<'a>(
     topic: &'a str,
     updated_at: chrono::NaiveDateTime,
 ) -> Result<User, diesel::result::Error> {
-    use schema::users;
+    use schema::{user_transactions, users};
 
     let new_user = NewUser {
         user_address,
@@ -52,13 +140,72 @@ pub fn save_user<'a>(
         updated_at,
         topic,
     };
+    let new_user_trx = NewUserTransaction {
+        topic,
+        user_address,
+        status,
+        tx_id,
+        created_at: updated_at,
+    };
 
-    diesel::insert_into(users::table)
-        .values(&new_user)
-        .on_conflict(users::user_address)
-        .do_update()
-        .set(&new_user)
-        .get_result(conn)
+    // keep the allow/deny history and the per-topic state in step, a replayed
+    // transaction corrects the time of its history row
+    conn.transaction(|| {
+        diesel::insert_into(user_transactions::table)
+            .values(&new_user_trx)
+            .on_conflict((
+                user_transactions::topic,
+                user_transactions::user_address,
+                user_transactions::tx_id,
+            ))
+            .do_update()
+            .set(user_transactions::created_at.eq(updated_at))
+            .execute(conn)?;
+
+        diesel::insert_into(users::table)
+            .values(&new_user)
+            .on_conflict((users::topic, users::user_address))
+            .do_update()
+            .set(&new_user)
+            .get_result(conn)
+    })
+}
+
+pub fn get_user(
+    conn: &PgConnection,
+    topic: &str,
+    user_address: &str,
+) -> Result<User, diesel::result::Error> {
+    use schema::users;
+
+    users::table.find((topic, user_address)).first::<User>(conn)
+}
+
+/// The status of `user_address` in every topic.
+pub fn get_users_by_address(
+    conn: &PgConnection,
+    user_address: &str,
+) -> Result<Vec<User>, diesel::result::Error> {
+    use schema::users;
+
+    users::table
+        .filter(users::user_address.eq(user_address))
+        .order(users::topic.asc())
+        .load::<User>(conn)
+}
+
+pub fn get_user_transactions(
+    conn: &PgConnection,
+    topic: &str,
+    user_address: &str,
+) -> Result<Vec<UserTransaction>, diesel::result::Error> {
+    use schema::user_transactions;
+
+    user_transactions::table
+        .filter(user_transactions::topic.eq(topic))
+        .filter(user_transactions::user_address.eq(user_address))
+        .order(user_transactions::id.asc())
+        .load::<UserTransaction>(conn)
 }
 
 #[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
@@ -71,6 +218,7 @@ pub fn save_post<'a>(
     hash_alg: &'a str,
     topic: &'a str,
     url: &'a str,
+    uris: &'a str,
     encryption: &str,
     updated_at: chrono::NaiveDateTime,
 ) -> Result<Post, diesel::result::Error> {
@@ -86,6 +234,7 @@ pub fn save_post<'a>(
         encryption,
         hash_alg,
         updated_at,
+        uris,
     };
 
     diesel::insert_into(posts::table)
@@ -127,6 +276,35 @@ pub fn get_post_by_publish_tx_id(
         .first::<Post>(conn)
 }
 
+/// Posts of the content `file_hash`, the last updated first.
+pub fn get_posts_by_file_hash(
+    conn: &PgConnection,
+    file_hash: &str,
+) -> Result<Vec<Post>, diesel::result::Error> {
+    use schema::posts;
+
+    posts::table
+        .filter(posts::file_hash.eq(file_hash))
+        .order(posts::updated_at.desc())
+        .load::<Post>(conn)
+}
+
+/// The post of `user_address` which replaced `publish_tx_id`, the next one
+/// in its update chain.
+pub fn get_post_by_updated_tx_id(
+    conn: &PgConnection,
+    publish_tx_id: &str,
+    user_address: &str,
+) -> Result<Post, diesel::result::Error> {
+    use schema::posts;
+
+    posts::table
+        .filter(posts::updated_tx_id.eq(publish_tx_id))
+        .filter(posts::user_address.eq(user_address))
+        .order(posts::id.asc())
+        .first::<Post>(conn)
+}
+
 pub fn get_posts(
     conn: &PgConnection,
     fetch_status: bool,
@@ -140,96 +318,282 @@ pub fn get_posts(
         .load::<Post>(conn)
 }
 
+pub fn get_quarantined_posts(
+    conn: &PgConnection,
+    before: chrono::NaiveDateTime,
+    limit: i64,
+) -> Result<Vec<Post>, diesel::result::Error> {
+    use schema::posts::dsl::*;
+    posts
+        .filter(quarantined.eq(true))
+        .filter(quarantined_at.le(before))
+        .filter(deleted.eq(false))
+        .order(quarantined_at.asc())
+        .limit(limit)
+        .load::<Post>(conn)
+}
+
+pub fn quarantine_post<'a>(
+    conn: &PgConnection,
+    input_file_hash: &'a str,
+    hash: &'a str,
+) -> Result<usize, diesel::result::Error> {
+    use schema::posts::dsl::*;
+
+    let result = diesel::update(posts.filter(file_hash.eq(input_file_hash)))
+        .set((
+            fetched.eq(true),
+            verify.eq(false),
+            quarantined.eq(true),
+            observed_hash.eq(hash),
+            quarantined_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn);
+    debug!(
+        "update posts set quarantined = true, observed_hash = {} where file_hash = {}",
+        hash, input_file_hash
+    );
+    result
+}
+
 pub fn get_allow_posts(
     conn: &PgConnection,
     topic: &str,
 ) -> Result<Vec<PostPartial>, diesel::result::Error> {
-    let sql = format!(
-        r#"
-        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.deleted
+    let sql = r#"
+        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.deleted,
+            posts.user_address, posts.updated_tx_id, posts.updated_at, posts.url, posts.uris
         FROM posts, users
         WHERE posts.user_address = users.user_address
-        AND posts.topic = '{}'
+        AND posts.topic = users.topic
+        AND posts.topic = $1
         AND posts.deleted = 'f'
         AND posts.fetched = 't'
         AND posts.verify = 't'
+        AND posts.superseded_by IS NULL
         AND users.status = 'allow'
         ORDER BY posts.updated_at desc
-        "#,
-        topic
-    );
-    diesel::sql_query(sql).load::<PostPartial>(conn)
+        "#;
+    diesel::sql_query(sql)
+        .bind::<Text, _>(topic)
+        .load::<PostPartial>(conn)
 }
 
-pub fn get_posts_for_json(
+/// Optional filters and order of the post list apis.
+#[derive(Debug, Default)]
+pub struct PostFilter {
+    pub author: Option<String>,
+    pub tag: Option<String>,
+    // order by the published date of the frontmatter instead of posts.updated_at,
+    // posts without one fall back to posts.updated_at
+    pub by_published: bool,
+}
+
+impl PostFilter {
+    fn order_column(&self) -> &'static str {
+        if self.by_published {
+            "COALESCE(post_meta.published_at, posts.updated_at)"
+        } else {
+            "posts.updated_at"
+        }
+    }
+}
+
+/// Position of the last row of a page, the next page starts right after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    // value of the order column
+    pub key: NaiveDateTime,
+    // publish_tx_id of a post or user_address of a user, breaks ties of `key`
+    pub id: String,
+}
+
+/// Which part of a list to load, `after` is used instead of `offset` when set.
+#[derive(Debug, Default)]
+pub struct Page {
+    pub offset: i64,
+    pub limit: i64,
+    pub after: Option<Cursor>,
+}
+
+/// Only rows changed in a range of time or blocks. The lower bounds are
+/// exclusive and the upper bounds inclusive, so polling with the last seen
+/// value neither misses nor repeats a row.
+#[derive(Debug, Default)]
+pub struct Range {
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub since_block: Option<i64>,
+    pub until_block: Option<i64>,
+}
+
+// updated_at is taken before a write commits, so with concurrent writers a
+// row can commit with an older updated_at than a row a poller has already
+// passed. The ascending lists only show rows once their updated_at is this
+// old, a write committing later than that after taking its time is missed.
+const POLL_SAFETY_LAG_SECS: i64 = 30;
+
+// a replaced revision is listed as deleted, the way updates were reported
+// before revisions were kept
+const POST_JSON_COLUMNS: &str =
+    "posts.publish_tx_id, posts.file_hash, posts.topic, posts.updated_tx_id, posts.updated_at,
+            (posts.deleted OR posts.superseded_by IS NOT NULL) AS deleted, posts.superseded_by";
+const POST_PARTIAL_COLUMNS: &str =
+    "posts.publish_tx_id, posts.file_hash, posts.topic, (posts.deleted OR posts.superseded_by IS NOT NULL) AS deleted,
+            posts.user_address, posts.updated_tx_id, posts.updated_at, posts.url, posts.uris";
+
+/// Load a page of the fetched and verified posts of `topic`, `allowed_only`
+/// keeps only the latest revisions and drops deleted posts and posts of
+/// denied users.
+/// The block range matches the transaction which last changed a post: the
+/// update which replaced it, or else its publish transaction.
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+fn load_post_page<T: QueryableByName<Pg>>(
     conn: &PgConnection,
+    columns: &str,
+    allowed_only: bool,
+    desc: bool,
     topic: &str,
-    offset: i64,
-    limit: i64,
-) -> Result<Vec<PostJson>, diesel::result::Error> {
+    page: &Page,
+    range: &Range,
+    filter: &PostFilter,
+) -> Result<Vec<T>, diesel::result::Error> {
+    let allowed = if allowed_only {
+        "AND posts.deleted = 'f' AND posts.superseded_by IS NULL AND users.status = 'allow'"
+    } else {
+        ""
+    };
+    let (direction, cmp) = if desc { ("desc", "<") } else { ("asc", ">") };
+    let visible_before = if desc || filter.by_published {
+        None
+    } else {
+        Some(Utc::now().naive_utc() - chrono::Duration::seconds(POLL_SAFETY_LAG_SECS))
+    };
+    // the order column and the direction are constants, everything else is bound
     let sql = format!(
         r#"
-        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.updated_tx_id, posts.updated_at, posts.deleted
-        FROM posts, users
-        WHERE posts.user_address = users.user_address
-        AND posts.topic = '{}'
+        SELECT {columns}
+        FROM posts
+        JOIN users ON posts.user_address = users.user_address AND posts.topic = users.topic
+        LEFT JOIN post_meta ON post_meta.file_hash = posts.file_hash
+        LEFT JOIN transactions
+            ON transactions.trx_id = COALESCE(posts.superseded_by, posts.publish_tx_id)
+        WHERE posts.topic = $1
         AND posts.fetched = 't'
         AND posts.verify = 't'
-        ORDER BY posts.updated_at asc
-        OFFSET {}
-        LIMIT {}
+        {allowed}
+        AND ($4::varchar IS NULL OR post_meta.author = $4)
+        AND ($5::text IS NULL OR $5 = ANY(post_meta.tags))
+        AND ($6::timestamp IS NULL OR ({order}, posts.publish_tx_id::text) {cmp} ($6, $7::text))
+        AND ($8::timestamp IS NULL OR posts.updated_at > $8)
+        AND ($9::timestamp IS NULL OR posts.updated_at <= $9)
+        AND ($10::bigint IS NULL OR transactions.block_num > $10)
+        AND ($11::bigint IS NULL OR transactions.block_num <= $11)
+        AND ($12::timestamp IS NULL OR posts.updated_at <= $12)
+        ORDER BY {order} {direction}, posts.publish_tx_id {direction}
+        OFFSET $2
+        LIMIT $3
         "#,
-        topic, offset, limit
+        columns = columns,
+        allowed = allowed,
+        order = filter.order_column(),
+        cmp = cmp,
+        direction = direction,
     );
-    diesel::sql_query(sql).load::<PostJson>(conn)
+    let offset = if page.after.is_some() { 0 } else { page.offset };
+    diesel::sql_query(sql)
+        .bind::<Text, _>(topic)
+        .bind::<BigInt, _>(offset)
+        .bind::<BigInt, _>(page.limit)
+        .bind::<Nullable<Text>, _>(filter.author.as_ref())
+        .bind::<Nullable<Text>, _>(filter.tag.as_ref())
+        .bind::<Nullable<Timestamp>, _>(page.after.as_ref().map(|v| v.key))
+        .bind::<Nullable<Text>, _>(page.after.as_ref().map(|v| v.id.as_str()))
+        .bind::<Nullable<Timestamp>, _>(range.since)
+        .bind::<Nullable<Timestamp>, _>(range.until)
+        .bind::<Nullable<BigInt>, _>(range.since_block)
+        .bind::<Nullable<BigInt>, _>(range.until_block)
+        .bind::<Nullable<Timestamp>, _>(visible_before)
+        .load::<T>(conn)
+}
+
+pub fn get_posts_for_json(
+    conn: &PgConnection,
+    topic: &str,
+    page: &Page,
+    range: &Range,
+    filter: &PostFilter,
+) -> Result<Vec<PostJson>, diesel::result::Error> {
+    load_post_page(
+        conn,
+        POST_JSON_COLUMNS,
+        false,
+        false,
+        topic,
+        page,
+        range,
+        filter,
+    )
 }
 
 pub fn get_all_atom_posts_by_asc(
     conn: &PgConnection,
     topic: &str,
-    offset: i64,
-    limit: i64,
+    page: &Page,
+    range: &Range,
+    filter: &PostFilter,
 ) -> Result<Vec<PostPartial>, diesel::result::Error> {
-    let sql = format!(
-        r#"
-        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.deleted
-        FROM posts, users
-        WHERE posts.user_address = users.user_address
-        AND posts.topic = '{}'
-        AND posts.fetched = 't'
-        AND posts.verify = 't'
-        ORDER BY posts.updated_at asc
-        OFFSET {}
-        LIMIT {}
-        "#,
-        topic, offset, limit
-    );
-    diesel::sql_query(sql).load::<PostPartial>(conn)
+    load_post_page(
+        conn,
+        POST_PARTIAL_COLUMNS,
+        false,
+        false,
+        topic,
+        page,
+        range,
+        filter,
+    )
 }
 
 pub fn get_latest_posts_by_page(
     conn: &PgConnection,
     topic: &str,
-    offset: i64,
-    limit: i64,
+    page: &Page,
+    range: &Range,
+    filter: &PostFilter,
 ) -> Result<Vec<PostPartial>, diesel::result::Error> {
-    let sql = format!(
-        r#"
-        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.deleted
-        FROM posts, users
-        WHERE posts.user_address = users.user_address
-        AND posts.topic = '{}'
-        AND posts.fetched = 't'
-        AND posts.verify = 't'
-        AND posts.deleted = 'f'
-        AND users.status = 'allow'
-        ORDER BY posts.updated_at desc
-        OFFSET {}
-        LIMIT {}
-        "#,
-        topic, offset, limit
-    );
-    diesel::sql_query(sql).load::<PostPartial>(conn)
+    load_post_page(
+        conn,
+        POST_PARTIAL_COLUMNS,
+        true,
+        true,
+        topic,
+        page,
+        range,
+        filter,
+    )
+}
+
+/// The cursor pointing right after a post in a list ordered by `filter`.
+pub fn get_post_cursor(
+    conn: &PgConnection,
+    filter: &PostFilter,
+    publish_tx_id: &str,
+    file_hash: &str,
+    updated_at: NaiveDateTime,
+) -> Result<Cursor, diesel::result::Error> {
+    let key = if filter.by_published {
+        get_post_meta(conn, file_hash)
+            .optional()?
+            .and_then(|v| v.published_at)
+            .unwrap_or(updated_at)
+    } else {
+        updated_at
+    };
+    Ok(Cursor {
+        key,
+        id: publish_tx_id.to_string(),
+    })
 }
 
 pub fn get_content<'a>(
@@ -242,14 +606,162 @@ pub fn get_content<'a>(
         .first::<models::Content>(conn)
 }
 
-pub fn delete_content<'a>(
+/// Keyset page of contents after `file_hash`, for backfilling post_meta.
+pub fn get_contents_after(
     conn: &PgConnection,
-    _file_hash: &'a str,
+    file_hash: &str,
+    limit: i64,
+) -> Result<Vec<Content>, diesel::result::Error> {
+    use schema::contents;
+    contents::table
+        .filter(contents::file_hash.gt(file_hash))
+        .order(contents::file_hash.asc())
+        .limit(limit)
+        .load::<Content>(conn)
+}
+
+pub fn save_post_meta(
+    conn: &PgConnection,
+    file_hash: &str,
+    attrs: &MarkdownAttrs,
+    parse_error: Option<&str>,
+) -> Result<PostMeta, diesel::result::Error> {
+    use schema::post_meta;
+
+    let extra = serde_json::to_string(&attrs.extra).unwrap_or_else(|_| String::from("{}"));
+    let new_post_meta = NewPostMeta {
+        file_hash,
+        title: &attrs.title,
+        author: &attrs.author,
+        avatar: &attrs.avatar,
+        published_at: attrs.published.map(|v| v.naive_utc()),
+        tags: &attrs.tags,
+        summary: &attrs.summary,
+        cover: &attrs.cover,
+        lang: &attrs.lang,
+        extra: &extra,
+        parse_error,
+        updated_at: Utc::now().naive_utc(),
+        published_offset: attrs.published.map(|v| v.offset().local_minus_utc()),
+    };
+
+    diesel::insert_into(post_meta::table)
+        .values(&new_post_meta)
+        .on_conflict(post_meta::file_hash)
+        .do_update()
+        .set(&new_post_meta)
+        .get_result(conn)
+}
+
+/// Fails unless `search_config` names a text search configuration of the
+/// database.
+pub fn check_search_config(
+    conn: &PgConnection,
+    search_config: &str,
 ) -> Result<usize, diesel::result::Error> {
-    use schema::contents::dsl::*;
+    diesel::sql_query("SELECT $1::regconfig")
+        .bind::<Text, _>(search_config)
+        .execute(conn)
+}
 
-    diesel::update(contents.filter(file_hash.eq(_file_hash)))
-        .set((deleted.eq(true), updated_at.eq(Utc::now().naive_utc())))
+/// Index the frontmatter and content of `file_hash` for search, with the
+/// title weighted over author and tags, then the summary and the body.
+pub fn save_post_search(
+    conn: &PgConnection,
+    file_hash: &str,
+    search_config: &str,
+) -> Result<usize, diesel::result::Error> {
+    let sql = r#"
+        INSERT INTO post_search (file_hash, search_config, search_vector, updated_at)
+        SELECT post_meta.file_hash, $2,
+            setweight(to_tsvector($2::regconfig, post_meta.title), 'A')
+            || setweight(to_tsvector($2::regconfig, post_meta.author || ' ' || array_to_string(post_meta.tags, ' ')), 'B')
+            || setweight(to_tsvector($2::regconfig, post_meta.summary), 'C')
+            || setweight(to_tsvector($2::regconfig, contents.content), 'D'),
+            now()
+        FROM post_meta
+        JOIN contents ON contents.file_hash = post_meta.file_hash
+        WHERE post_meta.file_hash = $1
+        ON CONFLICT (file_hash) DO UPDATE
+        SET search_config = EXCLUDED.search_config,
+            search_vector = EXCLUDED.search_vector,
+            updated_at = EXCLUDED.updated_at
+        "#;
+    diesel::sql_query(sql)
+        .bind::<Text, _>(file_hash)
+        .bind::<Text, _>(search_config)
+        .execute(conn)
+}
+
+/// Posts of `topic` matching `query`, a web search style query, the best
+/// ranked first. `highlight` are the ts_headline options of `snippet`.
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+pub fn search_posts(
+    conn: &PgConnection,
+    topic: &str,
+    query: &str,
+    search_config: &str,
+    highlight: &str,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<SearchResult>, diesel::result::Error> {
+    // the same filters as get_latest_posts_by_page
+    let sql = r#"
+        SELECT posts.publish_tx_id, posts.file_hash, posts.topic, posts.user_address, posts.updated_at,
+            post_meta.title, post_meta.author, post_meta.tags,
+            ts_rank_cd(post_search.search_vector, query) AS rank,
+            ts_headline($3::regconfig, contents.content, query, $4) AS snippet
+        FROM posts
+        JOIN users ON posts.user_address = users.user_address AND posts.topic = users.topic
+        JOIN post_meta ON post_meta.file_hash = posts.file_hash
+        JOIN post_search ON post_search.file_hash = posts.file_hash
+        JOIN contents ON contents.file_hash = posts.file_hash
+        CROSS JOIN websearch_to_tsquery($3::regconfig, $2) AS query
+        WHERE posts.topic = $1
+        AND posts.fetched = 't'
+        AND posts.verify = 't'
+        AND posts.superseded_by IS NULL
+        AND posts.deleted = 'f'
+        AND users.status = 'allow'
+        AND post_search.search_vector @@ query
+        ORDER BY rank desc, posts.updated_at desc, posts.publish_tx_id
+        OFFSET $5
+        LIMIT $6
+        "#;
+    diesel::sql_query(sql)
+        .bind::<Text, _>(topic)
+        .bind::<Text, _>(query)
+        .bind::<Text, _>(search_config)
+        .bind::<Text, _>(highlight)
+        .bind::<BigInt, _>(offset)
+        .bind::<BigInt, _>(limit)
+        .load::<SearchResult>(conn)
+}
+
+pub fn get_post_meta(
+    conn: &PgConnection,
+    file_hash: &str,
+) -> Result<PostMeta, diesel::result::Error> {
+    use schema::post_meta;
+    post_meta::table.find(file_hash).first::<PostMeta>(conn)
+}
+
+/// Keep `publish_tx_id` as an earlier revision of `superseded_by`.
+pub fn supersede_post(
+    conn: &PgConnection,
+    publish_tx_id: &str,
+    superseded_by: &str,
+) -> Result<usize, diesel::result::Error> {
+    use schema::posts;
+
+    let now = Utc::now().naive_utc();
+    diesel::update(posts::table.filter(posts::publish_tx_id.eq(publish_tx_id)))
+        .set((
+            posts::superseded_by.eq(superseded_by),
+            posts::superseded_at.eq(now),
+            // so pollers of the lists see the replaced revision again
+            posts::updated_at.eq(now),
+        ))
         .execute(conn)
 }
 
@@ -276,6 +788,7 @@ pub fn update_post_status<'a>(
         .set((
             fetched.eq(fetched_flag),
             verify.eq(verify_flag),
+            quarantined.eq(false),
             updated_at.eq(Utc::now().naive_utc()),
         ))
         .execute(conn);
@@ -286,6 +799,49 @@ pub fn update_post_status<'a>(
     result
 }
 
+/// Clear the fetch state of a post so its content is fetched and verified
+/// again by the next round of syncserver.
+pub fn reset_post_fetch(
+    conn: &PgConnection,
+    _publish_tx_id: &str,
+) -> Result<Post, diesel::result::Error> {
+    use schema::posts::dsl::*;
+
+    diesel::update(posts.filter(publish_tx_id.eq(_publish_tx_id)))
+        .set((
+            fetched.eq(false),
+            verify.eq(false),
+            quarantined.eq(false),
+            observed_hash.eq(None::<String>),
+            quarantined_at.eq(None::<chrono::NaiveDateTime>),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .get_result::<Post>(conn)
+}
+
+/// Posts whose content is not fetched yet, the oldest first.
+pub fn get_unfetched_posts(
+    conn: &PgConnection,
+    _topic: Option<&str>,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<Post>, diesel::result::Error> {
+    use schema::posts::dsl::*;
+
+    let mut query = posts
+        .filter(fetched.eq(false))
+        .filter(deleted.eq(false))
+        .into_boxed();
+    if let Some(v) = _topic {
+        query = query.filter(topic.eq(v));
+    }
+    query
+        .order((updated_at.asc(), id.asc()))
+        .offset(offset)
+        .limit(limit)
+        .load::<Post>(conn)
+}
+
 pub fn get_last_status(
     conn: &PgConnection,
     _key: &str,
@@ -294,6 +850,31 @@ pub fn get_last_status(
     last_status.filter(key.eq(_key)).first::<LastStatus>(conn)
 }
 
+/// Set `key` to `val` only while it is still `expected`, or not there yet.
+/// Returns false when it was moved in the meantime, by the admin api for
+/// example, and is left alone.
+pub fn compare_and_set_last_status(
+    conn: &PgConnection,
+    _key: &str,
+    expected: i64,
+    _val: i64,
+) -> Result<bool, diesel::result::Error> {
+    use schema::last_status::dsl::*;
+    let updated = diesel::update(last_status.filter(key.eq(_key)).filter(val.eq(expected)))
+        .set(val.eq(_val))
+        .execute(conn)?;
+    if updated > 0 {
+        return Ok(true);
+    }
+    let inserted = diesel::sql_query(
+        "INSERT INTO last_status (key, val) SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM last_status WHERE key = $1)",
+    )
+    .bind::<Text, _>(_key)
+    .bind::<BigInt, _>(_val)
+    .execute(conn)?;
+    Ok(inserted > 0)
+}
+
 pub fn get_max_tx_num(conn: &PgConnection) -> Result<i32, diesel::result::Error> {
     use schema::transactions::dsl::*;
 
@@ -369,6 +950,60 @@ pub fn save_trx(conn: &PgConnection, trx: &prs::Transaction) -> Result<Trx, dies
     item
 }
 
+pub fn save_pending_trx(
+    conn: &PgConnection,
+    trx: &prs::Transaction,
+) -> Result<PendingTrx, diesel::result::Error> {
+    use schema::pending_transactions;
+
+    let topic = trx.get_topic();
+    let data = json!(trx).to_string();
+    let new_pending_trx = NewPendingTrx {
+        trx_id: &trx.trx_id,
+        block_num: trx.block_num,
+        topic: &topic,
+        data: &data,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let item = diesel::insert_into(pending_transactions::table)
+        .values(&new_pending_trx)
+        .on_conflict(pending_transactions::trx_id)
+        .do_update()
+        .set(&new_pending_trx)
+        .get_result(conn);
+
+    info!(
+        "saved pending trx from block_num = {}, trx_id = {}",
+        trx.block_num, trx.trx_id
+    );
+
+    item
+}
+
+pub fn get_pending_trxs(
+    conn: &PgConnection,
+    _topic: &str,
+    max_block_num: i64,
+) -> Result<Vec<PendingTrx>, diesel::result::Error> {
+    use schema::pending_transactions::dsl::*;
+
+    pending_transactions
+        .filter(topic.eq(_topic))
+        .filter(block_num.le(max_block_num))
+        .order(block_num.asc())
+        .load::<PendingTrx>(conn)
+}
+
+pub fn delete_pending_trx(
+    conn: &PgConnection,
+    _trx_id: &str,
+) -> Result<usize, diesel::result::Error> {
+    use schema::pending_transactions::dsl::*;
+
+    diesel::delete(pending_transactions.filter(trx_id.eq(_trx_id))).execute(conn)
+}
+
 pub fn get_trx_by_trx_id(conn: &PgConnection, trx_id: &str) -> Result<Trx, diesel::result::Error> {
     use schema::transactions;
 
@@ -409,12 +1044,52 @@ pub fn update_trx_status(
     result
 }
 
+/// Mark transactions as unprocessed so synctxdata verifies and processes
+/// them again: the ones in `trx_ids`, or in the blocks after `since_block`
+/// up to `until_block`.
+pub fn mark_trxs_unprocessed(
+    conn: &PgConnection,
+    trx_ids: Option<&[String]>,
+    since_block: Option<i64>,
+    until_block: Option<i64>,
+) -> Result<usize, diesel::result::Error> {
+    use schema::transactions;
+
+    let mut query = transactions::table.select(transactions::id).into_boxed();
+    if let Some(v) = trx_ids {
+        query = query.filter(transactions::trx_id.eq_any(v));
+    }
+    if let Some(v) = since_block {
+        query = query.filter(transactions::block_num.gt(v));
+    }
+    if let Some(v) = until_block {
+        query = query.filter(transactions::block_num.le(v));
+    }
+    let ids = query.load::<i32>(conn)?;
+
+    let result = diesel::update(transactions::table.filter(transactions::id.eq_any(&ids)))
+        .set((
+            transactions::processed.eq(false),
+            transactions::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn);
+    info!(
+        "update transactions set processed = false where trx_id in {:?}, block_num in ({:?}, {:?}]",
+        trx_ids, since_block, until_block
+    );
+    result
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
 pub fn save_notify(
     conn: &PgConnection,
     data_id: &str,
     block_num: i64,
     trx_id: &str,
     topic: &str,
+    event: &str,
+    extra: &str,
+    subscription: &str,
 ) -> Result<Notify, diesel::result::Error> {
     use schema::notifies;
 
@@ -423,18 +1098,21 @@ pub fn save_notify(
         block_num,
         trx_id,
         topic,
+        event,
+        extra,
+        subscription,
     };
 
     let notify = diesel::insert_into(notifies::table)
         .values(&new_notify)
-        .on_conflict(notifies::data_id)
+        .on_conflict((notifies::data_id, notifies::event, notifies::subscription))
         .do_update()
         .set(&new_notify)
         .get_result(conn);
 
     info!(
-        "saved notify data, data_id = {} block_num = {} trx_id = {}",
-        data_id, block_num, trx_id
+        "saved notify data, data_id = {} event = {} subscription = {} block_num = {} trx_id = {}",
+        data_id, event, subscription, block_num, trx_id
     );
 
     notify
@@ -443,42 +1121,72 @@ pub fn save_notify(
 pub fn get_unnotified_list(
     conn: &PgConnection,
 ) -> Result<Vec<NotifyPartial>, diesel::result::Error> {
+    // post events wait until the content is fetched and verified
     let sql = r#"
         SELECT
+            notifies.id,
             notifies.data_id,
             notifies.block_num,
             notifies.trx_id,
-            notifies.topic
-        FROM notifies, posts
+            notifies.topic,
+            notifies.event,
+            notifies.subscription
+        FROM notifies
+        LEFT JOIN posts ON notifies.data_id = posts.publish_tx_id
         WHERE
             notifies.success = 'f'
-            and notifies.data_id = posts.publish_tx_id
-            and posts.deleted = 'f'
-            and posts.fetched = 't'
-            and posts.verify = 't'
+            and notifies.dead = 'f'
+            and (notifies.next_attempt_at IS NULL OR notifies.next_attempt_at <= $1)
+            and (
+                notifies.event NOT IN ('post_published', 'post_updated')
+                or (posts.deleted = 'f' and posts.fetched = 't' and posts.verify = 't')
+            )
+        ORDER BY notifies.id asc
         "#;
-    diesel::sql_query(sql).load::<NotifyPartial>(conn)
+    diesel::sql_query(sql)
+        .bind::<Timestamp, _>(Utc::now().naive_utc())
+        .load::<NotifyPartial>(conn)
+}
+
+pub fn get_dead_notifies(
+    conn: &PgConnection,
+    _topic: Option<&str>,
+) -> Result<Vec<Notify>, diesel::result::Error> {
+    use schema::notifies::dsl::*;
+
+    let mut query = notifies.filter(dead.eq(true)).into_boxed();
+    if let Some(v) = _topic {
+        query = query.filter(topic.eq(v));
+    }
+    query.order(id.asc()).load::<Notify>(conn)
+}
+
+pub fn get_notify(conn: &PgConnection, id: i32) -> Result<Notify, diesel::result::Error> {
+    use schema::notifies;
+
+    notifies::table.find(id).first::<Notify>(conn)
 }
 
-pub fn get_notify_by_data_id(
+pub fn get_notifies_by_data_id(
     conn: &PgConnection,
     data_id: &str,
-) -> Result<Notify, diesel::result::Error> {
+) -> Result<Vec<Notify>, diesel::result::Error> {
     use schema::notifies;
 
     notifies::table
         .filter(notifies::data_id.eq(data_id))
-        .first::<Notify>(conn)
+        .order(notifies::id.asc())
+        .load::<Notify>(conn)
 }
 
 pub fn update_notify_status(
     conn: &PgConnection,
-    data_id: &str,
+    id: i32,
     success: bool,
 ) -> Result<Notify, diesel::result::Error> {
     use schema::notifies;
 
-    let notify = diesel::update(notifies::table.filter(notifies::data_id.eq(data_id)))
+    let notify = diesel::update(notifies::table.find(id))
         .set((
             notifies::success.eq(success),
             notifies::retries.eq(notifies::retries + 1),
@@ -486,32 +1194,246 @@ pub fn update_notify_status(
         ))
         .get_result::<Notify>(conn);
     info!(
-        "update notifies set success = {}, retries = retries + 1 where data_id = {}",
+        "update notifies set success = {}, retries = retries + 1 where id = {}",
+        success, id
+    );
+
+    notify
+}
+
+/// Mark every notify of `data_id` as delivered, e.g. the publish event of a
+/// post that was deleted before it could be sent.
+pub fn update_notifies_status_by_data_id(
+    conn: &PgConnection,
+    data_id: &str,
+    success: bool,
+) -> Result<usize, diesel::result::Error> {
+    use schema::notifies;
+
+    let result = diesel::update(notifies::table.filter(notifies::data_id.eq(data_id)))
+        .set((
+            notifies::success.eq(success),
+            notifies::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn);
+    info!(
+        "update notifies set success = {} where data_id = {}",
         success, data_id
     );
 
+    result
+}
+
+/// Record a failed delivery attempt: either schedule the next one or move
+/// the notify to the dead-letter state.
+pub fn update_notify_retry(
+    conn: &PgConnection,
+    id: i32,
+    next_attempt_at: Option<chrono::NaiveDateTime>,
+) -> Result<Notify, diesel::result::Error> {
+    use schema::notifies;
+
+    let dead = next_attempt_at.is_none();
+    let notify = diesel::update(notifies::table.find(id))
+        .set((
+            notifies::success.eq(false),
+            notifies::dead.eq(dead),
+            notifies::next_attempt_at.eq(next_attempt_at),
+            notifies::retries.eq(notifies::retries + 1),
+            notifies::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .get_result::<Notify>(conn);
+    info!(
+        "update notifies set dead = {}, next_attempt_at = {:?}, retries = retries + 1 where id = {}",
+        dead, next_attempt_at, id
+    );
+
     notify
 }
 
+/// Take a notify out of the dead-letter state so it is delivered again.
+pub fn reset_notify(conn: &PgConnection, id: i32) -> Result<Notify, diesel::result::Error> {
+    use schema::notifies;
+
+    diesel::update(notifies::table.find(id))
+        .set((
+            notifies::success.eq(false),
+            notifies::dead.eq(false),
+            notifies::next_attempt_at.eq(None::<chrono::NaiveDateTime>),
+            notifies::retries.eq(0),
+            notifies::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .get_result::<Notify>(conn)
+}
+
+/// Notifies not delivered yet, either waiting for the next attempt or, with
+/// `_dead`, given up on.
+pub fn get_undelivered_notifies(
+    conn: &PgConnection,
+    _topic: Option<&str>,
+    _dead: bool,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<Notify>, diesel::result::Error> {
+    use schema::notifies::dsl::*;
+
+    let mut query = notifies
+        .filter(success.eq(false))
+        .filter(dead.eq(_dead))
+        .into_boxed();
+    if let Some(v) = _topic {
+        query = query.filter(topic.eq(v));
+    }
+    query
+        .order(id.asc())
+        .offset(offset)
+        .limit(limit)
+        .load::<Notify>(conn)
+}
+
+/// Drop a notify without delivering it, together with its delivery log.
+pub fn delete_notify(conn: &PgConnection, id: i32) -> Result<usize, diesel::result::Error> {
+    use schema::{notifies, webhook_logs};
+
+    conn.transaction(|| {
+        diesel::delete(webhook_logs::table.filter(webhook_logs::notify_id.eq(id))).execute(conn)?;
+        let deleted = diesel::delete(notifies::table.find(id)).execute(conn)?;
+        info!("delete notify id = {}, deleted = {}", id, deleted);
+        Ok(deleted)
+    })
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
+pub fn save_webhook_log<'a>(
+    conn: &PgConnection,
+    notify: &'a Notify,
+    url: &'a str,
+    attempt: i32,
+    status_code: Option<i32>,
+    response_body: &'a str,
+    error: Option<&'a str>,
+    duration_ms: i64,
+) -> Result<WebhookLog, diesel::result::Error> {
+    use schema::webhook_logs;
+
+    let new_log = NewWebhookLog {
+        notify_id: notify.id,
+        data_id: &notify.data_id,
+        topic: &notify.topic,
+        url,
+        attempt,
+        status_code,
+        response_body,
+        error,
+        duration_ms,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(webhook_logs::table)
+        .values(&new_log)
+        .get_result(conn)
+}
+
+pub fn get_webhook_logs(
+    conn: &PgConnection,
+    _notify_id: i32,
+) -> Result<Vec<WebhookLog>, diesel::result::Error> {
+    use schema::webhook_logs::dsl::*;
+
+    webhook_logs
+        .filter(notify_id.eq(_notify_id))
+        .order(id.asc())
+        .load::<WebhookLog>(conn)
+}
+
 impl UserList {
     pub fn list(conn: &PgConnection, _topic: &str, offset: i64, limit: i64) -> Self {
+        let page = Page {
+            offset,
+            limit,
+            after: None,
+        };
+        Self::page(conn, _topic, &page, &Range::default()).expect("loading users failed")
+    }
+
+    /// Users of `_topic` ordered by the time their status was set, with the
+    /// range on that time or on the block of the transaction that set it.
+    pub fn page(
+        conn: &PgConnection,
+        _topic: &str,
+        page: &Page,
+        range: &Range,
+    ) -> Result<Self, diesel::result::Error> {
+        use schema::transactions;
         use schema::users::dsl::*;
 
-        let result = users
+        let mut query = users
             .filter(topic.eq(_topic))
-            .order(updated_at.asc())
-            .limit(limit)
-            .offset(offset)
-            .load::<User>(conn)
-            .expect("loading users failed");
+            .order((updated_at.asc(), user_address.asc()))
+            .limit(page.limit)
+            .into_boxed();
+        match &page.after {
+            Some(cursor) => {
+                query = query.filter(
+                    updated_at.gt(cursor.key).or(updated_at
+                        .eq(cursor.key)
+                        .and(user_address.gt(cursor.id.clone()))),
+                );
+            }
+            None => query = query.offset(page.offset),
+        }
+        if let Some(v) = range.since {
+            query = query.filter(updated_at.gt(v));
+        }
+        if let Some(v) = range.until {
+            query = query.filter(updated_at.le(v));
+        }
+        if let Some(v) = range.since_block {
+            query = query.filter(
+                tx_id.eq_any(
+                    transactions::table
+                        .select(transactions::trx_id)
+                        .filter(transactions::block_num.gt(v)),
+                ),
+            );
+        }
+        if let Some(v) = range.until_block {
+            query = query.filter(
+                tx_id.eq_any(
+                    transactions::table
+                        .select(transactions::trx_id)
+                        .filter(transactions::block_num.le(v)),
+                ),
+            );
+        }
 
-        let res = result
+        let res = query
+            .load::<User>(conn)?
             .into_iter()
             .map(|mut v| {
                 v.status = v.status.trim().to_string();
                 v
             })
             .collect();
-        UserList(res)
+        Ok(UserList(res))
     }
 }
+
+pub fn save_topic_worker(
+    conn: &PgConnection,
+    worker: &NewTopicWorker,
+) -> Result<TopicWorker, diesel::result::Error> {
+    use schema::topic_workers;
+
+    diesel::insert_into(topic_workers::table)
+        .values(worker)
+        .on_conflict(topic_workers::topic)
+        .do_update()
+        .set(worker)
+        .get_result(conn)
+}
+
+pub fn get_topic_workers(conn: &PgConnection) -> Result<Vec<TopicWorker>, diesel::result::Error> {
+    use schema::topic_workers::dsl::*;
+    topic_workers.order(topic.asc()).load::<TopicWorker>(conn)
+}
@@ -6,6 +6,7 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt;
 
+use crate::frontmatter::MarkdownAttrs;
 use crate::prs_utility_rust::utility;
 use crate::qs_rs::qs;
 use anyhow::{anyhow, Result};
@@ -15,9 +16,14 @@ use super::prs;
 use super::schema::contents;
 use super::schema::last_status;
 use super::schema::notifies;
+use super::schema::pending_transactions;
+use super::schema::post_meta;
 use super::schema::posts;
+use super::schema::topic_workers;
 use super::schema::transactions;
+use super::schema::user_transactions;
 use super::schema::users;
+use super::schema::webhook_logs;
 
 #[derive(Serialize, Deserialize)]
 pub struct UserList(pub Vec<User>);
@@ -41,7 +47,27 @@ pub struct NewUser<'a> {
     pub topic: &'a str,
 }
 
-#[derive(Queryable)]
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
+pub struct UserTransaction {
+    pub id: i32,
+    pub topic: String,
+    pub user_address: String,
+    pub status: String,
+    pub tx_id: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_transactions"]
+pub struct NewUserTransaction<'a> {
+    pub topic: &'a str,
+    pub user_address: &'a str,
+    pub status: &'a str,
+    pub tx_id: &'a str,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize)]
 pub struct Post {
     pub id: i32,
     pub publish_tx_id: String,
@@ -56,6 +82,47 @@ pub struct Post {
     pub encryption: String,
     pub hash_alg: String,
     pub deleted: bool,
+    pub uris: String, // json dumps of meta.uris
+    pub quarantined: bool,
+    pub observed_hash: Option<String>,
+    pub quarantined_at: Option<chrono::NaiveDateTime>,
+    // publish_tx_id of the revision which replaced this one
+    pub superseded_by: Option<String>,
+    pub superseded_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Post {
+    /// All uris of the post in order, falls back to `url` for rows saved
+    /// before `uris` was recorded.
+    pub fn get_uris(&self) -> Vec<String> {
+        parse_uris(&self.uris, &self.url)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !self.encryption.trim().is_empty()
+    }
+
+    /// `pending` until fetched, then `verified`, `quarantined` when the
+    /// content hash did not match under `strict_verify`, or `failed`.
+    pub fn verification_state(&self) -> &'static str {
+        if self.quarantined {
+            "quarantined"
+        } else if !self.fetched {
+            "pending"
+        } else if self.verify {
+            "verified"
+        } else {
+            "failed"
+        }
+    }
+}
+
+/// `uris` is a JSON array, posts saved before it existed only have `url`.
+fn parse_uris(uris: &str, url: &str) -> Vec<String> {
+    match serde_json::from_str::<Vec<String>>(uris) {
+        Ok(uris) if !uris.is_empty() => uris,
+        _ => vec![url.to_string()],
+    }
 }
 
 #[derive(Queryable, PartialEq, QueryableByName, Debug, Serialize)]
@@ -65,6 +132,17 @@ pub struct PostPartial {
     pub file_hash: String,
     pub topic: String,
     pub deleted: bool,
+    pub user_address: String,
+    pub updated_tx_id: String,
+    pub updated_at: chrono::NaiveDateTime,
+    pub url: String,
+    pub uris: String,
+}
+
+impl PostPartial {
+    pub fn get_uris(&self) -> Vec<String> {
+        parse_uris(&self.uris, &self.url)
+    }
 }
 
 #[derive(Queryable, PartialEq, QueryableByName, Debug, Serialize)]
@@ -76,6 +154,34 @@ pub struct PostJson {
     pub updated_tx_id: String,
     pub updated_at: chrono::NaiveDateTime,
     pub deleted: bool,
+    // publish_tx_id of the revision which replaced this one
+    pub superseded_by: Option<String>,
+}
+
+/// A post matching a search, see `db::search_posts`.
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct SearchResult {
+    #[sql_type = "diesel::sql_types::Text"]
+    pub publish_tx_id: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub file_hash: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub topic: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub user_address: String,
+    #[sql_type = "diesel::sql_types::Timestamp"]
+    pub updated_at: chrono::NaiveDateTime,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub title: String,
+    #[sql_type = "diesel::sql_types::Text"]
+    pub author: String,
+    #[sql_type = "diesel::sql_types::Array<diesel::sql_types::Text>"]
+    pub tags: Vec<String>,
+    #[sql_type = "diesel::sql_types::Float4"]
+    pub rank: f32,
+    // fragments of the content around the matches, marked by the highlight options
+    #[sql_type = "diesel::sql_types::Text"]
+    pub snippet: String,
 }
 
 #[derive(Insertable, AsChangeset)]
@@ -90,6 +196,7 @@ pub struct NewPost<'a> {
     pub updated_at: chrono::NaiveDateTime,
     pub encryption: &'a str,
     pub hash_alg: &'a str,
+    pub uris: &'a str,
 }
 
 #[derive(Queryable)]
@@ -111,6 +218,65 @@ pub struct NewContent<'a> {
     pub created_at: chrono::NaiveDateTime,
 }
 
+#[derive(Queryable, Debug, Serialize)]
+pub struct PostMeta {
+    pub file_hash: String,
+    pub title: String,
+    pub author: String,
+    pub avatar: String,
+    pub published_at: Option<chrono::NaiveDateTime>,
+    pub tags: Vec<String>,
+    pub summary: String,
+    pub cover: String,
+    pub lang: String,
+    pub extra: String, // json dumps of the extra frontmatter fields
+    pub parse_error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+    // seconds east of UTC the author wrote `published` in, NULL for UTC
+    pub published_offset: Option<i32>,
+}
+
+impl PostMeta {
+    pub fn to_attrs(&self) -> MarkdownAttrs {
+        MarkdownAttrs {
+            title: self.title.clone(),
+            author: self.author.clone(),
+            avatar: self.avatar.clone(),
+            published: self.published_at.map(|v| {
+                let offset = self
+                    .published_offset
+                    .and_then(chrono::FixedOffset::east_opt)
+                    .unwrap_or_else(|| chrono::FixedOffset::east(0));
+                chrono::DateTime::from_utc(v, offset)
+            }),
+            tags: self.tags.clone(),
+            summary: self.summary.clone(),
+            cover: self.cover.clone(),
+            lang: self.lang.clone(),
+            extra: serde_json::from_str(&self.extra).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name = "post_meta"]
+pub struct NewPostMeta<'a> {
+    pub file_hash: &'a str,
+    pub title: &'a str,
+    pub author: &'a str,
+    pub avatar: &'a str,
+    pub published_at: Option<chrono::NaiveDateTime>,
+    pub tags: &'a [String],
+    pub summary: &'a str,
+    pub cover: &'a str,
+    pub lang: &'a str,
+    pub extra: &'a str,
+    pub parse_error: Option<&'a str>,
+    pub updated_at: chrono::NaiveDateTime,
+    pub published_offset: Option<i32>,
+}
+
 #[derive(Queryable, Debug)]
 pub struct LastStatus {
     pub id: i32,
@@ -263,6 +429,25 @@ impl Trx {
     }
 }
 
+#[derive(Queryable, Debug)]
+pub struct PendingTrx {
+    pub trx_id: String,
+    pub block_num: i64,
+    pub topic: String,
+    pub data: String, // json dumps of prs::Transaction
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[table_name = "pending_transactions"]
+pub struct NewPendingTrx<'a> {
+    pub trx_id: &'a str,
+    pub block_num: i64,
+    pub topic: &'a str,
+    pub data: &'a str,
+    pub created_at: chrono::NaiveDateTime,
+}
+
 #[derive(Insertable, AsChangeset, Debug)]
 #[table_name = "transactions"]
 pub struct NewTrx<'a> {
@@ -277,7 +462,7 @@ pub struct NewTrx<'a> {
     pub user_address: &'a str,
 }
 
-#[derive(Queryable, Clone, Debug)]
+#[derive(Queryable, Serialize, Clone, Debug)]
 pub struct Notify {
     pub data_id: String,
     pub block_num: i64,
@@ -287,6 +472,12 @@ pub struct Notify {
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: Option<chrono::NaiveDateTime>,
     pub topic: String,
+    pub next_attempt_at: Option<chrono::NaiveDateTime>,
+    pub dead: bool,
+    pub id: i32,
+    pub event: String,
+    pub extra: String, // json dumps of the event specific data
+    pub subscription: String,
 }
 
 #[derive(Insertable, AsChangeset, Debug)]
@@ -296,13 +487,75 @@ pub struct NewNotify<'a> {
     pub block_num: i64,
     pub trx_id: &'a str,
     pub topic: &'a str,
+    pub event: &'a str,
+    pub extra: &'a str,
+    pub subscription: &'a str,
 }
 
 #[derive(Queryable, PartialEq, QueryableByName, Debug)]
 #[table_name = "notifies"]
 pub struct NotifyPartial {
+    pub id: i32,
     pub data_id: String,
     pub block_num: i64,
     pub trx_id: String,
     pub topic: String,
+    pub event: String,
+    pub subscription: String,
+}
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct WebhookLog {
+    pub id: i32,
+    pub data_id: String,
+    pub topic: String,
+    pub url: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub response_body: String,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub created_at: chrono::NaiveDateTime,
+    pub notify_id: i32,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "webhook_logs"]
+pub struct NewWebhookLog<'a> {
+    pub notify_id: i32,
+    pub data_id: &'a str,
+    pub topic: &'a str,
+    pub url: &'a str,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub response_body: &'a str,
+    pub error: Option<&'a str>,
+    pub duration_ms: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// The sync worker of a topic as last reported by syncserver.
+#[derive(Queryable, Serialize, Debug)]
+pub struct TopicWorker {
+    pub topic: String,
+    // running, paused or stopped
+    pub state: String,
+    // the block the topic is synced to
+    pub block_num: Option<i64>,
+    pub last_synced_at: Option<chrono::NaiveDateTime>,
+    // the error of the last round, none when it succeeded
+    pub last_error: Option<String>,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset, Debug)]
+#[table_name = "topic_workers"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewTopicWorker<'a> {
+    pub topic: &'a str,
+    pub state: &'a str,
+    pub block_num: Option<i64>,
+    pub last_synced_at: Option<chrono::NaiveDateTime>,
+    pub last_error: Option<&'a str>,
+    pub updated_at: chrono::NaiveDateTime,
 }
@@ -18,7 +18,7 @@ table! {
 }
 
 table! {
-    notifies (data_id) {
+    notifies (id) {
         data_id -> Varchar,
         block_num -> Int8,
         trx_id -> Varchar,
@@ -27,6 +27,41 @@ table! {
         created_at -> Timestamp,
         updated_at -> Nullable<Timestamp>,
         topic -> Varchar,
+        next_attempt_at -> Nullable<Timestamp>,
+        dead -> Bool,
+        id -> Int4,
+        event -> Varchar,
+        extra -> Varchar,
+        subscription -> Varchar,
+    }
+}
+
+table! {
+    pending_transactions (trx_id) {
+        trx_id -> Varchar,
+        block_num -> Int8,
+        topic -> Varchar,
+        data -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    post_meta (file_hash) {
+        file_hash -> Bpchar,
+        title -> Varchar,
+        author -> Varchar,
+        avatar -> Varchar,
+        published_at -> Nullable<Timestamp>,
+        tags -> Array<Text>,
+        summary -> Text,
+        cover -> Varchar,
+        lang -> Varchar,
+        extra -> Text,
+        parse_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        published_offset -> Nullable<Int4>,
     }
 }
 
@@ -45,6 +80,23 @@ table! {
         encryption -> Varchar,
         hash_alg -> Varchar,
         deleted -> Bool,
+        uris -> Varchar,
+        quarantined -> Bool,
+        observed_hash -> Nullable<Varchar>,
+        quarantined_at -> Nullable<Timestamp>,
+        superseded_by -> Nullable<Bpchar>,
+        superseded_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    topic_workers (topic) {
+        topic -> Varchar,
+        state -> Varchar,
+        block_num -> Nullable<Int8>,
+        last_synced_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        updated_at -> Timestamp,
     }
 }
 
@@ -65,7 +117,18 @@ table! {
 }
 
 table! {
-    users (user_address) {
+    user_transactions (id) {
+        id -> Int4,
+        topic -> Varchar,
+        user_address -> Bpchar,
+        status -> Bpchar,
+        tx_id -> Bpchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    users (topic, user_address) {
         user_address -> Bpchar,
         status -> Bpchar,
         tx_id -> Bpchar,
@@ -74,11 +137,32 @@ table! {
     }
 }
 
+table! {
+    webhook_logs (id) {
+        id -> Int4,
+        data_id -> Varchar,
+        topic -> Varchar,
+        url -> Varchar,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        response_body -> Text,
+        error -> Nullable<Text>,
+        duration_ms -> Int8,
+        created_at -> Timestamp,
+        notify_id -> Int4,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
     contents,
     last_status,
     notifies,
+    pending_transactions,
+    post_meta,
     posts,
+    topic_workers,
     transactions,
+    user_transactions,
     users,
+    webhook_logs,
 );
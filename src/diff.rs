@@ -0,0 +1,405 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+// unchanged lines around a change in a unified diff hunk
+const CONTEXT_LINES: usize = 3;
+// the diff crate fills a table of u32 for the tokens between the common
+// prefix and suffix, this keeps it under 64 MiB
+const MAX_TABLE_CELLS: usize = 16 * 1024 * 1024;
+
+/// The changed part of the two texts has too many tokens to diff.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub old_tokens: usize,
+    pub new_tokens: usize,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the changed part has {} old and {} new tokens, too large to diff",
+            self.old_tokens, self.new_tokens
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granularity {
+    Line,
+    Word,
+}
+
+impl FromStr for Granularity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Granularity> {
+        match s {
+            "line" => Ok(Granularity::Line),
+            "word" => Ok(Granularity::Word),
+            _ => Err(anyhow!("unsupported granularity: {}", s)),
+        }
+    }
+}
+
+impl Granularity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Line => "line",
+            Granularity::Word => "word",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Equal,
+    Insert,
+    Delete,
+}
+
+/// A run of text with the same op, whole lines or words with the whitespace
+/// around them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub op: Op,
+    pub text: String,
+}
+
+/// Inserted and deleted lines, or words without the whitespace between them.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+    pub stats: Stats,
+}
+
+impl Diff {
+    pub fn new(old: &str, new: &str, granularity: Granularity) -> Result<Diff, TooLarge> {
+        let old_tokens = tokenize(old, granularity);
+        let new_tokens = tokenize(new, granularity);
+        let mut changes: Vec<Change> = Vec::new();
+        let mut stats = Stats::default();
+
+        for (op, token) in ops(&old_tokens, &new_tokens)? {
+            let counted = granularity == Granularity::Line || !token.trim().is_empty();
+            match op {
+                Op::Insert if counted => stats.insertions += 1,
+                Op::Delete if counted => stats.deletions += 1,
+                _ => {}
+            }
+            match changes.last_mut() {
+                Some(last) if last.op == op => last.text.push_str(token),
+                _ => changes.push(Change {
+                    op,
+                    text: token.to_string(),
+                }),
+            }
+        }
+
+        Ok(Diff { changes, stats })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.iter().all(|v| v.op == Op::Equal)
+    }
+
+    /// The changes inline, deleted text as `[-...-]` and inserted as `{+...+}`,
+    /// like `git diff --word-diff=plain`.
+    pub fn to_word_diff(&self) -> String {
+        let mut text = String::new();
+        for change in &self.changes {
+            match change.op {
+                Op::Equal => text.push_str(&change.text),
+                Op::Delete => {
+                    text.push_str("[-");
+                    text.push_str(&change.text);
+                    text.push_str("-]");
+                }
+                Op::Insert => {
+                    text.push_str("{+");
+                    text.push_str(&change.text);
+                    text.push_str("+}");
+                }
+            }
+        }
+        text
+    }
+}
+
+/// Lines with their line break, or runs of whitespace and of other chars, so
+/// joining the tokens gives back `text`.
+pub fn tokenize(text: &str, granularity: Granularity) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut last_is_space = None;
+    for (i, c) in text.char_indices() {
+        match granularity {
+            Granularity::Line => {
+                if c == '\n' {
+                    tokens.push(&text[start..=i]);
+                    start = i + 1;
+                }
+            }
+            Granularity::Word => {
+                let is_space = c.is_whitespace();
+                if last_is_space == Some(!is_space) {
+                    tokens.push(&text[start..i]);
+                    start = i;
+                }
+                last_is_space = Some(is_space);
+            }
+        }
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+fn ops<'a>(old: &[&'a str], new: &[&'a str]) -> Result<Vec<(Op, &'a str)>, TooLarge> {
+    check_size(old, new)?;
+    Ok(::diff::slice(old, new)
+        .into_iter()
+        .map(|v| match v {
+            ::diff::Result::Left(l) => (Op::Delete, *l),
+            ::diff::Result::Both(l, _) => (Op::Equal, *l),
+            ::diff::Result::Right(r) => (Op::Insert, *r),
+        })
+        .collect())
+}
+
+/// The table the diff crate allocates must stay under `MAX_TABLE_CELLS`.
+fn check_size(old: &[&str], new: &[&str]) -> Result<(), TooLarge> {
+    let prefix = old.iter().zip(new).take_while(|(l, r)| l == r).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(l, r)| l == r)
+        .count();
+    let old_tokens = old.len() - prefix - suffix;
+    let new_tokens = new.len() - prefix - suffix;
+    match (old_tokens + 1).checked_mul(new_tokens + 1) {
+        Some(cells) if cells <= MAX_TABLE_CELLS => Ok(()),
+        _ => Err(TooLarge {
+            old_tokens,
+            new_tokens,
+        }),
+    }
+}
+
+/// A unified diff of the lines of `old` and `new`, empty when they are equal.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> Result<String, TooLarge> {
+    let old_lines = tokenize(old, Granularity::Line);
+    let new_lines = tokenize(new, Granularity::Line);
+    let ops = ops(&old_lines, &new_lines)?;
+
+    let changed: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != Op::Equal).collect();
+    if changed.is_empty() {
+        return Ok(String::new());
+    }
+
+    // ranges of ops, two changes share a hunk when their contexts touch
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in &changed {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = std::cmp::min(i + 1 + CONTEXT_LINES, ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut text = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let (mut old_idx, mut new_idx, mut op_idx) = (0, 0, 0);
+    for (start, end) in hunks {
+        while op_idx < start {
+            advance(ops[op_idx].0, &mut old_idx, &mut new_idx);
+            op_idx += 1;
+        }
+        let old_count = ops[start..end].iter().filter(|v| v.0 != Op::Insert).count();
+        let new_count = ops[start..end].iter().filter(|v| v.0 != Op::Delete).count();
+        text.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_idx, old_count),
+            hunk_range(new_idx, new_count)
+        ));
+        for (op, line) in &ops[start..end] {
+            text.push(match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            });
+            text.push_str(line);
+            if !line.ends_with('\n') {
+                text.push_str("\n\\ No newline at end of file\n");
+            }
+            advance(*op, &mut old_idx, &mut new_idx);
+        }
+        op_idx = end;
+    }
+
+    Ok(text)
+}
+
+fn advance(op: Op, old_idx: &mut usize, new_idx: &mut usize) {
+    if op != Op::Insert {
+        *old_idx += 1;
+    }
+    if op != Op::Delete {
+        *new_idx += 1;
+    }
+}
+
+/// `start,count` with a 1-based start, an empty range starts at the line
+/// before it.
+fn hunk_range(idx: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", idx),
+        1 => format!("{}", idx + 1),
+        _ => format!("{},{}", idx + 1, count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_keeps_text() {
+        let text = "# Hello\n\nsome  text\nno newline";
+        assert_eq!(
+            tokenize(text, Granularity::Line),
+            vec!["# Hello\n", "\n", "some  text\n", "no newline"]
+        );
+        assert_eq!(
+            tokenize(text, Granularity::Word),
+            vec!["#", " ", "Hello", "\n\n", "some", "  ", "text", "\n", "no", " ", "newline"]
+        );
+        assert_eq!(tokenize("", Granularity::Line), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn line_diff() {
+        let diff = Diff::new("a\nb\nc\n", "a\nB\nc\nd\n", Granularity::Line).unwrap();
+        assert_eq!(
+            diff.changes,
+            vec![
+                Change {
+                    op: Op::Equal,
+                    text: String::from("a\n")
+                },
+                Change {
+                    op: Op::Delete,
+                    text: String::from("b\n")
+                },
+                Change {
+                    op: Op::Insert,
+                    text: String::from("B\n")
+                },
+                Change {
+                    op: Op::Equal,
+                    text: String::from("c\n")
+                },
+                Change {
+                    op: Op::Insert,
+                    text: String::from("d\n")
+                },
+            ]
+        );
+        assert_eq!(
+            diff.stats,
+            Stats {
+                insertions: 2,
+                deletions: 1
+            }
+        );
+        assert!(!diff.is_empty());
+        assert!(Diff::new("a\n", "a\n", Granularity::Line)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn word_diff() {
+        let diff = Diff::new(
+            "the quick brown fox",
+            "the slow brown fox jumps",
+            Granularity::Word,
+        )
+        .unwrap();
+        assert_eq!(
+            diff.to_word_diff(),
+            "the [-quick-]{+slow+} brown fox{+ jumps+}"
+        );
+        assert_eq!(
+            diff.stats,
+            Stats {
+                insertions: 2,
+                deletions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn unified_diff() {
+        let old: String = (1..=15).map(|v| format!("{}\n", v)).collect();
+        let new: String = (1..=15)
+            .map(|v| match v {
+                5 => String::from("five\n"),
+                _ => format!("{}\n", v),
+            })
+            .collect::<String>()
+            + "16";
+        assert_eq!(
+            unified("a", "b", &old, &new).unwrap(),
+            "--- a\n+++ b\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n@@ -13,3 +13,4 @@\n 13\n 14\n 15\n+16\n\\ No newline at end of file\n"
+        );
+        // changes closer than two contexts share a hunk
+        assert_eq!(
+            unified("a", "b", "1\n2\n3\n", "0\n2\n4\n").unwrap(),
+            "--- a\n+++ b\n@@ -1,3 +1,3 @@\n-1\n+0\n 2\n-3\n+4\n"
+        );
+        assert_eq!(unified("a", "b", &old, &old).unwrap(), "");
+        assert_eq!(
+            unified("a", "b", "", "x\n").unwrap(),
+            "--- a\n+++ b\n@@ -0,0 +1 @@\n+x\n"
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let old: String = (0..5000).map(|v| format!("{} ", v)).collect();
+        let new: String = (0..5000).map(|v| format!("{} ", v + 1)).collect();
+        assert!(Diff::new(&old, &new, Granularity::Word).is_err());
+        // the common prefix and suffix do not count
+        let new = format!("{}x {}", &old[..old.len() / 2], &old[old.len() / 2..]);
+        assert!(Diff::new(&old, &new, Granularity::Word).is_ok());
+    }
+
+    #[test]
+    fn json_output() {
+        let diff = Diff::new("a\n", "b\n", Granularity::Line).unwrap();
+        assert_eq!(
+            serde_json::to_value(&diff).unwrap(),
+            json!({
+                "changes": [
+                    { "op": "delete", "text": "a\n" },
+                    { "op": "insert", "text": "b\n" },
+                ],
+                "stats": { "insertions": 1, "deletions": 1 },
+            })
+        );
+    }
+}
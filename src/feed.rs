@@ -0,0 +1,810 @@
+use anyhow::{anyhow, Result};
+use atom_syndication::extension::Extension;
+use chrono::prelude::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use chrono::SecondsFormat;
+use rss::extension::dublincore::{self, DublinCoreExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::db::models::{Content, PostPartial};
+use crate::frontmatter::{self, MarkdownAttrs};
+use crate::render::{self, ContentFormat};
+use crate::settings::FeedConf;
+
+const GENERATOR: &str = "PRESSone Atom Generator";
+// summary of posts without a frontmatter summary
+const SUMMARY_MAX_CHARS: usize = 200;
+// namespace of the atom extension elements, e.g. prs:avatar
+const PRS_NAMESPACE: &str = "https://press.one/atom/ns";
+// namespace of the rss content:encoded element
+const RSS_CONTENT_NAMESPACE: &str = "http://purl.org/rss/1.0/modules/content/";
+const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Atom,
+    Rss,
+    JsonFeed,
+}
+
+impl Format {
+    pub fn all() -> Vec<Format> {
+        vec![Format::Atom, Format::Rss, Format::JsonFeed]
+    }
+
+    /// The first media type of an `Accept` header that we can write.
+    pub fn from_accept(accept: &str) -> Option<Format> {
+        accept
+            .split(',')
+            .map(|v| v.split(';').next().unwrap_or("").trim())
+            .filter_map(|v| match v {
+                "application/atom+xml" => Some(Format::Atom),
+                "application/rss+xml" => Some(Format::Rss),
+                "application/feed+json" | "application/json" => Some(Format::JsonFeed),
+                _ => None,
+            })
+            .next()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Atom => "application/atom+xml; charset=utf-8",
+            Format::Rss => "application/rss+xml; charset=utf-8",
+            Format::JsonFeed => "application/feed+json; charset=utf-8",
+        }
+    }
+
+    /// The output file of a topic, atom keeps the bare topic name it always had.
+    pub fn file_name(&self, topic: &str) -> String {
+        match self {
+            Format::Atom => topic.to_string(),
+            Format::Rss => format!("{}.rss", topic),
+            Format::JsonFeed => format!("{}.json", topic),
+        }
+    }
+}
+
+/// The `format` query parameter.
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "atom" => Ok(Format::Atom),
+            "rss" => Ok(Format::Rss),
+            "json" | "jsonfeed" => Ok(Format::JsonFeed),
+            _ => Err(anyhow!("unsupported format: {}", s)),
+        }
+    }
+}
+
+/// A feed independent of the format it is written in.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub id: String,
+    pub title: String,
+    pub subtitle: Option<String>,
+    pub link: Option<String>,
+    pub self_link: Option<String>,
+    pub icon: Option<String>,
+    pub updated: DateTime<FixedOffset>,
+    // how atom <content> is written
+    pub content_format: ContentFormat,
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub id: String,
+    pub title: String,
+    pub published: DateTime<FixedOffset>,
+    pub updated: DateTime<FixedOffset>,
+    pub summary: Option<String>,
+    // raw markdown, frontmatter included
+    pub content: String,
+    // sanitized html of the markdown body
+    pub content_html: String,
+    pub author: String,
+    pub author_uri: Option<String>,
+    pub avatar: Option<String>,
+    // the original uri of the post
+    pub link: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Item {
+    /// `first_published_at` is when the first revision in the update chain
+    /// was saved, used when the frontmatter has no `published`.
+    pub fn new(
+        feed_conf: &FeedConf,
+        post: &PostPartial,
+        content: &Content,
+        attrs: &MarkdownAttrs,
+        first_published_at: NaiveDateTime,
+    ) -> Item {
+        let summary = if attrs.summary.is_empty() {
+            get_summary(frontmatter::body(&content.content))
+        } else {
+            attrs.summary.clone()
+        };
+
+        // the title is required too, fall back to the first heading of the
+        // body, then to the id of the post
+        let title = if attrs.title.trim().is_empty() {
+            get_heading(frontmatter::body(&content.content))
+                .unwrap_or_else(|| post.publish_tx_id.clone())
+        } else {
+            attrs.title.clone()
+        };
+
+        // the author is required, fall back to the address that signed the post
+        let user_address = post.user_address.trim();
+        let author = if attrs.author.is_empty() {
+            user_address.to_string()
+        } else {
+            attrs.author.clone()
+        };
+
+        Item {
+            id: post.publish_tx_id.clone(),
+            title,
+            published: attrs
+                .published
+                .unwrap_or_else(|| to_utc(first_published_at)),
+            updated: to_utc(post.updated_at),
+            summary: none_if_empty(summary),
+            content: content.content.clone(),
+            content_html: render::markdown_to_html(&content.content),
+            author,
+            author_uri: feed_conf
+                .author_uri_prefix
+                .as_ref()
+                .map(|prefix| format!("{}{}", prefix, user_address)),
+            avatar: none_if_empty(attrs.avatar.clone()),
+            // data: uris carry the whole post, they are no use as a link
+            link: post
+                .get_uris()
+                .into_iter()
+                .find(|v| !v.is_empty() && !v.starts_with("data:")),
+            tags: attrs.tags.clone(),
+        }
+    }
+}
+
+impl Feed {
+    pub fn new(topic: &str, feed_conf: &FeedConf, items: Vec<Item>) -> Feed {
+        let updated = items
+            .iter()
+            .map(|v| v.updated)
+            .max()
+            .unwrap_or_else(|| to_utc(Utc::now().naive_utc()));
+        let content_format = match &feed_conf.content_format {
+            Some(v) => v.parse().unwrap_or_else(|_| {
+                warn!(
+                    "unknown content_format = {} of topic = {}, use markdown",
+                    v, topic
+                );
+                ContentFormat::Markdown
+            }),
+            None => ContentFormat::Markdown,
+        };
+
+        Feed {
+            id: feed_conf
+                .id
+                .clone()
+                .unwrap_or_else(|| format!("urn:prs:topic:{}", topic)),
+            title: feed_conf.title.clone().unwrap_or_else(|| topic.to_string()),
+            subtitle: feed_conf.subtitle.clone(),
+            link: feed_conf.link.clone(),
+            self_link: feed_conf.self_link.clone(),
+            icon: feed_conf.icon.clone(),
+            updated,
+            content_format,
+            items,
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Atom => self.to_atom(),
+            Format::Rss => self.to_rss(),
+            Format::JsonFeed => self.to_json_feed(),
+        }
+    }
+
+    pub fn to_atom(&self) -> String {
+        use atom_syndication::{Category, Content, Entry, Generator, Person};
+
+        // atom_syndication 0.6 writes text elements as they are, only the
+        // attributes are escaped by the writer
+        let mut generator = Generator::default();
+        generator.set_value(escape_xml(GENERATOR));
+
+        let mut feed = atom_syndication::Feed::default();
+        feed.set_id(escape_xml(&self.id));
+        feed.set_title(escape_xml(&self.title));
+        feed.set_subtitle(self.subtitle.as_deref().map(escape_xml));
+        feed.set_icon(self.icon.as_deref().map(escape_xml));
+        feed.set_updated(to_rfc3339(&self.updated));
+
+        let mut links = Vec::new();
+        if let Some(href) = &self.link {
+            links.push(atom_link(href, "alternate", None));
+        }
+        if let Some(href) = &self.self_link {
+            links.push(atom_link(href, "self", Some("application/atom+xml")));
+        }
+        feed.set_links(links);
+
+        let mut namespaces = HashMap::new();
+        namespaces.insert(String::from("prs"), String::from(PRS_NAMESPACE));
+        feed.set_namespaces(namespaces);
+        feed.set_generator(generator);
+
+        let mut entries = Vec::new();
+        for item in &self.items {
+            let mut entry = Entry::default();
+            entry.set_id(escape_xml(&item.id));
+            entry.set_title(escape_xml(&item.title));
+            entry.set_updated(to_rfc3339(&item.updated));
+            entry.set_published(to_rfc3339(&item.published));
+            entry.set_summary(item.summary.as_deref().map(escape_xml));
+
+            let mut person = Person::default();
+            person.set_name(escape_xml(&item.author));
+            person.set_uri(item.author_uri.as_deref().map(escape_xml));
+            entry.set_authors(vec![person]);
+
+            if let Some(uri) = &item.link {
+                entry.set_links(vec![
+                    atom_link(uri, "alternate", Some("text/markdown")),
+                    atom_link(uri, "via", Some("text/markdown")),
+                ]);
+            }
+
+            entry.set_categories(
+                item.tags
+                    .iter()
+                    .map(|tag| {
+                        let mut category = Category::default();
+                        category.set_term(tag.as_str());
+                        category
+                    })
+                    .collect::<Vec<Category>>(),
+            );
+
+            // atom has no avatar in person constructs, it goes in our namespace
+            if let Some(v) = &item.avatar {
+                let mut avatar = Extension::default();
+                avatar.set_name("prs:avatar");
+                avatar.set_value(escape_xml(v));
+                let mut extensions = HashMap::new();
+                extensions.insert(String::from("avatar"), vec![avatar]);
+                let mut extension_map = HashMap::new();
+                extension_map.insert(String::from("prs"), extensions);
+                entry.set_extensions(extension_map);
+            }
+
+            // markdown and html are escaped text, xhtml is written as markup
+            let mut content = Content::default();
+            match self.content_format {
+                ContentFormat::Markdown => {
+                    content.set_content_type("text/markdown".to_string());
+                    content.set_value(escape_xml(&item.content));
+                }
+                ContentFormat::Html => {
+                    content.set_content_type("html".to_string());
+                    content.set_value(escape_xml(&item.content_html));
+                }
+                ContentFormat::Xhtml => {
+                    content.set_content_type("xhtml".to_string());
+                    content.set_value(format!(
+                        r#"<div xmlns="http://www.w3.org/1999/xhtml">{}</div>"#,
+                        render::html_to_xhtml(&item.content_html)
+                    ));
+                }
+            }
+            entry.set_content(content);
+
+            entries.push(entry);
+        }
+        feed.set_entries(entries);
+
+        feed.to_string()
+    }
+
+    pub fn to_rss(&self) -> String {
+        use rss::{Category, Channel, Guid, Image};
+
+        let mut channel = Channel::default();
+        channel.set_title(self.title.as_str());
+        channel.set_link(
+            self.link
+                .clone()
+                .or_else(|| self.self_link.clone())
+                .unwrap_or_default(),
+        );
+        channel.set_description(self.subtitle.clone().unwrap_or_else(|| self.title.clone()));
+        channel.set_generator(String::from(GENERATOR));
+        channel.set_last_build_date(self.updated.to_rfc2822());
+        if let (Some(icon), Some(link)) = (&self.icon, &self.link) {
+            let mut image = Image::default();
+            image.set_url(icon.as_str());
+            image.set_title(self.title.as_str());
+            image.set_link(link.as_str());
+            channel.set_image(image);
+        }
+        // the items use dc:creator and content:encoded
+        let mut namespaces = HashMap::new();
+        namespaces.insert(String::from("dc"), String::from(dublincore::NAMESPACE));
+        namespaces.insert(String::from("content"), String::from(RSS_CONTENT_NAMESPACE));
+        channel.set_namespaces(namespaces);
+
+        let mut items = Vec::new();
+        for item in &self.items {
+            let mut rss_item = rss::Item::default();
+            rss_item.set_title(item.title.clone());
+            rss_item.set_link(item.link.clone());
+            rss_item.set_description(item.summary.clone());
+            rss_item.set_content(item.content_html.clone());
+            rss_item.set_pub_date(item.published.to_rfc2822());
+
+            // the publish_tx_id is not a url
+            let mut guid = Guid::default();
+            guid.set_value(item.id.as_str());
+            guid.set_permalink(false);
+            rss_item.set_guid(guid);
+
+            // rss <author> must be an email address, the name goes in dc:creator
+            let mut dublin_core = DublinCoreExtension::default();
+            dublin_core.set_creators(vec![item.author.clone()]);
+            rss_item.set_dublin_core_ext(dublin_core);
+
+            rss_item.set_categories(
+                item.tags
+                    .iter()
+                    .map(|tag| {
+                        let mut category = Category::default();
+                        category.set_name(tag.as_str());
+                        category
+                    })
+                    .collect::<Vec<Category>>(),
+            );
+            items.push(rss_item);
+        }
+        channel.set_items(items);
+
+        channel.to_string()
+    }
+
+    pub fn to_json_feed(&self) -> String {
+        let feed = JsonFeed {
+            version: JSON_FEED_VERSION,
+            title: &self.title,
+            home_page_url: self.link.as_ref(),
+            feed_url: self.self_link.as_ref(),
+            description: self.subtitle.as_ref(),
+            icon: self.icon.as_ref(),
+            items: self
+                .items
+                .iter()
+                .map(|item| JsonFeedItem {
+                    id: &item.id,
+                    external_url: item.link.as_ref(),
+                    title: &item.title,
+                    content_text: &item.content,
+                    content_html: &item.content_html,
+                    summary: item.summary.as_ref(),
+                    date_published: to_rfc3339(&item.published),
+                    date_modified: to_rfc3339(&item.updated),
+                    authors: vec![JsonFeedAuthor {
+                        name: &item.author,
+                        url: item.author_uri.as_ref(),
+                        avatar: item.avatar.as_ref(),
+                    }],
+                    tags: &item.tags,
+                })
+                .collect(),
+        };
+
+        serde_json::to_string(&feed).expect("serialize json feed failed")
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    home_page_url: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feed_url: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<&'a String>,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    external_url: Option<&'a String>,
+    title: &'a str,
+    content_text: &'a str,
+    content_html: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a String>,
+    date_published: String,
+    date_modified: String,
+    authors: Vec<JsonFeedAuthor<'a>>,
+    #[serde(skip_serializing_if = "is_empty")]
+    tags: &'a [String],
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<&'a String>,
+}
+
+fn atom_link(href: &str, rel: &str, mime_type: Option<&str>) -> atom_syndication::Link {
+    let mut link = atom_syndication::Link::default();
+    link.set_href(href);
+    link.set_rel(rel);
+    link.set_mime_type(mime_type.map(|v| v.to_string()));
+    link
+}
+
+/// Escape `&`, `<` and `>` of an xml text node.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn is_empty(v: &&[String]) -> bool {
+    v.is_empty()
+}
+
+fn none_if_empty(v: String) -> Option<String> {
+    if v.is_empty() {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+fn to_utc(datetime: NaiveDateTime) -> DateTime<FixedOffset> {
+    DateTime::from_utc(datetime, FixedOffset::east(0))
+}
+
+fn to_rfc3339(datetime: &DateTime<FixedOffset>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The text of the first ATX heading of the markdown, like `# Hello`.
+fn get_heading(body: &str) -> Option<String> {
+    body.lines()
+        .map(|v| v.trim())
+        .filter(|v| v.starts_with('#'))
+        .map(|v| v.trim_start_matches('#'))
+        .filter(|v| v.is_empty() || v.starts_with(' '))
+        .map(|v| v.trim_end_matches('#').trim().to_string())
+        .find(|v| !v.is_empty())
+}
+
+/// The first text paragraph of the markdown, cut to `SUMMARY_MAX_CHARS`.
+fn get_summary(body: &str) -> String {
+    let paragraph = body
+        .split("\n\n")
+        .map(|v| v.trim())
+        .find(|v| !v.is_empty() && !v.starts_with('#') && !v.starts_with("!["))
+        .unwrap_or("");
+    let text = paragraph
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if text.chars().count() <= SUMMARY_MAX_CHARS {
+        return text;
+    }
+    let mut summary: String = text.chars().take(SUMMARY_MAX_CHARS).collect();
+    summary.push('…');
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::NaiveDate;
+
+    const CONTENT: &str = "---
+title: Hello
+author: alice
+avatar: https://example.com/alice.png
+published: 2020-03-01T08:00:00+08:00
+tags: [rust, atom]
+---
+
+# Hello
+
+The first paragraph
+of the post.
+
+The second paragraph.
+";
+
+    fn post(uris: &str) -> PostPartial {
+        PostPartial {
+            publish_tx_id: String::from("a1"),
+            file_hash: String::from("b1"),
+            topic: String::from("t1"),
+            deleted: false,
+            user_address: String::from("u1"),
+            updated_tx_id: String::from(""),
+            updated_at: NaiveDate::from_ymd(2020, 3, 2).and_hms(1, 2, 3),
+            url: String::from("https://example.com/post.md"),
+            uris: uris.to_string(),
+        }
+    }
+
+    fn content(text: &str) -> Content {
+        let now = NaiveDate::from_ymd(2020, 3, 2).and_hms(1, 2, 3);
+        Content {
+            file_hash: String::from("b1"),
+            url: String::from("https://example.com/post.md"),
+            content: text.to_string(),
+            created_at: now,
+            updated_at: now,
+            deleted: false,
+        }
+    }
+
+    fn item(feed_conf: &FeedConf, post: &PostPartial, text: &str) -> Item {
+        let published_at = NaiveDate::from_ymd(2020, 3, 1).and_hms(0, 0, 0);
+        let attrs = frontmatter::parse(text).expect("invalid frontmatter");
+        Item::new(feed_conf, post, &content(text), &attrs, published_at)
+    }
+
+    fn feed_conf() -> FeedConf {
+        FeedConf {
+            id: Some(String::from("tag:example.com,2020:feed")),
+            title: Some(String::from("Example")),
+            subtitle: Some(String::from("posts from example")),
+            link: Some(String::from("https://example.com/")),
+            self_link: Some(String::from("https://example.com/atom")),
+            icon: Some(String::from("https://example.com/favicon.ico")),
+            author_uri_prefix: Some(String::from("https://example.com/users/")),
+            content_format: None,
+        }
+    }
+
+    fn feed() -> Feed {
+        let items = vec![
+            item(&feed_conf(), &post(""), CONTENT),
+            item(&feed_conf(), &post(""), "plain"),
+        ];
+        Feed::new("t1", &feed_conf(), items)
+    }
+
+    fn is_date(v: &str) -> bool {
+        DateTime::parse_from_rfc3339(v).is_ok()
+    }
+
+    /// Checks a hand-picked subset of the RFC 4287 constraints on feed and
+    /// entry elements, this is not a validation against the RFC's schema.
+    fn assert_rfc4287(xml: &str) {
+        assert!(xml.contains(r#"xmlns="http://www.w3.org/2005/Atom""#));
+        let feed = atom_syndication::Feed::read_from(xml.as_bytes()).expect("invalid atom xml");
+        assert!(!feed.id().is_empty(), "atom:feed MUST contain one atom:id");
+        assert!(
+            !feed.title().is_empty(),
+            "atom:feed MUST contain one atom:title"
+        );
+        assert!(is_date(feed.updated()), "updated = {}", feed.updated());
+        assert!(feed.generator().is_some());
+        for entry in feed.entries() {
+            assert!(!entry.id().is_empty());
+            assert!(
+                !entry.title().is_empty(),
+                "atom:entry MUST contain one atom:title"
+            );
+            assert!(is_date(entry.updated()), "updated = {}", entry.updated());
+            if let Some(v) = entry.published() {
+                assert!(is_date(v), "published = {}", v);
+            }
+            assert!(!entry.authors().is_empty() || !feed.authors().is_empty());
+            for person in entry.authors() {
+                assert!(!person.name().is_empty());
+            }
+            let alternates = entry
+                .links()
+                .iter()
+                .filter(|v| v.rel() == "alternate")
+                .count();
+            assert!(alternates <= 1);
+            if entry.content().is_none() {
+                assert!(alternates == 1);
+            }
+        }
+    }
+
+    #[test]
+    fn item_from_frontmatter() {
+        let item = item(&feed_conf(), &post(""), CONTENT);
+        assert_eq!(item.title, "Hello");
+        assert_eq!(to_rfc3339(&item.updated), "2020-03-02T01:02:03Z");
+        assert_eq!(to_rfc3339(&item.published), "2020-03-01T08:00:00+08:00");
+        assert_eq!(
+            item.summary,
+            Some(String::from("The first paragraph of the post."))
+        );
+        assert_eq!(item.tags, vec!["rust", "atom"]);
+        assert_eq!(item.author, "alice");
+        assert_eq!(
+            item.author_uri,
+            Some(String::from("https://example.com/users/u1"))
+        );
+        assert_eq!(
+            item.avatar,
+            Some(String::from("https://example.com/alice.png"))
+        );
+        assert_eq!(item.link, Some(String::from("https://example.com/post.md")));
+    }
+
+    #[test]
+    fn item_falls_back_to_post() {
+        let text = "no frontmatter here";
+        let item = item(
+            &FeedConf::default(),
+            &post(r#"["data:text/markdown,hello", "ipfs://QmHash"]"#),
+            text,
+        );
+        assert_eq!(to_rfc3339(&item.published), "2020-03-01T00:00:00Z");
+        assert_eq!(item.author, "u1");
+        assert_eq!(item.author_uri, None);
+        assert_eq!(item.link, Some(String::from("ipfs://QmHash")));
+        assert_eq!(item.summary, Some(String::from(text)));
+        assert_eq!(item.title, "a1");
+    }
+
+    #[test]
+    fn item_title_from_heading() {
+        let text = "---\nauthor: alice\n---\n\n#hashtag\n\n## Second ##\n\nbody";
+        assert_eq!(item(&FeedConf::default(), &post(""), text).title, "Second");
+    }
+
+    #[test]
+    fn atom_is_valid() {
+        let xml = feed().to_atom();
+        assert_rfc4287(&xml);
+
+        let atom = atom_syndication::Feed::read_from(xml.as_bytes()).unwrap();
+        assert_eq!(atom.id(), "tag:example.com,2020:feed");
+        assert_eq!(atom.subtitle(), Some("posts from example"));
+        assert_eq!(atom.icon(), Some("https://example.com/favicon.ico"));
+        assert_eq!(atom.updated(), "2020-03-02T01:02:03Z");
+        assert_eq!(atom.links().len(), 2);
+        let entry = &atom.entries()[0];
+        let links: Vec<(&str, &str)> = entry.links().iter().map(|v| (v.rel(), v.href())).collect();
+        assert_eq!(
+            links,
+            vec![
+                ("alternate", "https://example.com/post.md"),
+                ("via", "https://example.com/post.md"),
+            ]
+        );
+        let terms: Vec<&str> = entry.categories().iter().map(|v| v.term()).collect();
+        assert_eq!(terms, vec!["rust", "atom"]);
+        assert_eq!(
+            entry.authors()[0].uri(),
+            Some("https://example.com/users/u1")
+        );
+        assert!(xml.contains("<prs:avatar>https://example.com/alice.png</prs:avatar>"));
+
+        // an empty feed still has every required element
+        let xml = Feed::new("t1", &FeedConf::default(), Vec::new()).to_atom();
+        assert_rfc4287(&xml);
+        assert!(xml.contains("urn:prs:topic:t1"));
+    }
+
+    #[test]
+    fn atom_content_formats() {
+        let mut feed = feed();
+        feed.items[0].title = String::from("Q&A <draft>");
+        let entry_content = |feed: &Feed| {
+            let xml = feed.to_atom();
+            assert_rfc4287(&xml);
+            let atom = atom_syndication::Feed::read_from(xml.as_bytes()).unwrap();
+            let content = atom.entries()[0].content().unwrap().clone();
+            (xml, content)
+        };
+
+        // the markdown is written as escaped text, no literal CDATA markers
+        let (xml, content) = entry_content(&feed);
+        assert!(!xml.contains("CDATA"));
+        assert!(xml.contains("<title>Q&amp;A &lt;draft&gt;</title>"));
+        assert_eq!(content.content_type(), Some("text/markdown"));
+        assert_eq!(content.value().map(|v| v.trim()), Some(CONTENT.trim()));
+
+        feed.content_format = ContentFormat::Html;
+        let (xml, content) = entry_content(&feed);
+        assert!(xml.contains("&lt;h1&gt;Hello&lt;/h1&gt;"));
+        assert_eq!(content.content_type(), Some("html"));
+        assert!(!content.value().unwrap().contains("title:"));
+
+        feed.content_format = ContentFormat::Xhtml;
+        let (xml, content) = entry_content(&feed);
+        assert!(xml.contains(r#"<div xmlns="http://www.w3.org/1999/xhtml"><h1>Hello</h1>"#));
+        assert_eq!(content.content_type(), Some("xhtml"));
+    }
+
+    #[test]
+    fn rss_is_valid() {
+        let xml = feed().to_rss();
+        let channel = rss::Channel::read_from(xml.as_bytes()).expect("invalid rss xml");
+        assert_eq!(channel.title(), "Example");
+        assert_eq!(channel.link(), "https://example.com/");
+        assert_eq!(channel.description(), "posts from example");
+        let item = &channel.items()[0];
+        assert_eq!(item.title(), Some("Hello"));
+        assert_eq!(item.link(), Some("https://example.com/post.md"));
+        assert_eq!(item.guid().map(|v| v.value()), Some("a1"));
+        assert_eq!(item.guid().map(|v| v.is_permalink()), Some(false));
+        assert!(DateTime::parse_from_rfc2822(item.pub_date().unwrap()).is_ok());
+        assert_eq!(
+            item.dublin_core_ext().map(|v| v.creators().to_vec()),
+            Some(vec![String::from("alice")])
+        );
+        assert!(item.content().unwrap().contains("<h1>Hello</h1>"));
+        assert!(xml.contains(r#"xmlns:content="http://purl.org/rss/1.0/modules/content/""#));
+        assert_eq!(item.categories().len(), 2);
+    }
+
+    #[test]
+    fn json_feed_is_valid() {
+        let json: serde_json::Value = serde_json::from_str(&feed().to_json_feed()).unwrap();
+        assert_eq!(json["version"], JSON_FEED_VERSION);
+        assert_eq!(json["title"], "Example");
+        assert_eq!(json["feed_url"], "https://example.com/atom");
+        let item = &json["items"][0];
+        assert_eq!(item["id"], "a1");
+        assert_eq!(item["content_text"], CONTENT);
+        assert!(item["content_html"]
+            .as_str()
+            .unwrap()
+            .contains("<h1>Hello</h1>"));
+        assert_eq!(item["date_published"], "2020-03-01T08:00:00+08:00");
+        assert_eq!(item["authors"][0]["name"], "alice");
+        assert_eq!(
+            item["authors"][0]["avatar"],
+            "https://example.com/alice.png"
+        );
+        assert_eq!(item["tags"], json!(["rust", "atom"]));
+        // optional members are left out instead of written as null
+        assert!(json["items"][1].get("tags").is_none());
+        assert!(json["items"][1]["authors"][0].get("avatar").is_none());
+    }
+
+    #[test]
+    fn format_negotiation() {
+        assert_eq!("rss".parse::<Format>().ok(), Some(Format::Rss));
+        assert_eq!("json".parse::<Format>().ok(), Some(Format::JsonFeed));
+        assert!("xml".parse::<Format>().is_err());
+        assert_eq!(
+            Format::from_accept("application/rss+xml, application/atom+xml;q=0.9"),
+            Some(Format::Rss)
+        );
+        assert_eq!(
+            Format::from_accept("text/html, application/feed+json"),
+            Some(Format::JsonFeed)
+        );
+        assert_eq!(Format::from_accept("*/*"), None);
+    }
+}
@@ -0,0 +1,435 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::SETTINGS;
+use crate::metrics;
+use crate::pipeline;
+use crate::prs;
+use crate::prs_utility_rust::utility;
+
+const DEFAULT_IPFS_GATEWAY: &str = "http://127.0.0.1:8080";
+
+/// A non-200 response from a content host.
+#[derive(Debug)]
+pub struct StatusCodeError {
+    pub url: String,
+    pub status_code: u32,
+}
+
+impl fmt::Display for StatusCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "url = {} error status code: {:?}",
+            self.url, self.status_code
+        )
+    }
+}
+
+impl std::error::Error for StatusCodeError {}
+
+pub trait ContentBackend {
+    fn fetch(&self, uri: &str) -> Result<String>;
+}
+
+pub struct HttpBackend;
+
+impl ContentBackend for HttpBackend {
+    fn fetch(&self, uri: &str) -> Result<String> {
+        fetch_markdown(uri)
+    }
+}
+
+/// Reads `file://` uris from under `root`. The uris come from the chain, so
+/// without a root every `file://` uri is rejected, and a path which resolves
+/// outside of it, through `..` or a symlink, is rejected too.
+pub struct FileBackend {
+    root: Option<PathBuf>,
+}
+
+impl FileBackend {
+    pub fn new(root: Option<&str>) -> FileBackend {
+        FileBackend {
+            root: root.map(PathBuf::from),
+        }
+    }
+
+    fn resolve_path(&self, uri: &str) -> Result<PathBuf> {
+        let root = match &self.root {
+            Some(v) => v
+                .canonicalize()
+                .map_err(|e| anyhow!("atom.file_root {} is invalid: {}", v.display(), e))?,
+            None => return Err(anyhow!("file:// uris are disabled, set atom.file_root")),
+        };
+        let path = uri.trim_start_matches("file://");
+        let path = path.trim_start_matches("localhost");
+        let path = Path::new(path)
+            .canonicalize()
+            .map_err(|e| anyhow!("read {} failed: {}", uri, e))?;
+        if !path.starts_with(&root) {
+            return Err(anyhow!(
+                "{} is outside of atom.file_root {}",
+                uri,
+                root.display()
+            ));
+        }
+        Ok(path)
+    }
+}
+
+impl ContentBackend for FileBackend {
+    fn fetch(&self, uri: &str) -> Result<String> {
+        let path = self.resolve_path(uri)?;
+        fs::read_to_string(path).map_err(|e| anyhow!("read {} failed: {}", uri, e))
+    }
+}
+
+pub struct DataBackend;
+
+impl ContentBackend for DataBackend {
+    fn fetch(&self, uri: &str) -> Result<String> {
+        parse_data_uri(uri)
+    }
+}
+
+/// Resolves `ipfs://` and `ipns://` uris through a local HTTP gateway.
+pub struct IpfsBackend {
+    gateway: String,
+}
+
+impl IpfsBackend {
+    pub fn new(gateway: &str) -> IpfsBackend {
+        IpfsBackend {
+            gateway: gateway.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn gateway_url(&self, uri: &str) -> Result<String> {
+        for scheme in &["ipfs", "ipns"] {
+            let prefix = format!("{}://", scheme);
+            if uri.starts_with(&prefix) {
+                let path = &uri[prefix.len()..];
+                if path.is_empty() {
+                    return Err(anyhow!("invalid uri: {}", uri));
+                }
+                return Ok(format!("{}/{}/{}", self.gateway, scheme, path));
+            }
+        }
+        Err(anyhow!("not an ipfs uri: {}", uri))
+    }
+}
+
+impl ContentBackend for IpfsBackend {
+    fn fetch(&self, uri: &str) -> Result<String> {
+        fetch_markdown(&self.gateway_url(uri)?)
+    }
+}
+
+/// The result of trying every uri of a post.
+pub enum Resolved {
+    /// the body hashes to `file_hash`
+    Verified { uri: String, body: String },
+    /// bodies were fetched but none of them hashes to `file_hash`, this is
+    /// the first one
+    Mismatch {
+        uri: String,
+        body: String,
+        hash: String,
+    },
+    /// every uri answered 404
+    NotFound,
+    /// nothing verified and a uri was not tried because its host was at its
+    /// request limit, try again later
+    Deferred,
+    /// the last error when nothing could be fetched
+    Failed(anyhow::Error),
+}
+
+/// Picks a backend by uri scheme: http(s), `file://` under `atom.file_root`,
+/// `data:`, and `ipfs://`/`ipns://` through the `atom.ipfs_gateway`.
+pub struct ContentResolver {
+    http: HttpBackend,
+    file: FileBackend,
+    data: DataBackend,
+    ipfs: IpfsBackend,
+}
+
+impl ContentResolver {
+    pub fn new(ipfs_gateway: &str, file_root: Option<&str>) -> ContentResolver {
+        ContentResolver {
+            http: HttpBackend,
+            file: FileBackend::new(file_root),
+            data: DataBackend,
+            ipfs: IpfsBackend::new(ipfs_gateway),
+        }
+    }
+
+    pub fn from_settings() -> ContentResolver {
+        let settings = SETTINGS.get();
+        let gateway = match &settings.atom.ipfs_gateway {
+            Some(v) => v.as_str(),
+            None => DEFAULT_IPFS_GATEWAY,
+        };
+        ContentResolver::new(gateway, settings.atom.file_root.as_deref())
+    }
+
+    fn backend(&self, uri: &str) -> Result<&dyn ContentBackend> {
+        let scheme = match uri.find(':') {
+            Some(offset) => uri[..offset].to_lowercase(),
+            None => return Err(anyhow!("uri without scheme: {}", uri)),
+        };
+        match scheme.as_str() {
+            "http" | "https" => Ok(&self.http),
+            "file" => Ok(&self.file),
+            "data" => Ok(&self.data),
+            "ipfs" | "ipns" => Ok(&self.ipfs),
+            _ => Err(anyhow!("unsupport uri scheme: {}", uri)),
+        }
+    }
+
+    pub fn fetch(&self, uri: &str) -> Result<String> {
+        self.backend(uri)?.fetch(uri)
+    }
+
+    /// Try `uris` in order until a body, after `decode`, hashes to
+    /// `file_hash`.
+    pub fn resolve<F>(
+        &self,
+        uris: &[String],
+        file_hash: &str,
+        hash_alg: &str,
+        decode: F,
+    ) -> Resolved
+    where
+        F: Fn(String) -> Result<String>,
+    {
+        let mut mismatch = None;
+        let mut not_found = 0;
+        let mut busy = false;
+        let mut last_error = anyhow!("no uris");
+
+        for uri in uris {
+            let body = match self.fetch(uri).and_then(|data| decode(data)) {
+                Ok(v) => v,
+                Err(e) => {
+                    if is_not_found(&e) {
+                        not_found += 1;
+                    }
+                    busy |= pipeline::is_host_busy(&e);
+                    debug!("fetch uri = {} failed: {}", uri, e);
+                    last_error = e;
+                    continue;
+                }
+            };
+
+            let hash = match utility::hash_text(&body, hash_alg).ok() {
+                Some(v) => v,
+                None => {
+                    last_error = anyhow!("utility::hash_text failed, hash_alg = {}", hash_alg);
+                    continue;
+                }
+            };
+            if hash == file_hash {
+                return Resolved::Verified {
+                    uri: uri.clone(),
+                    body,
+                };
+            }
+            debug!(
+                "uri = {} hash = {} does not match file_hash = {}",
+                uri, hash, file_hash
+            );
+            if mismatch.is_none() {
+                mismatch = Some((uri.clone(), body, hash));
+            }
+        }
+
+        if busy {
+            Resolved::Deferred
+        } else if let Some((uri, body, hash)) = mismatch {
+            Resolved::Mismatch { uri, body, hash }
+        } else if !uris.is_empty() && not_found == uris.len() {
+            Resolved::NotFound
+        } else {
+            Resolved::Failed(last_error)
+        }
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<StatusCodeError>() {
+        Some(v) => v.status_code == 404,
+        None => false,
+    }
+}
+
+/// GET `url`, fails with `pipeline::HostBusy` when its host has no free
+/// request slot.
+pub fn fetch_markdown(url: &str) -> Result<String> {
+    pipeline::with_host_limit(url, || get_markdown(url))
+}
+
+fn get_markdown(url: &str) -> Result<String> {
+    let mut easy = prs::get_curl_easy_with_timeout(pipeline::request_timeout())?;
+    easy.url(&url)?;
+    let _redirect = easy.follow_location(true);
+    let mut data = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|new_data| {
+            data.extend_from_slice(new_data);
+            Ok(new_data.len())
+        })?;
+        if let Err(e) = transfer.perform() {
+            metrics::content_fetched(None);
+            return Err(e.into());
+        }
+    };
+
+    let result = easy.response_code();
+    metrics::content_fetched(result.as_ref().ok().copied());
+    match result {
+        Ok(respcode) => {
+            if respcode == 200 {
+                String::from_utf8(data).map_err(|e| anyhow!("body is not valid UTF8: {}", e))
+            } else {
+                Err(anyhow::Error::new(StatusCodeError {
+                    url: url.to_string(),
+                    status_code: respcode,
+                }))
+            }
+        }
+        Err(e) => Err(anyhow!("url = {} error = {}", url, e)),
+    }
+}
+
+/// `data:[<mediatype>][;base64],<data>`, see RFC 2397
+fn parse_data_uri(uri: &str) -> Result<String> {
+    let rest = match uri.get(..5) {
+        Some(v) if v.eq_ignore_ascii_case("data:") => &uri[5..],
+        _ => return Err(anyhow!("not a data uri: {}", uri)),
+    };
+    let offset = rest
+        .find(',')
+        .ok_or_else(|| anyhow!("invalid data uri: {}", uri))?;
+    let (meta, data) = (&rest[..offset], &rest[offset + 1..]);
+
+    let bytes = if meta.to_lowercase().ends_with(";base64") {
+        base64::decode(&percent_decode(data)?)
+            .map_err(|e| anyhow!("base64 decode data uri failed: {}", e))?
+    } else {
+        percent_decode(data)?
+    };
+    String::from_utf8(bytes).map_err(|e| anyhow!("data uri is not valid UTF8: {}", e))
+}
+
+fn percent_decode(s: &str) -> Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = s
+                .get(idx + 1..idx + 3)
+                .ok_or_else(|| anyhow!("invalid percent encoding: {}", s))?;
+            result.push(u8::from_str_radix(hex, 16)?);
+            idx += 3;
+        } else {
+            result.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_uri() {
+        assert_eq!(
+            parse_data_uri("data:text/markdown,%23%20title%0Abody").unwrap(),
+            "# title\nbody"
+        );
+        assert_eq!(
+            parse_data_uri("data:text/markdown;charset=utf-8;base64,IyB0aXRsZQpib2R5").unwrap(),
+            "# title\nbody"
+        );
+        assert_eq!(parse_data_uri("data:,").unwrap(), "");
+        assert!(parse_data_uri("data:text/plain").is_err());
+        assert!(parse_data_uri("data:,%zz").is_err());
+    }
+
+    #[test]
+    fn ipfs_gateway_url() {
+        let ipfs = IpfsBackend::new("http://127.0.0.1:8080/");
+        assert_eq!(
+            ipfs.gateway_url("ipfs://QmHash/post.md").unwrap(),
+            "http://127.0.0.1:8080/ipfs/QmHash/post.md"
+        );
+        assert_eq!(
+            ipfs.gateway_url("ipns://example.com").unwrap(),
+            "http://127.0.0.1:8080/ipns/example.com"
+        );
+        assert!(ipfs.gateway_url("ipfs://").is_err());
+        assert!(ipfs.gateway_url("https://example.com").is_err());
+    }
+
+    #[test]
+    fn file_uri_stays_under_root() {
+        let dir = std::env::temp_dir().join(format!("atom-file-root-{}", std::process::id()));
+        let root = dir.join("posts");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("post.md"), "# title").unwrap();
+        fs::write(dir.join("Settings.toml"), "secret").unwrap();
+
+        let uri = |path: &Path| format!("file://{}", path.display());
+        let backend = FileBackend::new(root.to_str());
+        assert_eq!(
+            backend.fetch(&uri(&root.join("post.md"))).unwrap(),
+            "# title"
+        );
+        assert!(backend.fetch(&uri(&root.join("../Settings.toml"))).is_err());
+        assert!(backend.fetch(&uri(&dir.join("Settings.toml"))).is_err());
+        assert!(FileBackend::new(None)
+            .fetch(&uri(&root.join("post.md")))
+            .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_tries_every_uri() {
+        let resolver = ContentResolver::new(DEFAULT_IPFS_GATEWAY, None);
+        let file_hash = utility::hash_text("# title", "sha256").ok().unwrap();
+        let uris = vec![
+            String::from("gopher://example.com/post.md"),
+            String::from("data:,%23%20other"),
+            String::from("data:,%23%20title"),
+        ];
+
+        match resolver.resolve(&uris, &file_hash, "sha256", |data| Ok(data)) {
+            Resolved::Verified { uri, body } => {
+                assert_eq!(uri, uris[2]);
+                assert_eq!(body, "# title");
+            }
+            _ => panic!("expect Resolved::Verified"),
+        }
+
+        match resolver.resolve(&uris[..2], &file_hash, "sha256", |data| Ok(data)) {
+            Resolved::Mismatch { uri, body, .. } => {
+                assert_eq!(uri, uris[1]);
+                assert_eq!(body, "# other");
+            }
+            _ => panic!("expect Resolved::Mismatch"),
+        }
+
+        match resolver.resolve(&uris[..1], &file_hash, "sha256", |data| Ok(data)) {
+            Resolved::Failed(_) => {}
+            _ => panic!("expect Resolved::Failed"),
+        }
+    }
+}
@@ -1,52 +1,317 @@
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use chrono::prelude::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+use serde_json::{Map, Value};
 
+const YAML_DELIMITER: &str = "---";
+const TOML_DELIMITER: &str = "+++";
+
+/// The frontmatter of a post. Known fields are typed, missing ones are empty,
+/// everything else is kept in `extra`.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct MarkdownAttrs {
     pub title: String,
     pub author: String,
     pub avatar: String,
-    pub published: String,
+    pub published: Option<DateTime<FixedOffset>>,
+    pub tags: Vec<String>,
+    pub summary: String,
+    pub cover: String,
+    pub lang: String,
+    pub extra: Map<String, Value>,
+}
+
+impl MarkdownAttrs {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "title": self.title,
+            "author": self.author,
+            "avatar": self.avatar,
+            "published": self.published.map(|v| v.to_rfc3339()),
+            "tags": self.tags,
+            "summary": self.summary,
+            "cover": self.cover,
+            "lang": self.lang,
+            "extra": self.extra,
+        })
+    }
 }
 
-pub fn parse(mdtext: &str) -> MarkdownAttrs {
-    let mut frontmatter_flag = false;
-    let mut frontmatter_fields = HashMap::new();
-    frontmatter_fields.insert("title".to_string(), "".to_string());
-    frontmatter_fields.insert("author".to_string(), "".to_string());
-    frontmatter_fields.insert("avatar".to_string(), "".to_string());
-    frontmatter_fields.insert("published".to_string(), "".to_string());
+/// Parse the YAML (`---`) or TOML (`+++`) frontmatter at the start of the
+/// markdown. A post without frontmatter has empty attrs, a malformed block or
+/// a known field of the wrong type is an error.
+pub fn parse(mdtext: &str) -> Result<MarkdownAttrs> {
+    let (delimiter, block) = match split(mdtext)? {
+        Some(v) => v,
+        None => return Ok(MarkdownAttrs::default()),
+    };
 
-    for (index, line) in mdtext.lines().enumerate() {
-        if index == 0 && line.trim() == "---" {
-            frontmatter_flag = true;
+    let fields = match delimiter {
+        YAML_DELIMITER => parse_yaml(block)?,
+        _ => parse_toml(block)?,
+    };
+    from_fields(fields)
+}
+
+/// The markdown after the frontmatter block.
+pub fn body(mdtext: &str) -> &str {
+    match split(mdtext) {
+        Ok(Some((_, block))) => {
+            // skip the block and the closing delimiter line after it
+            let offset = block.as_ptr() as usize - mdtext.as_ptr() as usize + block.len();
+            let rest = &mdtext[offset..];
+            match rest.find('\n') {
+                Some(v) => &rest[v + 1..],
+                None => "",
+            }
         }
-        if index > 0 && line.trim() == "---" && frontmatter_flag {
-            break;
+        _ => mdtext,
+    }
+}
+
+/// The delimiter and the text between the opening and closing delimiter
+/// lines, `None` when the markdown does not start with a delimiter.
+fn split(mdtext: &str) -> Result<Option<(&'static str, &str)>> {
+    let first_line_end = mdtext.find('\n').unwrap_or_else(|| mdtext.len());
+    let delimiter = match mdtext[..first_line_end].trim() {
+        YAML_DELIMITER => YAML_DELIMITER,
+        TOML_DELIMITER => TOML_DELIMITER,
+        _ => return Ok(None),
+    };
+
+    let start = std::cmp::min(first_line_end + 1, mdtext.len());
+    let mut offset = start;
+    for line in mdtext[start..].split('\n') {
+        if line.trim() == delimiter {
+            return Ok(Some((delimiter, &mdtext[start..offset])));
         }
+        offset += line.len() + 1;
+    }
+
+    Err(anyhow!("frontmatter is not closed by {}", delimiter))
+}
 
-        if frontmatter_flag && index > 0 {
-            let mut s = String::from(line);
-            let offset = s.find(':').unwrap_or_else(|| s.len());
-            let key: String = s.drain(..offset).collect();
-            let val: String = s[1..].trim().to_string();
-            frontmatter_fields.insert(key, val);
+fn parse_yaml(block: &str) -> Result<Map<String, Value>> {
+    if block.trim().is_empty() {
+        return Ok(Map::new());
+    }
+    let value: serde_yaml::Value =
+        serde_yaml::from_str(block).map_err(|e| anyhow!("invalid yaml frontmatter: {}", e))?;
+    match serde_json::to_value(value)? {
+        Value::Object(v) => Ok(v),
+        v => Err(anyhow!("yaml frontmatter is not a mapping: {}", v)),
+    }
+}
+
+fn parse_toml(block: &str) -> Result<Map<String, Value>> {
+    let value: toml::Value =
+        toml::from_str(block).map_err(|e| anyhow!("invalid toml frontmatter: {}", e))?;
+    match toml_to_json(value) {
+        Value::Object(v) => Ok(v),
+        v => Err(anyhow!("toml frontmatter is not a table: {}", v)),
+    }
+}
+
+/// `toml::Value` serializes datetimes as a private struct, keep them as text.
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(v) => Value::String(v),
+        toml::Value::Integer(v) => json!(v),
+        toml::Value::Float(v) => json!(v),
+        toml::Value::Boolean(v) => Value::Bool(v),
+        toml::Value::Datetime(v) => Value::String(v.to_string()),
+        toml::Value::Array(v) => Value::Array(v.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(v) => Value::Object(
+            v.into_iter()
+                .map(|(key, val)| (key, toml_to_json(val)))
+                .collect(),
+        ),
+    }
+}
+
+fn from_fields(mut fields: Map<String, Value>) -> Result<MarkdownAttrs> {
+    let published = match fields.remove("published") {
+        Some(Value::Null) | None => None,
+        Some(Value::String(v)) => {
+            Some(parse_date(&v).ok_or_else(|| anyhow!("published = {} is not a valid date", v))?)
         }
+        Some(v) => return Err(anyhow!("published = {} is not a valid date", v)),
+    };
+
+    Ok(MarkdownAttrs {
+        title: take_string(&mut fields, "title")?,
+        author: take_string(&mut fields, "author")?,
+        avatar: take_string(&mut fields, "avatar")?,
+        published,
+        tags: take_tags(&mut fields)?,
+        summary: take_string(&mut fields, "summary")?,
+        cover: take_string(&mut fields, "cover")?,
+        lang: take_string(&mut fields, "lang")?,
+        extra: fields,
+    })
+}
+
+fn take_string(fields: &mut Map<String, Value>, key: &str) -> Result<String> {
+    match fields.remove(key) {
+        Some(Value::Null) | None => Ok(String::new()),
+        Some(Value::String(v)) => Ok(v),
+        // a bare `title: 2020` is a number in yaml
+        Some(Value::Number(v)) => Ok(v.to_string()),
+        Some(v) => Err(anyhow!("{} = {} is not a string", key, v)),
     }
-    MarkdownAttrs {
-        title: frontmatter_fields
-            .get("title")
-            .expect("frontmatter_fields.get title failed")
-            .to_string(),
-        author: frontmatter_fields
-            .get("author")
-            .expect("frontmatter_fields.get author failed")
-            .to_string(),
-        avatar: frontmatter_fields
-            .get("avatar")
-            .expect("frontmatter_fields.get avatar failed")
-            .to_string(),
-        published: frontmatter_fields
-            .get("published")
-            .expect("frontmatter_fields.get published failed")
-            .to_string(),
+}
+
+/// `tags` is a list, or a comma separated string.
+fn take_tags(fields: &mut Map<String, Value>) -> Result<Vec<String>> {
+    let tags = match fields.remove("tags") {
+        Some(Value::Null) | None => Vec::new(),
+        Some(Value::String(v)) => v.split(',').map(|v| v.trim().to_string()).collect(),
+        Some(Value::Array(items)) => {
+            let mut tags = Vec::new();
+            for item in items {
+                match item {
+                    Value::String(v) => tags.push(v.trim().to_string()),
+                    Value::Number(v) => tags.push(v.to_string()),
+                    v => return Err(anyhow!("tag = {} is not a string", v)),
+                }
+            }
+            tags
+        }
+        Some(v) => return Err(anyhow!("tags = {} is not a list", v)),
+    };
+
+    Ok(tags.into_iter().filter(|v| !v.is_empty()).collect())
+}
+
+/// RFC 3339, a date and time without offset (UTC), which may have a
+/// fraction of seconds, or a bare date.
+pub fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.trim();
+    if let Ok(v) = DateTime::parse_from_rfc3339(s) {
+        return Some(v);
+    }
+    for fmt in &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(v) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Some(DateTime::from_utc(v, FixedOffset::east(0)));
+        }
+    }
+    if let Ok(v) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(DateTime::from_utc(v.and_hms(0, 0, 0), FixedOffset::east(0)));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_yaml_frontmatter() {
+        let attrs = parse(
+            r#"---
+title: "Hello: world"
+author: alice
+published: 2020-03-01T08:00:00+08:00
+tags:
+  - rust
+  - atom
+summary: >
+  a summary
+  on two lines
+lang: en
+series: intro
+---
+# Hello
+"#,
+        )
+        .unwrap();
+        assert_eq!(attrs.title, "Hello: world");
+        assert_eq!(attrs.author, "alice");
+        assert_eq!(
+            attrs.published.map(|v| v.to_rfc3339()),
+            Some(String::from("2020-03-01T08:00:00+08:00"))
+        );
+        assert_eq!(attrs.tags, vec!["rust", "atom"]);
+        assert_eq!(attrs.summary, "a summary on two lines\n");
+        assert_eq!(attrs.lang, "en");
+        assert_eq!(attrs.cover, "");
+        assert_eq!(attrs.extra.get("series"), Some(&json!("intro")));
+    }
+
+    #[test]
+    fn parse_toml_frontmatter() {
+        let attrs = parse(
+            r#"+++
+title = "Hello"
+published = 2020-03-01
+tags = ["rust"]
+cover = "https://example.com/cover.png"
+
+[series]
+name = "intro"
++++
+# Hello
+"#,
+        )
+        .unwrap();
+        assert_eq!(attrs.title, "Hello");
+        assert_eq!(
+            attrs.published.map(|v| v.to_rfc3339()),
+            Some(String::from("2020-03-01T00:00:00+00:00"))
+        );
+        assert_eq!(attrs.tags, vec!["rust"]);
+        assert_eq!(attrs.cover, "https://example.com/cover.png");
+        assert_eq!(attrs.extra.get("series"), Some(&json!({ "name": "intro" })));
+    }
+
+    #[test]
+    fn parse_without_frontmatter() {
+        assert_eq!(parse("# Hello\n").unwrap(), MarkdownAttrs::default());
+        assert_eq!(parse("").unwrap(), MarkdownAttrs::default());
+        assert_eq!(
+            parse("---\ntags: a, b\n---\n").unwrap().tags,
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("---\ntitle: Hello\n").is_err());
+        assert!(parse("---\ntitle: [a\n---\n").is_err());
+        assert!(parse("---\n- a\n- b\n---\n").is_err());
+        assert!(parse("---\npublished: last tuesday\n---\n").is_err());
+        assert!(parse("---\ntitle:\n  nested: map\n---\n").is_err());
+        assert!(parse("+++\ntitle = \n+++\n").is_err());
+        // the old parser panicked on lines without a colon
+        assert!(parse("---\njust a line\n---\n").is_err());
+    }
+
+    #[test]
+    fn body_after_frontmatter() {
+        assert_eq!(body("---\ntitle: a\n---\n# Hello\n"), "# Hello\n");
+        assert_eq!(body("+++\ntitle = \"a\"\n+++\nbody"), "body");
+        assert_eq!(body("---\ntitle: a\n---"), "");
+        assert_eq!(body("# Hello\n---\n"), "# Hello\n---\n");
+        assert_eq!(body("---\nnot closed\n"), "---\nnot closed\n");
+    }
+
+    #[test]
+    fn date_formats() {
+        let date = |v: &str| parse_date(v).map(|v| v.to_rfc3339());
+        assert_eq!(
+            date("2020-03-01"),
+            Some(String::from("2020-03-01T00:00:00+00:00"))
+        );
+        assert_eq!(
+            date("2020-03-01 10:00:00"),
+            Some(String::from("2020-03-01T10:00:00+00:00"))
+        );
+        // the format of updated_at in the json apis
+        assert_eq!(
+            date("2019-12-26T03:46:58.032960"),
+            Some(String::from("2019-12-26T03:46:58.032960+00:00"))
+        );
+        assert_eq!(date("last tuesday"), None);
+        assert_eq!(date(""), None);
     }
 }
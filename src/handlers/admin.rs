@@ -0,0 +1,289 @@
+use actix_web::dev::Payload;
+use actix_web::{error, web, Error, FromRequest, HttpRequest, HttpResponse, Result};
+use diesel::result::Error as DbError;
+use serde::Deserialize;
+
+use super::{DEFAULT_LIMIT, MAX_LIMIT};
+use crate::db;
+use crate::db::PgPool;
+use crate::handlers::pg_pool_handler;
+use crate::util;
+use crate::SETTINGS;
+
+/// Extracted by every `/admin` handler, rejects the request unless it has
+/// `Authorization: Bearer <atom.admin_token>`.
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Config = ();
+    type Error = Error;
+    type Future = Result<Self, Error>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = match &SETTINGS.get().atom.admin_token {
+            Some(v) => v.clone(),
+            None => {
+                return Err(error::InternalError::from_response(
+                    "admin api is disabled",
+                    HttpResponse::Forbidden().json("admin api is disabled, set atom.admin_token"),
+                )
+                .into())
+            }
+        };
+        let header = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok());
+        if is_authorized(header, &token) {
+            return Ok(AdminAuth);
+        }
+        Err(error::InternalError::from_response(
+            "invalid admin token",
+            HttpResponse::Unauthorized()
+                .header("WWW-Authenticate", "Bearer")
+                .json("invalid admin token"),
+        )
+        .into())
+    }
+}
+
+/// Compares the bearer token in constant time, only its length leaks.
+fn is_authorized(header: Option<&str>, token: &str) -> bool {
+    let mut parts = header.unwrap_or("").trim().splitn(2, ' ');
+    let scheme = parts.next().unwrap_or("");
+    let given = parts.next().unwrap_or("").trim();
+    scheme.eq_ignore_ascii_case("bearer")
+        && given.len() == token.len()
+        && openssl::memcmp::eq(given.as_bytes(), token.as_bytes())
+}
+
+fn db_error_response(e: DbError, not_found: &str) -> HttpResponse {
+    match e {
+        DbError::NotFound => HttpResponse::NotFound().json(not_found),
+        e => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CursorParams {
+    pub block_num: i64,
+}
+
+/// The block a topic is synced to, the next round reads the blocks after it.
+pub fn get_cursor(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    topic: web::Path<String>,
+) -> Result<HttpResponse> {
+    if !SETTINGS.get().contains_topic(&topic) {
+        return Ok(HttpResponse::NotFound().json("unknown topic"));
+    }
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_last_status(&db_conn, &util::get_last_block_num_by_topic(&topic)) {
+        Ok(v) => Ok(HttpResponse::Ok().json(json!({ "topic": *topic, "block_num": v.val }))),
+        Err(e) => Ok(db_error_response(e, "topic is not synced yet")),
+    }
+}
+
+/// Move the cursor of a topic back so the blocks after it are read again.
+/// Moving it forward would skip blocks and is rejected. A running worker only
+/// saves a page while the cursor is still where it read it from, so it drops
+/// its page instead of undoing the rewind.
+pub fn rewind_cursor(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    topic: web::Path<String>,
+    params: web::Json<CursorParams>,
+) -> Result<HttpResponse> {
+    if !SETTINGS.get().contains_topic(&topic) {
+        return Ok(HttpResponse::NotFound().json("unknown topic"));
+    }
+    if params.block_num < 0 {
+        return Ok(HttpResponse::BadRequest().json("block_num is negative"));
+    }
+    let key = util::get_last_block_num_by_topic(&topic);
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_last_status(&db_conn, &key) {
+        Ok(v) if params.block_num > v.val => {
+            return Ok(HttpResponse::BadRequest().json(format!(
+                "block_num {} is after the cursor {}, it can only be rewound",
+                params.block_num, v.val
+            )))
+        }
+        Ok(_) | Err(DbError::NotFound) => {}
+        Err(e) => return Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+    match db::update_last_status(&db_conn, &key, params.block_num) {
+        Ok(v) => {
+            info!(
+                "rewind cursor of topic: {} to block_num: {}",
+                topic.as_str(),
+                v.val
+            );
+            Ok(HttpResponse::Ok().json(json!({ "topic": *topic, "block_num": v.val })))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReprocessParams {
+    pub trx_ids: Option<Vec<String>>,
+    // the blocks after since_block up to and including until_block
+    pub since_block: Option<i64>,
+    pub until_block: Option<i64>,
+}
+
+/// Mark transactions unprocessed, syncserver verifies and processes them
+/// again in its next round.
+pub fn reprocess_transactions(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    params: web::Json<ReprocessParams>,
+) -> Result<HttpResponse> {
+    if params.trx_ids.is_none() && params.since_block.is_none() && params.until_block.is_none() {
+        return Ok(
+            HttpResponse::BadRequest().json("trx_ids, since_block or until_block is required")
+        );
+    }
+    let db_conn = pg_pool_handler(pool)?;
+    match db::mark_trxs_unprocessed(
+        &db_conn,
+        params.trx_ids.as_deref(),
+        params.since_block,
+        params.until_block,
+    ) {
+        Ok(n) => Ok(HttpResponse::Accepted().json(json!({ "updated": n }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnfetchedParams {
+    pub topic: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// Posts still waiting for their content, the oldest first.
+pub fn list_unfetched_posts(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    params: web::Query<UnfetchedParams>,
+) -> Result<HttpResponse> {
+    let offset = params.offset.unwrap_or(0) as i64;
+    let limit = std::cmp::min(params.limit.unwrap_or(DEFAULT_LIMIT), MAX_LIMIT) as i64;
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_unfetched_posts(&db_conn, params.topic.as_deref(), offset, limit) {
+        Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+/// Queue the content of a post to be fetched and verified again.
+pub fn refetch_post(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    publish_tx_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_post_by_publish_tx_id(&db_conn, &publish_tx_id) {
+        Ok(ref v) if v.deleted => return Ok(HttpResponse::Conflict().json("post is deleted")),
+        Ok(_) => {}
+        Err(e) => return Ok(db_error_response(e, "post not found")),
+    }
+    match db::reset_post_fetch(&db_conn, &publish_tx_id) {
+        Ok(v) => Ok(HttpResponse::Accepted().json(v)),
+        Err(e) => Ok(db_error_response(e, "post not found")),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotifyParams {
+    pub topic: Option<String>,
+    // `dead` (default) for the given up ones, or `pending`
+    pub state: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// Notifies not delivered yet.
+pub fn list_notifies(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    params: web::Query<NotifyParams>,
+) -> Result<HttpResponse> {
+    let dead = match params.state.as_deref() {
+        None | Some("dead") => true,
+        Some("pending") => false,
+        Some(v) => return Ok(HttpResponse::BadRequest().json(format!("unsupported state: {}", v))),
+    };
+    let offset = params.offset.unwrap_or(0) as i64;
+    let limit = std::cmp::min(params.limit.unwrap_or(DEFAULT_LIMIT), MAX_LIMIT) as i64;
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_undelivered_notifies(&db_conn, params.topic.as_deref(), dead, offset, limit) {
+        Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+/// A notify with its delivery log.
+pub fn get_notify(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let db_conn = pg_pool_handler(pool)?;
+    let notify = match db::get_notify(&db_conn, *id) {
+        Ok(v) => v,
+        Err(e) => return Ok(db_error_response(e, "notify not found")),
+    };
+    match db::get_webhook_logs(&db_conn, notify.id) {
+        Ok(logs) => Ok(HttpResponse::Ok().json(json!({ "notify": notify, "logs": logs }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+/// Queue a notify, delivered or dead, to be sent again with fresh retries.
+pub fn resend_notify(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let db_conn = pg_pool_handler(pool)?;
+    match db::reset_notify(&db_conn, *id) {
+        Ok(v) => Ok(HttpResponse::Accepted().json(v)),
+        Err(e) => Ok(db_error_response(e, "notify not found")),
+    }
+}
+
+/// Drop a notify so it is never sent.
+pub fn clear_notify(
+    _auth: AdminAuth,
+    pool: web::Data<PgPool>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let db_conn = pg_pool_handler(pool)?;
+    match db::delete_notify(&db_conn, *id) {
+        Ok(0) => Ok(HttpResponse::NotFound().json("notify not found")),
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token() {
+        let token = "0123456789abcdef";
+        assert!(is_authorized(Some("Bearer 0123456789abcdef"), token));
+        assert!(is_authorized(Some("bearer  0123456789abcdef "), token));
+        assert!(!is_authorized(Some("Bearer 0123456789abcdeF"), token));
+        assert!(!is_authorized(Some("Bearer 0123456789abcde"), token));
+        assert!(!is_authorized(Some("Basic 0123456789abcdef"), token));
+        assert!(!is_authorized(Some("0123456789abcdef"), token));
+        assert!(!is_authorized(None, token));
+    }
+}
@@ -0,0 +1,63 @@
+use actix_web::{web, HttpResponse};
+use diesel::RunQueryDsl;
+
+use crate::db::PgPool;
+
+// the version of the Prometheus text format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// `/healthz`, `/readyz` and `/metrics`, served by web and by syncserver.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/healthz").route(web::get().to(healthz)))
+        .service(web::resource("/readyz").route(web::get().to(readyz)))
+        .service(web::resource("/metrics").route(web::get().to(metrics)));
+}
+
+/// The process is up and serving requests.
+pub fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json("ok")
+}
+
+/// Ready when a database connection can run a query and the chain api
+/// answers, otherwise 503 with the failed checks. The chain api answer is
+/// cached for a few seconds.
+pub fn readyz(pool: web::Data<PgPool>) -> HttpResponse {
+    let database = pool
+        .get()
+        .map_err(|e| e.to_string())
+        .and_then(|conn| {
+            diesel::sql_query("SELECT 1")
+                .execute(&conn)
+                .map_err(|e| e.to_string())
+        })
+        .map(|_| ());
+    let chain = crate::metrics::get_chain_head().map(|_| ());
+
+    let body = json!({
+        "database": check_status(&database),
+        "chain": check_status(&chain),
+    });
+    if database.is_ok() && chain.is_ok() {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+fn check_status(result: &Result<(), String>) -> &str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) => e,
+    }
+}
+
+/// Metrics of this process in the Prometheus text format.
+pub fn metrics(pool: web::Data<PgPool>) -> HttpResponse {
+    let db_conn = pool.get().ok();
+    match crate::metrics::render(db_conn.as_ref().map(|v| &**v)) {
+        Ok(v) => HttpResponse::Ok()
+            .content_type(METRICS_CONTENT_TYPE)
+            .body(v),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
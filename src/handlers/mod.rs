@@ -1,19 +1,226 @@
-use super::db::{PgPool, PgPooledConnection};
+use super::db::{Cursor, Page, PgPool, PgPooledConnection, PostFilter, Range};
+use crate::frontmatter;
 use actix_web::web;
 use actix_web::HttpResponse;
+use chrono::prelude::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
+pub mod admin;
+pub mod health;
 pub mod posts;
+pub mod search;
+pub mod topics;
 pub mod users;
 
+// the cursor of the next page, set whenever the request could be continued
+pub const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+pub const DEFAULT_LIMIT: usize = 20;
+pub const MAX_LIMIT: usize = 100;
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Pagination {
     pub topic: String,
     pub offset: Option<usize>,
     pub limit: Option<usize>,
+    // opaque, from the `X-Next-Cursor` header of the previous page
+    pub cursor: Option<String>,
+}
+
+impl Pagination {
+    /// `kind` is the list and order the cursor must come from.
+    pub fn to_page(&self, kind: &str) -> Result<Page, HttpResponse> {
+        let after = match &self.cursor {
+            Some(v) => Some(
+                decode_cursor(kind, v)
+                    .ok_or_else(|| HttpResponse::BadRequest().json("invalid cursor"))?,
+            ),
+            None => None,
+        };
+        if after.is_some() && self.offset.unwrap_or(0) > 0 {
+            return Err(HttpResponse::BadRequest().json("offset can not be used with cursor"));
+        }
+        Ok(Page {
+            offset: self.offset.unwrap_or(0) as i64,
+            limit: std::cmp::min(self.limit.unwrap_or(DEFAULT_LIMIT), MAX_LIMIT) as i64,
+            after,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RangeParams {
+    // a date or date and time, RFC 3339 or UTC without offset
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub since_block: Option<i64>,
+    pub until_block: Option<i64>,
+}
+
+impl RangeParams {
+    pub fn to_range(&self) -> Result<Range, HttpResponse> {
+        let parse = |name: &str, value: &Option<String>| match value {
+            Some(v) => frontmatter::parse_date(v)
+                .map(|v| Some(v.naive_utc()))
+                .ok_or_else(|| HttpResponse::BadRequest().json(format!("invalid {}: {}", name, v))),
+            None => Ok(None),
+        };
+        Ok(Range {
+            since: parse("since", &self.since)?,
+            until: parse("until", &self.until)?,
+            since_block: self.since_block,
+            until_block: self.until_block,
+        })
+    }
+}
+
+pub fn encode_cursor(kind: &str, cursor: &Cursor) -> String {
+    let raw = format!(
+        "{}|{}|{}",
+        kind,
+        cursor.key.format(CURSOR_TIME_FORMAT),
+        cursor.id
+    );
+    base64::encode_config(&raw, base64::URL_SAFE_NO_PAD)
+}
+
+/// `None` when the cursor is malformed or comes from another kind of list.
+pub fn decode_cursor(kind: &str, s: &str) -> Option<Cursor> {
+    let raw = base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok()?;
+    let raw = String::from_utf8(raw).ok()?;
+    let mut parts = raw.splitn(3, '|');
+    if parts.next()? != kind {
+        return None;
+    }
+    let key = NaiveDateTime::parse_from_str(parts.next()?, CURSOR_TIME_FORMAT).ok()?;
+    Some(Cursor {
+        key,
+        id: parts.next()?.to_string(),
+    })
+}
+
+/// The cursor after the last row of a page, or the requested cursor when the
+/// page is empty so a poller keeps its position.
+pub fn next_cursor(kind: &str, page: &Page, last: Option<Cursor>) -> Option<String> {
+    last.or_else(|| page.after.clone())
+        .map(|v| encode_cursor(kind, &v))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FeedParams {
+    // atom, rss or json, overrides the Accept header
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PostFilterParams {
+    pub author: Option<String>,
+    pub tag: Option<String>,
+    // `updated` (default) or `published`, the frontmatter date
+    pub sort: Option<String>,
+}
+
+impl PostFilterParams {
+    pub fn to_filter(&self) -> Result<PostFilter, HttpResponse> {
+        let by_published = match self.sort.as_deref() {
+            None | Some("updated") => false,
+            Some("published") => true,
+            Some(v) => {
+                return Err(HttpResponse::BadRequest().json(format!("unsupported sort: {}", v)))
+            }
+        };
+        Ok(PostFilter {
+            author: self.author.clone(),
+            tag: self.tag.clone(),
+            by_published,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchParams {
+    pub topic: String,
+    pub q: String,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffParams {
+    // revision numbers, see `/posts/{publish_tx_id}/revisions`
+    pub from: Option<usize>,
+    pub to: Option<usize>,
+    // `line` (default) or `word`
+    pub granularity: Option<String>,
+    // `unified` (default) text or `json`
+    pub format: Option<String>,
+}
+
+/// Cursors of a post list only fit lists with the same order.
+pub fn post_cursor_kind(filter: &PostFilter) -> &'static str {
+    if filter.by_published {
+        "posts.published"
+    } else {
+        "posts.updated"
+    }
 }
 
 pub fn pg_pool_handler(pool: web::Data<PgPool>) -> Result<PgPooledConnection, HttpResponse> {
     pool.get()
         .map_err(|e| HttpResponse::InternalServerError().json(e.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            key: NaiveDateTime::parse_from_str("2019-12-26T03:46:58.032960", CURSOR_TIME_FORMAT)
+                .unwrap(),
+            id: String::from("04a90b96ba3d27b4ed2872f1eb3ad4bdd5c85178c6ddd9363ef8e6be62807a04"),
+        };
+        let encoded = encode_cursor("posts.updated", &cursor);
+        assert!(!encoded.contains('|'));
+        assert_eq!(
+            decode_cursor("posts.updated", &encoded),
+            Some(cursor.clone())
+        );
+        assert_eq!(decode_cursor("posts.published", &encoded), None);
+        assert_eq!(decode_cursor("users", "not a cursor"), None);
+
+        let page = Page {
+            offset: 0,
+            limit: 20,
+            after: Some(cursor),
+        };
+        assert_eq!(next_cursor("posts.updated", &page, None), Some(encoded));
+        assert_eq!(next_cursor("users", &Page::default(), None), None);
+    }
+
+    #[test]
+    fn pagination_to_page() {
+        let mut params = Pagination {
+            topic: String::new(),
+            offset: None,
+            limit: Some(1000),
+            cursor: None,
+        };
+        let page = params.to_page("users").unwrap();
+        assert_eq!((page.offset, page.limit, page.after), (0, 100, None));
+
+        params.cursor = Some(String::from("bad"));
+        assert!(params.to_page("users").is_err());
+
+        let cursor = Cursor {
+            key: NaiveDateTime::from_timestamp(1_577_000_000, 0),
+            id: String::from("74fb01e4d7ea240560978d98f66136c6211d3d61"),
+        };
+        params.cursor = Some(encode_cursor("users", &cursor));
+        assert_eq!(params.to_page("users").unwrap().after, Some(cursor));
+        params.offset = Some(20);
+        assert!(params.to_page("users").is_err());
+    }
+}
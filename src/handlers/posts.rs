@@ -1,10 +1,19 @@
-use actix_web::{web, HttpResponse};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 
-use super::Pagination;
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+
+use super::{next_cursor, post_cursor_kind, NEXT_CURSOR_HEADER};
+use super::{DiffParams, FeedParams, Pagination, PostFilterParams, RangeParams};
 use crate::db;
-use crate::db::PgPool;
+use crate::db::models::{Post, PostPartial};
+use crate::db::{Page, PgPool, PostFilter, Range};
+use crate::diff::Granularity;
+use crate::feed::{Feed, Format};
 use crate::handlers::pg_pool_handler;
 use crate::processor;
+use crate::render;
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -15,20 +24,48 @@ struct PostItem {
     pub updated_tx_id: String,
     pub updated_at: chrono::NaiveDateTime,
     pub deleted: bool,
+    pub superseded_by: Option<String>,
     pub content: String,
+    pub content_html: String,
 }
 
-pub fn list_all_asc(pool: web::Data<PgPool>, params: web::Query<Pagination>) -> HttpResponse {
+pub fn list_all_asc(
+    pool: web::Data<PgPool>,
+    params: web::Query<Pagination>,
+    filter_params: web::Query<PostFilterParams>,
+    range_params: web::Query<RangeParams>,
+) -> HttpResponse {
     // return json data to post web site
-    let offset = params.offset.unwrap_or(0) as i64;
-    let limit = std::cmp::min(params.limit.unwrap_or(20), 100) as i64;
     let topic = &params.topic;
 
+    let filter = match filter_params.to_filter() {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let page = match params.to_page(post_cursor_kind(&filter)) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let range = match range_params.to_range() {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
     let db_conn_res = pg_pool_handler(pool);
     if let Ok(db_conn) = db_conn_res {
-        let posts_result = db::get_posts_for_json(&db_conn, topic, offset, limit);
+        let posts_result = db::get_posts_for_json(&db_conn, topic, &page, &range, &filter);
         match posts_result {
             Ok(posts) => {
+                let cursor = match get_next_cursor(
+                    &db_conn,
+                    &filter,
+                    &page,
+                    posts
+                        .last()
+                        .map(|v| (v.publish_tx_id.as_str(), v.file_hash.as_str(), v.updated_at)),
+                ) {
+                    Ok(v) => v,
+                    Err(resp) => return resp,
+                };
                 let mut post_vec: Vec<PostItem> = Vec::new();
 
                 for p in posts {
@@ -42,6 +79,8 @@ pub fn list_all_asc(pool: web::Data<PgPool>, params: web::Query<Pagination>) ->
                                 updated_at: p.updated_at,
                                 updated_tx_id: p.updated_tx_id.trim().to_string(),
                                 deleted: p.deleted,
+                                superseded_by: p.superseded_by.map(|v| v.trim().to_string()),
+                                content_html: render::markdown_to_html(&content.content),
                                 content: content.content,
                             };
                             post_vec.push(item);
@@ -51,7 +90,11 @@ pub fn list_all_asc(pool: web::Data<PgPool>, params: web::Query<Pagination>) ->
                         }
                     }
                 }
-                HttpResponse::Ok().json(post_vec)
+                let mut resp = HttpResponse::Ok();
+                if let Some(v) = cursor {
+                    resp.header(NEXT_CURSOR_HEADER, v);
+                }
+                resp.json(post_vec)
             }
             Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
         }
@@ -60,42 +103,686 @@ pub fn list_all_asc(pool: web::Data<PgPool>, params: web::Query<Pagination>) ->
     }
 }
 
-pub fn list_all_atom_by_asc(pool: web::Data<PgPool>, params: web::Query<Pagination>) -> String {
-    let offset = params.offset.unwrap_or(0) as i64;
-    let limit = std::cmp::min(params.limit.unwrap_or(20), 100) as i64;
+pub fn list_all_atom_by_asc(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    params: web::Query<Pagination>,
+    feed_params: web::Query<FeedParams>,
+    filter_params: web::Query<PostFilterParams>,
+    range_params: web::Query<RangeParams>,
+) -> HttpResponse {
+    list_feed(
+        req,
+        pool,
+        params,
+        feed_params,
+        filter_params,
+        range_params,
+        db::get_all_atom_posts_by_asc,
+    )
+}
+
+pub fn list_latest(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    params: web::Query<Pagination>,
+    feed_params: web::Query<FeedParams>,
+    filter_params: web::Query<PostFilterParams>,
+    range_params: web::Query<RangeParams>,
+) -> HttpResponse {
+    list_feed(
+        req,
+        pool,
+        params,
+        feed_params,
+        filter_params,
+        range_params,
+        db::get_latest_posts_by_page,
+    )
+}
+
+type LoadPosts =
+    fn(&PgConnection, &str, &Page, &Range, &PostFilter) -> Result<Vec<PostPartial>, Error>;
+
+fn list_feed(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    params: web::Query<Pagination>,
+    feed_params: web::Query<FeedParams>,
+    filter_params: web::Query<PostFilterParams>,
+    range_params: web::Query<RangeParams>,
+    load_posts: LoadPosts,
+) -> HttpResponse {
     let topic = &params.topic;
 
+    let format = match get_feed_format(&req, &feed_params) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let filter = match filter_params.to_filter() {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let page = match params.to_page(post_cursor_kind(&filter)) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let range = match range_params.to_range() {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
     let db_conn_res = pg_pool_handler(pool);
     if let Ok(db_conn) = db_conn_res {
-        let posts_result = db::get_all_atom_posts_by_asc(&db_conn, topic, offset, limit);
+        let posts_result = load_posts(&db_conn, topic, &page, &range, &filter);
         match posts_result {
             Ok(posts) => {
-                let atomstring = processor::atom(&db_conn, posts);
-                atomstring
+                let cursor = match get_next_cursor(
+                    &db_conn,
+                    &filter,
+                    &page,
+                    posts
+                        .last()
+                        .map(|v| (v.publish_tx_id.as_str(), v.file_hash.as_str(), v.updated_at)),
+                ) {
+                    Ok(v) => v,
+                    Err(resp) => return resp,
+                };
+                feed_response(
+                    processor::build_feed(&db_conn, topic, posts),
+                    format,
+                    cursor,
+                )
             }
-            Err(e) => String::from(format!("{}", e)),
+            Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
         }
     } else {
-        String::from("connect to database failed")
+        HttpResponse::InternalServerError().json("connect to database failed")
     }
 }
 
-pub fn list_latest(pool: web::Data<PgPool>, params: web::Query<Pagination>) -> String {
-    let offset = params.offset.unwrap_or(0) as i64;
-    let limit = std::cmp::min(params.limit.unwrap_or(20), 100) as i64;
-    let topic = &params.topic;
+/// The encoded cursor after `last`, the (publish_tx_id, file_hash, updated_at)
+/// of the last post of the page.
+fn get_next_cursor(
+    conn: &PgConnection,
+    filter: &PostFilter,
+    page: &Page,
+    last: Option<(&str, &str, chrono::NaiveDateTime)>,
+) -> Result<Option<String>, HttpResponse> {
+    let last = match last {
+        Some((publish_tx_id, file_hash, updated_at)) => Some(
+            db::get_post_cursor(conn, filter, publish_tx_id, file_hash, updated_at)
+                .map_err(|e| HttpResponse::InternalServerError().json(e.to_string()))?,
+        ),
+        None => None,
+    };
+    Ok(next_cursor(post_cursor_kind(filter), page, last))
+}
 
-    let db_conn_res = pg_pool_handler(pool);
-    if let Ok(db_conn) = db_conn_res {
-        let posts_result = db::get_latest_posts_by_page(&db_conn, topic, offset, limit);
-        match posts_result {
-            Ok(posts) => {
-                let atomstring = processor::atom(&db_conn, posts);
-                atomstring
-            }
-            Err(e) => String::from(format!("{}", e)),
+#[derive(Serialize)]
+struct PostRevision {
+    // 1 for the first revision, the path of `/posts/{publish_tx_id}/revisions/{revision}`
+    pub revision: usize,
+    pub publish_tx_id: String,
+    pub file_hash: String,
+    pub updated_at: chrono::NaiveDateTime,
+    pub deleted: bool,
+    pub verification: &'static str,
+    pub superseded_by: Option<String>,
+    pub superseded_at: Option<chrono::NaiveDateTime>,
+}
+
+fn to_revisions(chain: Vec<Post>) -> Vec<PostRevision> {
+    chain
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| PostRevision {
+            revision: i + 1,
+            verification: v.verification_state(),
+            publish_tx_id: v.publish_tx_id,
+            file_hash: v.file_hash,
+            updated_at: v.updated_at,
+            deleted: v.deleted,
+            superseded_by: v.superseded_by.map(|v| v.trim().to_string()),
+            superseded_at: v.superseded_at,
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct PostDetail {
+    pub publish_tx_id: String,
+    pub user_address: String,
+    pub topic: String,
+    pub file_hash: String,
+    pub hash_alg: String,
+    pub url: String,
+    pub uris: Vec<String>,
+    pub updated_tx_id: String,
+    pub updated_at: chrono::NaiveDateTime,
+    pub deleted: bool,
+    pub encryption: String,
+    pub encrypted: bool,
+    pub verification: &'static str,
+    pub observed_hash: Option<String>,
+    pub quarantined_at: Option<chrono::NaiveDateTime>,
+    // set when a later revision replaced this post
+    pub superseded_by: Option<String>,
+    pub superseded_at: Option<chrono::NaiveDateTime>,
+    // none until the content is fetched, and for deleted posts and posts of
+    // users who are not allowed
+    pub content: Option<String>,
+    pub content_html: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    pub metadata_error: Option<String>,
+    // the first revision first, this post included
+    pub update_chain: Vec<PostRevision>,
+}
+
+pub fn get(pool: web::Data<PgPool>, publish_tx_id: web::Path<String>) -> HttpResponse {
+    match pg_pool_handler(pool) {
+        Ok(db_conn) => {
+            let post = db::get_post_by_publish_tx_id(&db_conn, &publish_tx_id);
+            post_detail_response(&db_conn, post)
+        }
+        Err(resp) => resp,
+    }
+}
+
+/// The last updated post of a content, a content may be published more than once.
+pub fn get_by_hash(pool: web::Data<PgPool>, file_hash: web::Path<String>) -> HttpResponse {
+    match pg_pool_handler(pool) {
+        Ok(db_conn) => {
+            let post = db::get_posts_by_file_hash(&db_conn, &file_hash)
+                .and_then(|v| v.into_iter().next().ok_or(Error::NotFound));
+            post_detail_response(&db_conn, post)
         }
+        Err(resp) => resp,
+    }
+}
+
+/// Every revision of the post, the first one first.
+pub fn list_revisions(pool: web::Data<PgPool>, publish_tx_id: web::Path<String>) -> HttpResponse {
+    let db_conn = match pg_pool_handler(pool) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let chain = db::get_post_by_publish_tx_id(&db_conn, &publish_tx_id)
+        .and_then(|post| processor::get_update_chain(&db_conn, post));
+    match chain {
+        Ok(chain) => HttpResponse::Ok().json(to_revisions(chain)),
+        Err(Error::NotFound) => HttpResponse::NotFound().json("post not found"),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// One revision of the post with its content, numbered from 1.
+pub fn get_revision(pool: web::Data<PgPool>, path: web::Path<(String, usize)>) -> HttpResponse {
+    let (publish_tx_id, revision) = path.into_inner();
+    let db_conn = match pg_pool_handler(pool) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let post = db::get_post_by_publish_tx_id(&db_conn, &publish_tx_id)
+        .and_then(|post| processor::get_update_chain(&db_conn, post))
+        .and_then(|chain| {
+            chain
+                .into_iter()
+                .nth(revision.wrapping_sub(1))
+                .ok_or(Error::NotFound)
+        });
+    post_detail_response(&db_conn, post)
+}
+
+/// The diff between two revisions of the post.
+pub fn diff_revisions(
+    pool: web::Data<PgPool>,
+    publish_tx_id: web::Path<String>,
+    params: web::Query<DiffParams>,
+) -> HttpResponse {
+    let granularity = match params.granularity.as_deref() {
+        None => Granularity::Line,
+        Some(v) => match v.parse::<Granularity>() {
+            Ok(v) => v,
+            Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+        },
+    };
+    let as_json = match params.format.as_deref() {
+        None | Some("unified") => false,
+        Some("json") => true,
+        Some(v) => return HttpResponse::BadRequest().json(format!("unsupported format: {}", v)),
+    };
+    let db_conn = match pg_pool_handler(pool) {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    match processor::get_revision_pair(&db_conn, &publish_tx_id, params.from, params.to) {
+        Ok((from, to)) => {
+            let result = if as_json {
+                processor::revision_diff_json(&from, &to, granularity)
+                    .map(|v| HttpResponse::Ok().json(v))
+            } else {
+                processor::revision_diff_text(&from, &to, granularity).map(|v| {
+                    HttpResponse::Ok()
+                        .content_type("text/plain; charset=utf-8")
+                        .body(v)
+                })
+            };
+            result.unwrap_or_else(|e| HttpResponse::PayloadTooLarge().json(e.to_string()))
+        }
+        Err(Error::NotFound) => {
+            HttpResponse::NotFound().json("revision not found or its content is not fetched")
+        }
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+fn post_detail_response(conn: &PgConnection, post: Result<Post, Error>) -> HttpResponse {
+    let post = match post {
+        Ok(v) => v,
+        Err(Error::NotFound) => return HttpResponse::NotFound().json("post not found"),
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    let withheld = match processor::is_content_withheld(conn, &post) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    let content = if withheld {
+        None
     } else {
-        String::from("connect to database failed")
+        match db::get_content(conn, &post.file_hash) {
+            Ok(v) => Some(v),
+            Err(Error::NotFound) => None,
+            Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+        }
+    };
+
+    // contents are saved decrypted, so encrypted posts are rendered too
+    let (mut content_html, mut metadata, mut metadata_error) = (None, None, None);
+    if let Some(content) = content.as_ref() {
+        content_html = Some(render::markdown_to_html(&content.content));
+        match processor::get_markdown_attrs(conn, content) {
+            Ok(v) => metadata = Some(v.to_json()),
+            Err(e) => metadata_error = Some(e.to_string()),
+        }
+    }
+
+    let detail = PostDetail {
+        publish_tx_id: post.publish_tx_id.clone(),
+        user_address: post.user_address.clone(),
+        topic: post.topic.clone(),
+        file_hash: post.file_hash.clone(),
+        hash_alg: post.hash_alg.clone(),
+        url: post.url.clone(),
+        uris: post.get_uris(),
+        updated_tx_id: post.updated_tx_id.trim().to_string(),
+        updated_at: post.updated_at,
+        deleted: post.deleted,
+        encryption: post.encryption.clone(),
+        encrypted: post.is_encrypted(),
+        verification: post.verification_state(),
+        observed_hash: post.observed_hash.clone(),
+        quarantined_at: post.quarantined_at,
+        superseded_by: post.superseded_by.as_ref().map(|v| v.trim().to_string()),
+        superseded_at: post.superseded_at,
+        content: content.map(|v| v.content),
+        content_html,
+        metadata,
+        metadata_error,
+        update_chain: Vec::new(),
+    };
+    match processor::get_update_chain(conn, post) {
+        Ok(chain) => HttpResponse::Ok().json(PostDetail {
+            update_chain: to_revisions(chain),
+            ..detail
+        }),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// The `format` query parameter wins over the `Accept` header, atom is the default.
+fn get_feed_format(req: &HttpRequest, params: &FeedParams) -> Result<Format, HttpResponse> {
+    match &params.format {
+        Some(v) => v
+            .parse()
+            .map_err(|e: anyhow::Error| HttpResponse::BadRequest().json(e.to_string())),
+        None => Ok(req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Format::from_accept)
+            .unwrap_or(Format::Atom)),
+    }
+}
+
+fn feed_response(feed: Feed, format: Format, cursor: Option<String>) -> HttpResponse {
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(format.content_type())
+        .header(header::VARY, "Accept");
+    if let Some(v) = cursor {
+        resp.header(NEXT_CURSOR_HEADER, v);
+    }
+    resp.body(feed.render(format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use chrono::prelude::Utc;
+    use diesel::RunQueryDsl;
+
+    // these tests need a migrated database, run them with:
+    // DATABASE_URL=postgresql://... cargo test -- --ignored
+    const TOPIC: &str = "a7b751cc0e2f6c5be01ce95bc80b02d071022af4";
+    const USER_ADDRESS: &str = "74fb01e4d7ea240560978d98f66136c6211d3d61";
+    const PUBLISH_TX_ID: &str = "00000000000000000000000000000000000000000000000000000000000000a1";
+    const FILE_HASH: &str = "00000000000000000000000000000000000000000000000000000000000000b1";
+
+    const HOSTILE_TOPICS: &[&str] = &[
+        "' OR '1'='1",
+        "' OR 1=1 --",
+        "'; DROP TABLE posts; --",
+        "'; DELETE FROM users; --",
+        "a7b751cc0e2f6c5be01ce95bc80b02d071022af4' OR posts.topic <> '",
+        "' UNION SELECT user_address, tx_id, topic, 'f' FROM users --",
+        "\\'; SELECT pg_sleep(10); --",
+        "%' OR posts.topic LIKE '%",
+    ];
+
+    fn database_url() -> String {
+        std::env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+    }
+
+    fn seed(pool: &PgPool) {
+        let conn = pool.get().expect("get database connection failed");
+        let now = Utc::now().naive_utc();
+        db::save_user(&conn, USER_ADDRESS, "allow", PUBLISH_TX_ID, TOPIC, now)
+            .expect("save user failed");
+        db::save_post(
+            &conn,
+            PUBLISH_TX_ID,
+            USER_ADDRESS,
+            "",
+            FILE_HASH,
+            "keccak256",
+            TOPIC,
+            "https://example.com/post.md",
+            r#"["https://example.com/post.md"]"#,
+            "",
+            now,
+        )
+        .expect("save post failed");
+        if db::get_content(&conn, FILE_HASH).is_err() {
+            db::save_content(&conn, FILE_HASH, "https://example.com/post.md", "# title")
+                .expect("save content failed");
+        }
+        db::update_post_status(&conn, FILE_HASH, true, true).expect("update post failed");
+        // older than the safety lag of the ascending lists
+        diesel::sql_query("UPDATE posts SET updated_at = $1 WHERE publish_tx_id = $2")
+            .bind::<diesel::sql_types::Timestamp, _>(now - chrono::Duration::hours(1))
+            .bind::<diesel::sql_types::Text, _>(PUBLISH_TX_ID)
+            .execute(&conn)
+            .expect("backdate post failed");
+    }
+
+    fn encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    }
+
+    fn get_body(path: &str, topic: &str) -> String {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        seed(&pool);
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .service(web::resource("/json_posts").route(web::get().to(list_all_asc)))
+                .service(web::resource("/posts").route(web::get().to(list_all_atom_by_asc)))
+                .service(web::resource("/atom").route(web::get().to(list_latest))),
+        );
+        let uri = format!("{}?topic={}", path, encode(topic));
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), StatusCode::OK, "uri = {}", uri);
+
+        String::from_utf8(test::read_body(resp).to_vec()).expect("body is not valid UTF8")
+    }
+
+    fn assert_tables_intact() {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        let conn = pool.get().expect("get database connection failed");
+        assert!(db::get_post_by_publish_tx_id(&conn, PUBLISH_TX_ID).is_ok());
+        assert_eq!(
+            db::models::UserList::list(&conn, TOPIC, 0, 100)
+                .0
+                .iter()
+                .filter(|u| u.user_address == USER_ADDRESS)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn json_posts_binds_topic() {
+        assert!(get_body("/json_posts", TOPIC).contains(PUBLISH_TX_ID));
+        for topic in HOSTILE_TOPICS {
+            assert_eq!(get_body("/json_posts", topic), "[]", "topic = {}", topic);
+        }
+        assert_tables_intact();
+    }
+
+    #[test]
+    #[ignore]
+    fn posts_binds_topic() {
+        assert!(get_body("/posts", TOPIC).contains(PUBLISH_TX_ID));
+        for topic in HOSTILE_TOPICS {
+            assert!(
+                !get_body("/posts", topic).contains("<entry>"),
+                "topic = {}",
+                topic
+            );
+        }
+        assert_tables_intact();
+    }
+
+    #[test]
+    #[ignore]
+    fn json_posts_filters_by_meta() {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        seed(&pool);
+        {
+            let conn = pool.get().expect("get database connection failed");
+            let attrs = crate::frontmatter::MarkdownAttrs {
+                author: String::from("alice"),
+                tags: vec![String::from("rust")],
+                ..Default::default()
+            };
+            db::save_post_meta(&conn, FILE_HASH, &attrs, None).expect("save post_meta failed");
+        }
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .service(web::resource("/json_posts").route(web::get().to(list_all_asc))),
+        );
+        let cases = &[
+            ("&author=alice", true),
+            ("&author=bob", false),
+            ("&tag=rust", true),
+            ("&tag=go", false),
+            ("&author=alice&tag=rust&sort=published", true),
+        ];
+        for (query, found) in cases {
+            let uri = format!("/json_posts?topic={}{}", TOPIC, query);
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&mut app, req);
+            assert_eq!(resp.status(), StatusCode::OK, "uri = {}", uri);
+            let body = String::from_utf8(test::read_body(resp).to_vec()).unwrap();
+            assert_eq!(body.contains(PUBLISH_TX_ID), *found, "uri = {}", uri);
+        }
+
+        let uri = format!("/json_posts?topic={}&sort=title", TOPIC);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    #[ignore]
+    fn json_posts_cursor() {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        seed(&pool);
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .service(web::resource("/json_posts").route(web::get().to(list_all_asc))),
+        );
+        let mut uri = format!("/json_posts?topic={}&limit=1", TOPIC);
+        let mut seen = Vec::new();
+        // walk the pages until the cursor stops moving
+        loop {
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&mut app, req);
+            assert_eq!(resp.status(), StatusCode::OK, "uri = {}", uri);
+            let cursor = resp
+                .headers()
+                .get(NEXT_CURSOR_HEADER)
+                .map(|v| v.to_str().unwrap().to_string())
+                .expect("no cursor");
+            let body = String::from_utf8(test::read_body(resp).to_vec()).unwrap();
+            if body == "[]" {
+                assert!(
+                    uri.ends_with(&cursor),
+                    "cursor of an empty page must not move"
+                );
+                break;
+            }
+            assert!(!seen.contains(&body), "page repeated: {}", body);
+            seen.push(body);
+            uri = format!("/json_posts?topic={}&limit=1&cursor={}", TOPIC, cursor);
+        }
+        assert!(seen.iter().any(|v| v.contains(PUBLISH_TX_ID)));
+
+        for query in &["&cursor=bad", "&since=yesterday", "&cursor=bad&offset=1"] {
+            let uri = format!("/json_posts?topic={}{}", TOPIC, query);
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&mut app, req);
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "uri = {}", uri);
+        }
+
+        let uri = format!("/json_posts?topic={}&since=2000-01-01&until_block=0", TOPIC);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(
+            String::from_utf8(test::read_body(resp).to_vec()).unwrap(),
+            "[]"
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn post_lookup() {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        seed(&pool);
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .service(
+                    web::resource("/posts/by-hash/{file_hash}").route(web::get().to(get_by_hash)),
+                )
+                .service(web::resource("/posts/{publish_tx_id}").route(web::get().to(get))),
+        );
+        for uri in &[
+            format!("/posts/{}", PUBLISH_TX_ID),
+            format!("/posts/by-hash/{}", FILE_HASH),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&mut app, req);
+            assert_eq!(resp.status(), StatusCode::OK, "uri = {}", uri);
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(resp)).expect("invalid json");
+            assert_eq!(body["publish_tx_id"], PUBLISH_TX_ID);
+            assert_eq!(body["verification"], "verified");
+            assert_eq!(body["encrypted"], false);
+            assert_eq!(body["content"], "# title");
+            assert!(body["update_chain"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|v| v["publish_tx_id"] == PUBLISH_TX_ID));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/posts/by-hash/not-a-hash")
+            .to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    #[ignore]
+    fn feed_formats() {
+        let pool = db::init_pool(&database_url()).expect("create database pool failed");
+        seed(&pool);
+        let mut app = test::init_service(
+            App::new()
+                .data(pool)
+                .service(web::resource("/atom").route(web::get().to(list_latest))),
+        );
+        let cases = &[
+            ("", None, "application/atom+xml"),
+            ("&format=rss", None, "application/rss+xml"),
+            ("&format=json", None, "application/feed+json"),
+            ("", Some("application/rss+xml"), "application/rss+xml"),
+            (
+                "&format=atom",
+                Some("application/rss+xml"),
+                "application/atom+xml",
+            ),
+        ];
+        for (query, accept, content_type) in cases {
+            let uri = format!("/atom?topic={}{}", TOPIC, query);
+            let mut req = test::TestRequest::get().uri(&uri);
+            if let Some(v) = accept {
+                req = req.header(header::ACCEPT, *v);
+            }
+            let resp = test::call_service(&mut app, req.to_request());
+            assert_eq!(resp.status(), StatusCode::OK, "uri = {}", uri);
+            let value = resp.headers().get(header::CONTENT_TYPE).unwrap();
+            assert!(
+                value.to_str().unwrap().starts_with(content_type),
+                "uri = {} accept = {:?}",
+                uri,
+                accept
+            );
+        }
+
+        let uri = format!("/atom?topic={}&format=xml", TOPIC);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    #[ignore]
+    fn atom_binds_topic() {
+        assert!(get_body("/atom", TOPIC).contains(PUBLISH_TX_ID));
+        for topic in HOSTILE_TOPICS {
+            assert!(
+                !get_body("/atom", topic).contains("<entry>"),
+                "topic = {}",
+                topic
+            );
+        }
+        assert_tables_intact();
     }
 }
@@ -0,0 +1,50 @@
+use actix_web::{web, HttpResponse, Result};
+
+use super::{SearchParams, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::db;
+use crate::db::PgPool;
+use crate::handlers::pg_pool_handler;
+use crate::processor;
+use crate::render;
+
+// ts_headline marks the matches with control chars, so the snippet can be
+// escaped before they are replaced by <mark>
+const HIGHLIGHT_START: char = '\u{2}';
+const HIGHLIGHT_STOP: char = '\u{3}';
+
+pub fn search(pool: web::Data<PgPool>, params: web::Query<SearchParams>) -> Result<HttpResponse> {
+    let query = params.q.trim();
+    if query.is_empty() {
+        return Ok(HttpResponse::BadRequest().json("q is empty"));
+    }
+    let offset = params.offset.unwrap_or(0) as i64;
+    let limit = std::cmp::min(params.limit.unwrap_or(DEFAULT_LIMIT), MAX_LIMIT) as i64;
+    let highlight = format!(
+        "StartSel={}, StopSel={}, MaxFragments=3, MaxWords=30, MinWords=10, FragmentDelimiter=\" ... \"",
+        HIGHLIGHT_START, HIGHLIGHT_STOP
+    );
+
+    let db_conn = pg_pool_handler(pool)?;
+    match db::search_posts(
+        &db_conn,
+        &params.topic,
+        query,
+        &processor::get_search_config(),
+        &highlight,
+        offset,
+        limit,
+    ) {
+        Ok(results) => {
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|mut v| {
+                    v.snippet =
+                        render::highlight_snippet(&v.snippet, HIGHLIGHT_START, HIGHLIGHT_STOP);
+                    v
+                })
+                .collect();
+            Ok(HttpResponse::Ok().json(results))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
@@ -0,0 +1,14 @@
+use actix_web::{web, HttpResponse, Result};
+
+use crate::db;
+use crate::db::PgPool;
+use crate::handlers::pg_pool_handler;
+
+/// The sync workers of topics as last reported by syncserver.
+pub fn list(pool: web::Data<PgPool>) -> Result<HttpResponse> {
+    let db_conn = pg_pool_handler(pool)?;
+    match db::get_topic_workers(&db_conn) {
+        Ok(v) => Ok(HttpResponse::Ok().json(v)),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    }
+}
@@ -1,15 +1,108 @@
 use actix_web::{web, HttpResponse, Result};
+use diesel::result::Error;
+use serde::Serialize;
 
-use super::Pagination;
+use super::{next_cursor, Pagination, RangeParams, NEXT_CURSOR_HEADER};
+use crate::db;
 use crate::db::models;
-use crate::db::PgPool;
+use crate::db::models::UserTransaction;
+use crate::db::{Cursor, PgPool};
 use crate::handlers::pg_pool_handler;
 
-pub fn list(pool: web::Data<PgPool>, pagination: web::Query<Pagination>) -> Result<HttpResponse> {
+const CURSOR_KIND: &str = "users";
+
+pub fn list(
+    pool: web::Data<PgPool>,
+    pagination: web::Query<Pagination>,
+    range_params: web::Query<RangeParams>,
+) -> Result<HttpResponse> {
     let topic = &pagination.topic;
-    let offset = pagination.offset.unwrap_or(0) as i64;
-    let limit = std::cmp::min(pagination.limit.unwrap_or(20), 100) as i64;
+    let page = match pagination.to_page(CURSOR_KIND) {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+    let range = match range_params.to_range() {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+
+    let db_conn = pg_pool_handler(pool)?;
+    let user_list = match models::UserList::page(&db_conn, topic, &page, &range) {
+        Ok(v) => v,
+        Err(e) => return Ok(HttpResponse::InternalServerError().json(e.to_string())),
+    };
+    let last = user_list.0.last().map(|v| Cursor {
+        key: v.updated_at,
+        id: v.user_address.clone(),
+    });
+
+    let mut resp = HttpResponse::Ok();
+    if let Some(v) = next_cursor(CURSOR_KIND, &page, last) {
+        resp.header(NEXT_CURSOR_HEADER, v);
+    }
+    Ok(resp.json(user_list))
+}
+
+#[derive(Serialize)]
+struct UserTopic {
+    pub topic: String,
+    pub status: String,
+    pub updated_at: chrono::NaiveDateTime,
+    // the transaction which set `status`
+    pub tx_id: String,
+    pub block_num: Option<i64>,
+    // every status change in the topic, the oldest first
+    pub history: Vec<UserTransaction>,
+}
+
+#[derive(Serialize)]
+struct UserDetail {
+    pub user_address: String,
+    pub topics: Vec<UserTopic>,
+}
 
+pub fn get(pool: web::Data<PgPool>, user_address: web::Path<String>) -> Result<HttpResponse> {
     let db_conn = pg_pool_handler(pool)?;
-    Ok(HttpResponse::Ok().json(models::UserList::list(&db_conn, topic, offset, limit)))
+    let internal_error = |e: Error| HttpResponse::InternalServerError().json(e.to_string());
+
+    let users = match db::get_users_by_address(&db_conn, &user_address) {
+        Ok(v) => v,
+        Err(e) => return Ok(internal_error(e)),
+    };
+    if users.is_empty() {
+        return Ok(HttpResponse::NotFound().json("user not found"));
+    }
+
+    let mut topics = Vec::new();
+    for user in users {
+        let tx_id = user.tx_id.trim().to_string();
+        let block_num = match db::get_trx_by_trx_id(&db_conn, &tx_id) {
+            Ok(v) => Some(v.block_num),
+            Err(Error::NotFound) => None,
+            Err(e) => return Ok(internal_error(e)),
+        };
+        let history = match db::get_user_transactions(&db_conn, &user.topic, &user.user_address) {
+            Ok(v) => v
+                .into_iter()
+                .map(|mut v| {
+                    v.status = v.status.trim().to_string();
+                    v
+                })
+                .collect(),
+            Err(e) => return Ok(internal_error(e)),
+        };
+        topics.push(UserTopic {
+            topic: user.topic.trim().to_string(),
+            status: user.status.trim().to_string(),
+            updated_at: user.updated_at,
+            tx_id,
+            block_num,
+            history,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(UserDetail {
+        user_address: user_address.into_inner(),
+        topics,
+    }))
 }
@@ -9,33 +9,52 @@ extern crate diesel;
 extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
 
 extern crate impl2001_rs;
 extern crate prs_utility_rust;
 extern crate qs_rs;
 extern crate sentry;
+extern crate signal_hook;
 
-use anyhow::Result;
 use diesel::pg::PgConnection;
+use signal_hook::iterator::Signals;
 use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 mod crypto_util;
 pub mod db;
+mod diff;
+mod feed;
+mod fetcher;
 mod frontmatter;
 mod handlers;
+mod metrics;
+mod pipeline;
 mod processor;
 mod prs;
+mod render;
+mod replay;
 mod settings;
+mod supervisor;
+mod sync;
 mod url;
 mod util;
+mod webhook;
 
 use crate::impl2001_rs::pip::pip2001::Pip2001;
 use crate::impl2001_rs::pip::Pip;
 
+// pause between two rounds of the processing loops when
+// atom.process_interval_secs is not set
+const DEFAULT_PROCESS_INTERVAL_SECS: u64 = 10;
+
 lazy_static! {
-    static ref SETTINGS: settings::Settings = settings::Settings::load().unwrap();
+    static ref SETTINGS: settings::SharedSettings = settings::SharedSettings::load().unwrap();
 }
 
 fn main() {
@@ -54,6 +73,12 @@ fn main() {
         "processpost" => process_post(),
         "atom" => generate_atom(),
         "web" => run_web(),
+        "rebuildusers" => rebuild_users(),
+        "dumpchain" => dump_chain(&args),
+        "deadletters" => dead_letters(&args),
+        "replaywebhook" => replay_webhook(&args),
+        "backfillmeta" => backfill_meta(),
+        "diff" => diff_revisions(&args),
         _ => check_or_show_usage(&vec![]),
     }
 }
@@ -61,7 +86,7 @@ fn main() {
 fn init_sentry() {
     // init sentry
     let _guard;
-    if let Some(sentry_dsn) = &SETTINGS.atom.sentry_dsn {
+    if let Some(sentry_dsn) = &SETTINGS.get().atom.sentry_dsn {
         _guard = sentry::init(String::from(sentry_dsn));
         sentry::integrations::panic::register_panic_handler();
     } else {
@@ -71,7 +96,7 @@ fn init_sentry() {
 
 fn check_or_show_usage(args: &Vec<String>) {
     let usage = format!(
-        "usage: {} <fetch|syncserver|processpost|atom|web>",
+        "usage: {} <fetch|syncserver|processpost|atom|web|rebuildusers|dumpchain <topic> <file>|deadletters [topic]|replaywebhook <notify_id>|backfillmeta|diff <publish_tx_id> [from] [to] [--word] [--json]>",
         &args[0]
     );
     if args.len() <= 1 {
@@ -82,80 +107,155 @@ fn check_or_show_usage(args: &Vec<String>) {
 
 fn run_fetch() {
     let db_conn_pool = db::establish_connection_pool();
-    match db_conn_pool.get() {
-        Ok(db_conn) => processor::fetchcontent(&db_conn),
-        Err(e) => error!("db_conn_pool.get failed: {}", e),
-    }
+    check_search_config(&db_conn_pool);
+    pipeline::fetch_pending(&db_conn_pool);
 }
 
 fn run_syncserver() {
-    for item in &SETTINGS.topics {
-        let topic = &item.topic;
-        let _handle = thread::spawn(move || {
-            let last_block_num_key = util::get_last_block_num_by_topic(&topic);
-            let db_conn_pool = db::establish_connection_pool();
-            loop {
-                if let Ok(db_conn) = db_conn_pool.get() {
-                    let start_block_num: i64;
-                    if let Ok(last_block_num) = db::get_last_status(&db_conn, &last_block_num_key) {
-                        start_block_num = last_block_num.val;
-                    } else {
-                        match prs::get_start_block_num_by_topic(&topic) {
-                            Ok(v) => start_block_num = v as i64,
-                            Err(e) => {
-                                error!("get_start_block_num for topic: {} failed: {}", &topic, e);
-                                continue;
-                            }
-                        }
-                    }
+    check_search_config(&db::establish_connection_pool_with_size(1));
+    reload_settings_on_sighup();
+    run_metrics_server();
 
-                    if let Err(e) = sync_transactions(&db_conn, &topic, start_block_num) {
-                        error!("sync_transactions for topic: {} failed: {}", &topic, e);
-                    }
-                    info!("sync transactions for topic: {} done. sleep...", &topic);
-                } else {
-                    error!("get database connection failed");
-                }
-                thread::sleep(Duration::from_millis(5000));
-            }
-        });
-    }
+    // start, pause and stop the sync workers of topics as the settings change
+    let _handle_topics = thread::spawn(move || {
+        let mut supervisor = supervisor::Supervisor::default();
+        loop {
+            supervisor.reconcile();
+            thread::sleep(supervisor::get_sync_interval());
+        }
+    });
 
+    // verify the signatures of new transactions and save their posts and users
     let handle_tx = thread::spawn(move || {
-        let db_conn_pool = db::establish_connection_pool();
+        let mut db_conn_pool = db::ConfiguredPool::from_settings("transactions");
 
         loop {
-            if let Ok(db_conn) = db_conn_pool.get() {
+            if let Ok(db_conn) = db_conn_pool.pool().get() {
                 synctxdata(&db_conn);
-                processor::fetchcontent(&db_conn);
-
-                if let Ok(unnotified_list) = db::get_unnotified_list(&db_conn) {
-                    for item in &unnotified_list {
-                        if let Err(e) = processor::check_and_send_webhook(&db_conn, &item.data_id) {
-                            error!("check_and_send_webhook failed: {}", e);
-                        }
-                    }
-                } else {
-                    error!("get_unnotified_list failed");
-                }
             } else {
                 error!("get database connection failed");
             }
-            thread::sleep(Duration::from_millis(10000));
+            thread::sleep(get_process_interval());
+        }
+    });
+
+    // contents are fetched and webhooks delivered by their own workers, so a
+    // slow content host or webhook does not hold up the others
+    let fetch_queue = pipeline::start_fetch_queue();
+    let _handle_fetch = thread::spawn(move || {
+        let mut db_conn_pool = db::ConfiguredPool::from_settings("enqueue_posts");
+        loop {
+            fetch_queue.resize(pipeline::get_fetch_workers(&SETTINGS.get()));
+            let total = pipeline::enqueue_posts(db_conn_pool.pool(), &fetch_queue);
+            debug!(
+                "queued {} file_hashes to fetch, {} in flight, {} deferred by busy hosts",
+                total,
+                fetch_queue.in_flight(),
+                fetch_queue.take_deferred()
+            );
+            thread::sleep(get_process_interval());
+        }
+    });
+
+    let webhook_queue = pipeline::start_webhook_queue();
+    let _handle_webhook = thread::spawn(move || {
+        let mut db_conn_pool = db::ConfiguredPool::from_settings("enqueue_notifies");
+        loop {
+            webhook_queue.resize(pipeline::get_webhook_workers(&SETTINGS.get()));
+            let total = pipeline::enqueue_notifies(db_conn_pool.pool(), &webhook_queue);
+            debug!(
+                "queued notifies of {} subscriptions, {} in flight, {} deferred by busy hosts",
+                total,
+                webhook_queue.in_flight(),
+                webhook_queue.take_deferred()
+            );
+            thread::sleep(get_process_interval());
         }
     });
 
     handle_tx.join().expect("handle_tx.join failed");
 }
 
+/// Exit when atom.search_config is unknown to the database, otherwise every
+/// post would fail to be indexed.
+fn check_search_config(db_conn_pool: &db::PgPool) {
+    let result = db_conn_pool
+        .get()
+        .map_err(anyhow::Error::from)
+        .and_then(|db_conn| processor::check_search_config(&db_conn));
+    if let Err(e) = result {
+        error!("check search_config failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn get_process_interval() -> Duration {
+    Duration::from_secs(
+        SETTINGS
+            .get()
+            .atom
+            .process_interval_secs
+            .unwrap_or(DEFAULT_PROCESS_INTERVAL_SECS),
+    )
+}
+
+/// Serve `/healthz`, `/readyz` and `/metrics` of syncserver on
+/// `atom.metrics_bind_address`, its counters are not visible to web.
+fn run_metrics_server() {
+    use actix_web::{App, HttpServer};
+
+    let bind_address = match &SETTINGS.get().atom.metrics_bind_address {
+        Some(v) => v.clone(),
+        None => return,
+    };
+    thread::spawn(move || {
+        let pool = db::establish_connection_pool();
+        metrics::register_pool("metrics", &pool);
+        let result = HttpServer::new(move || {
+            App::new()
+                .data(pool.clone())
+                .configure(handlers::health::routes)
+        })
+        .workers(1)
+        // syncserver handles the signals, not this server's System
+        .disable_signals()
+        .bind(&bind_address)
+        .and_then(|server| server.run());
+        if let Err(e) = result {
+            error!("serve metrics on {} failed: {}", bind_address, e);
+        }
+    });
+}
+
+/// Load `Settings.toml` again on SIGHUP. The loops read the settings every
+/// round, so they pick up the new values without a restart.
+fn reload_settings_on_sighup() {
+    let signals = match Signals::new(&[signal_hook::SIGHUP]) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("register SIGHUP handler failed: {}", e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match SETTINGS.reload() {
+                Ok(_) => info!("settings reloaded"),
+                Err(e) => error!("reload settings failed, keep the running ones: {}", e),
+            }
+        }
+    });
+}
+
 fn process_post() {
     let db_conn_pool = db::establish_connection_pool();
+    check_search_config(&db_conn_pool);
     if let Ok(db_conn) = db_conn_pool.get() {
         synctxdata(&db_conn);
-        processor::fetchcontent(&db_conn);
     } else {
         error!("get database connection failed");
     }
+    pipeline::fetch_pending(&db_conn_pool);
 }
 
 fn generate_atom() {
@@ -169,23 +269,217 @@ fn generate_atom() {
     }
 }
 
+fn rebuild_users() {
+    let db_conn_pool = db::establish_connection_pool();
+    if let Ok(db_conn) = db_conn_pool.get() {
+        if let Err(e) = processor::rebuild_users(&db_conn) {
+            error!("rebuild_users failed: {}", e);
+        }
+    } else {
+        error!("get database connection failed");
+    }
+}
+
+fn backfill_meta() {
+    let db_conn_pool = db::establish_connection_pool();
+    check_search_config(&db_conn_pool);
+    if let Ok(db_conn) = db_conn_pool.get() {
+        match processor::backfill_post_meta(&db_conn) {
+            Ok(total) => info!("backfilled post_meta of {} contents", total),
+            Err(e) => error!("backfill_post_meta failed: {}", e),
+        }
+    } else {
+        error!("get database connection failed");
+    }
+}
+
+fn dump_chain(args: &[String]) {
+    if args.len() < 4 {
+        check_or_show_usage(&args[..1].to_vec());
+        return;
+    }
+    let (topic, path) = (&args[2], &args[3]);
+    let result =
+        prs::new_chain_source().and_then(|mut source| replay::dump(source.as_mut(), topic, path));
+    match result {
+        Ok(total) => info!(
+            "dumped {} transactions of topic: {} to {}",
+            total, topic, path
+        ),
+        Err(e) => error!("dump chain for topic: {} failed: {}", topic, e),
+    }
+}
+
+fn dead_letters(args: &[String]) {
+    let topic = args.get(2).map(|v| v.as_str());
+    let db_conn_pool = db::establish_connection_pool();
+    if let Ok(db_conn) = db_conn_pool.get() {
+        if let Err(e) = webhook::list_dead_letters(&db_conn, topic) {
+            error!("list_dead_letters failed: {}", e);
+        }
+    } else {
+        error!("get database connection failed");
+    }
+}
+
+fn replay_webhook(args: &[String]) {
+    let notify_id: i32 = match args.get(2).and_then(|v| v.parse().ok()) {
+        Some(v) => v,
+        None => {
+            check_or_show_usage(&args[..1].to_vec());
+            return;
+        }
+    };
+    let db_conn_pool = db::establish_connection_pool();
+    if let Ok(db_conn) = db_conn_pool.get() {
+        if let Err(e) = webhook::replay_dead_letter(&db_conn, notify_id) {
+            error!("replay_dead_letter id = {} failed: {}", notify_id, e);
+        }
+    } else {
+        error!("get database connection failed");
+    }
+}
+
+fn diff_revisions(args: &[String]) {
+    let publish_tx_id = match args.get(2) {
+        Some(v) => v,
+        None => {
+            check_or_show_usage(&args[..1].to_vec());
+            return;
+        }
+    };
+    let mut revisions = Vec::new();
+    let (mut granularity, mut as_json) = (diff::Granularity::Line, false);
+    for arg in &args[3..] {
+        match arg.as_str() {
+            "--word" => granularity = diff::Granularity::Word,
+            "--json" => as_json = true,
+            v => match v.parse::<usize>() {
+                Ok(v) => revisions.push(v),
+                Err(_) => {
+                    check_or_show_usage(&args[..1].to_vec());
+                    return;
+                }
+            },
+        }
+    }
+
+    let db_conn_pool = db::establish_connection_pool();
+    if let Ok(db_conn) = db_conn_pool.get() {
+        match processor::get_revision_pair(
+            &db_conn,
+            publish_tx_id,
+            revisions.first().cloned(),
+            revisions.get(1).cloned(),
+        ) {
+            Ok((from, to)) => {
+                let result = if as_json {
+                    processor::revision_diff_json(&from, &to, granularity)
+                        .map(|v| format!("{}\n", v))
+                } else {
+                    processor::revision_diff_text(&from, &to, granularity)
+                };
+                match result {
+                    Ok(v) => print!("{}", v),
+                    Err(e) => error!(
+                        "diff revisions of publish_tx_id = {} failed: {}",
+                        publish_tx_id, e
+                    ),
+                }
+            }
+            Err(e) => error!(
+                "diff revisions of publish_tx_id = {} failed: {}",
+                publish_tx_id, e
+            ),
+        }
+    } else {
+        error!("get database connection failed");
+    }
+}
+
 fn run_web() {
     use actix_web::{middleware, web, App, HttpServer};
 
-    let bind_address = &SETTINGS.atom.bind_address;
+    check_search_config(&db::establish_connection_pool_with_size(1));
+    reload_settings_on_sighup();
+    let settings = SETTINGS.get();
+    let bind_address = &settings.atom.bind_address;
 
+    let web_workers = Arc::new(AtomicUsize::new(0));
     HttpServer::new(move || {
+        let pool = db::establish_connection_pool();
+        let worker = web_workers.fetch_add(1, Ordering::SeqCst);
+        metrics::register_pool(&format!("web_{}", worker), &pool);
         App::new()
             .wrap(middleware::Compress::default())
-            .data(db::establish_connection_pool())
+            .data(pool)
+            .configure(handlers::health::routes)
             .service(web::resource("/users").route(web::get().to(handlers::users::list)))
+            .service(
+                web::resource("/users/{user_address}").route(web::get().to(handlers::users::get)),
+            )
             .service(
                 web::resource("/json_posts").route(web::get().to(handlers::posts::list_all_asc)),
             )
             .service(
                 web::resource("/posts").route(web::get().to(handlers::posts::list_all_atom_by_asc)),
             )
+            // registered before `/posts/{publish_tx_id}` which would match it too
+            .service(
+                web::resource("/posts/by-hash/{file_hash}")
+                    .route(web::get().to(handlers::posts::get_by_hash)),
+            )
+            .service(
+                web::resource("/posts/{publish_tx_id}").route(web::get().to(handlers::posts::get)),
+            )
+            .service(
+                web::resource("/posts/{publish_tx_id}/revisions")
+                    .route(web::get().to(handlers::posts::list_revisions)),
+            )
+            .service(
+                web::resource("/posts/{publish_tx_id}/revisions/{revision}")
+                    .route(web::get().to(handlers::posts::get_revision)),
+            )
+            .service(
+                web::resource("/posts/{publish_tx_id}/diff")
+                    .route(web::get().to(handlers::posts::diff_revisions)),
+            )
             .service(web::resource("/atom").route(web::get().to(handlers::posts::list_latest)))
+            .service(web::resource("/search").route(web::get().to(handlers::search::search)))
+            .service(web::resource("/topics").route(web::get().to(handlers::topics::list)))
+            .service(
+                web::scope("/admin")
+                    .service(
+                        web::resource("/topics/{topic}/cursor")
+                            .route(web::get().to(handlers::admin::get_cursor))
+                            .route(web::put().to(handlers::admin::rewind_cursor)),
+                    )
+                    .service(
+                        web::resource("/transactions/reprocess")
+                            .route(web::post().to(handlers::admin::reprocess_transactions)),
+                    )
+                    .service(
+                        web::resource("/posts/unfetched")
+                            .route(web::get().to(handlers::admin::list_unfetched_posts)),
+                    )
+                    .service(
+                        web::resource("/posts/{publish_tx_id}/refetch")
+                            .route(web::post().to(handlers::admin::refetch_post)),
+                    )
+                    .service(
+                        web::resource("/notifies")
+                            .route(web::get().to(handlers::admin::list_notifies)),
+                    )
+                    .service(
+                        web::resource("/notifies/{id}")
+                            .route(web::get().to(handlers::admin::get_notify))
+                            .route(web::delete().to(handlers::admin::clear_notify)),
+                    )
+                    .service(
+                        web::resource("/notifies/{id}/resend")
+                            .route(web::post().to(handlers::admin::resend_notify)),
+                    ),
+            )
     })
     .bind(&bind_address)
     .unwrap_or_else(|_| panic!("can not bind to {}", &bind_address))
@@ -206,6 +500,7 @@ pub fn synctxdata(connection: &PgConnection) {
                             "block_num = {}, trx verify_signature failed: {}",
                             trx.block_num, e
                         );
+                        metrics::transaction_rejected("verify_error");
                         continue;
                     }
                 };
@@ -223,14 +518,18 @@ pub fn synctxdata(connection: &PgConnection) {
                                 serde_json::from_str(&trx.data).expect("parse trx data failed");
                             // verify user pubaddr and sign
                             let encryption = data.get_encryption();
-                            let _result = processor::process_pip2001_message(
+                            let processed = processor::process_pip2001_message(
                                 connection,
                                 &pipobject,
                                 &data.id,
                                 &trx.user_address,
                                 i64::from(trx.id),
+                                trx.created_at,
                                 &encryption,
                             );
+                            if !processed {
+                                metrics::transaction_rejected("invalid_message");
+                            }
                             if let Err(e) = db::update_trx_status(connection, trx.block_num, true) {
                                 error!(
                                     "update_trx_status failed: {}, block_num = {} processed = true",
@@ -243,6 +542,7 @@ pub fn synctxdata(connection: &PgConnection) {
                                 "Pip2001.from_json return None\ntrx = {:?}\njson_post_str = {}",
                                 trx, json_post_str
                             );
+                            metrics::transaction_rejected("invalid_pip");
                             continue;
                         }
                         Err(e) => {
@@ -250,6 +550,7 @@ pub fn synctxdata(connection: &PgConnection) {
                                 "from_json failed: {:?}\ntrx = {:?}\njson_post_str = {}",
                                 e, trx, json_post_str
                             );
+                            metrics::transaction_rejected("invalid_pip");
                             continue;
                         }
                     }
@@ -258,6 +559,7 @@ pub fn synctxdata(connection: &PgConnection) {
                         "block_num = {} trx_id = {}, verify failed",
                         trx.block_num, trx.trx_id
                     );
+                    metrics::transaction_rejected("invalid_signature");
                     continue;
                 }
             }
@@ -267,49 +569,3 @@ pub fn synctxdata(connection: &PgConnection) {
         }
     }
 }
-
-fn sync_transactions(conn: &PgConnection, topic: &str, start_block_num: i64) -> Result<()> {
-    let mut start_block_num = start_block_num;
-    let mut easy = prs::get_curl_easy().expect("get curl easy failed");
-    loop {
-        let transactions =
-            prs::fetch_transactions_by_topic(&mut easy, &topic, start_block_num, 20)?;
-        if transactions.is_empty() {
-            return Ok(());
-        }
-
-        for trx in transactions {
-            start_block_num = trx.block_num;
-            debug!(
-                "got block_num = {} topic = {}, new transaction: {:?}",
-                trx.block_num, &topic, &trx
-            );
-
-            db::save_trx(&conn, &trx)?;
-            let payload = match trx.get_notify_payload() {
-                Ok(v) => match v {
-                    Some(vv) => vv,
-                    None => {
-                        // PUBLISH_MANAGEMENT
-                        continue;
-                    }
-                },
-                Err(e) => {
-                    error!("get_notify_payload failed: {}", e);
-                    continue;
-                }
-            };
-
-            let data_id = payload.block.data_id;
-            db::save_notify(
-                &conn,
-                &data_id,
-                payload.block.block_num,
-                &payload.block.trx_id,
-                &trx.get_topic(),
-            )?;
-            let key = util::get_last_block_num_by_topic(&trx.get_topic());
-            db::update_last_status(&conn, &key, trx.block_num)?;
-        }
-    }
-}
@@ -0,0 +1,223 @@
+use anyhow::{anyhow, Result};
+use diesel::pg::PgConnection;
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::SETTINGS;
+use crate::db;
+use crate::db::PgPool;
+use crate::prs;
+use crate::util;
+
+// webhook requests give up after atom.request_timeout_secs, 30 by default
+const WEBHOOK_LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// /metrics and /readyz ask the chain api at most once per this long
+const CHAIN_INFO_TTL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref TRANSACTIONS_INGESTED: IntCounterVec = register_int_counter_vec!(
+        "atom_transactions_ingested_total",
+        "Transactions read from the chain and saved",
+        &["topic"]
+    )
+    .unwrap();
+    static ref TRANSACTIONS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "atom_transactions_rejected_total",
+        "Transactions which failed verification or processing, counted again every round they are retried",
+        &["reason"]
+    )
+    .unwrap();
+    static ref CONTENT_FETCHES: IntCounterVec = register_int_counter_vec!(
+        "atom_content_fetches_total",
+        "Content requests by status code, `error` when there is no response",
+        &["status"]
+    )
+    .unwrap();
+    static ref WEBHOOK_LATENCY: HistogramVec = register_histogram_vec!(
+        "atom_webhook_delivery_seconds",
+        "Time taken by webhook requests",
+        &["topic", "subscription"],
+        WEBHOOK_LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref WEBHOOK_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "atom_webhook_failures_total",
+        "Failed webhook requests by status code, `error` when there is no response",
+        &["topic", "subscription", "status"]
+    )
+    .unwrap();
+    static ref CHAIN_HEAD: IntGauge = register_int_gauge!(
+        "atom_chain_head_block_num",
+        "head_block_num of the chain at the last scrape"
+    )
+    .unwrap();
+    static ref SYNC_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "atom_sync_lag_blocks",
+        "head_block_num of the chain minus the block the topic is synced to",
+        &["topic"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "atom_db_pool_connections",
+        "Open connections of a database pool, idle or in use",
+        &["pool", "state"]
+    )
+    .unwrap();
+    static ref DB_POOL_MAX_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "atom_db_pool_max_connections",
+        "Size of a database pool",
+        &["pool"]
+    )
+    .unwrap();
+    static ref POOLS: Mutex<HashMap<String, PgPool>> = Mutex::new(HashMap::new());
+    static ref CHAIN_HEAD_CACHE: Mutex<Option<(Instant, Result<i64, String>)>> = Mutex::new(None);
+}
+
+pub fn transactions_ingested(topic: &str, total: usize) {
+    TRANSACTIONS_INGESTED
+        .with_label_values(&[topic])
+        .inc_by(total as i64);
+}
+
+/// `reason` is a fixed name like `invalid_signature`, never an error message.
+pub fn transaction_rejected(reason: &str) {
+    TRANSACTIONS_REJECTED.with_label_values(&[reason]).inc();
+}
+
+/// `status_code` is `None` when the request failed without a response.
+pub fn content_fetched(status_code: Option<u32>) {
+    CONTENT_FETCHES
+        .with_label_values(&[&status_label(status_code)])
+        .inc();
+}
+
+/// A webhook request failed unless it got a 2xx response.
+pub fn webhook_delivered(
+    topic: &str,
+    subscription: &str,
+    duration: Duration,
+    status_code: Option<u32>,
+) {
+    WEBHOOK_LATENCY
+        .with_label_values(&[topic, subscription])
+        .observe(duration.as_secs_f64());
+    match status_code {
+        Some(v) if (200..300).contains(&v) => {}
+        _ => WEBHOOK_FAILURES
+            .with_label_values(&[topic, subscription, &status_label(status_code)])
+            .inc(),
+    }
+}
+
+fn status_label(status_code: Option<u32>) -> String {
+    match status_code {
+        Some(v) => v.to_string(),
+        None => String::from("error"),
+    }
+}
+
+/// Report the usage of `pool` as `name`, a pool registered again under the
+/// same name replaces the old one.
+pub fn register_pool(name: &str, pool: &PgPool) {
+    POOLS.lock().unwrap().insert(name.to_string(), pool.clone());
+}
+
+pub fn unregister_pool(name: &str) {
+    POOLS.lock().unwrap().remove(name);
+}
+
+/// All metrics in the Prometheus text format. The sync lag needs `conn` and
+/// the chain api, it is left out when either of them fails.
+pub fn render(conn: Option<&PgConnection>) -> Result<String> {
+    SYNC_LAG.reset();
+    if let Some(conn) = conn {
+        if let Err(e) = update_sync_lag(conn) {
+            warn!("get sync lag failed: {}", e);
+        }
+    }
+    update_pools();
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// head_block_num of the chain, or why it could not be read, kept for
+/// CHAIN_INFO_TTL so scrapes and probes do not each wait on the chain api.
+pub fn get_chain_head() -> Result<i64, String> {
+    let mut cached = CHAIN_HEAD_CACHE.lock().unwrap();
+    if let Some((at, result)) = &*cached {
+        if at.elapsed() < CHAIN_INFO_TTL {
+            return result.clone();
+        }
+    }
+    let result = prs::new_chain_source()
+        .and_then(|mut source| source.get_info())
+        .map(|v| v.head_block_num)
+        .map_err(|e| e.to_string());
+    *cached = Some((Instant::now(), result.clone()));
+    result
+}
+
+fn update_sync_lag(conn: &PgConnection) -> Result<()> {
+    let head_block_num = get_chain_head().map_err(|e| anyhow!(e))?;
+    CHAIN_HEAD.set(head_block_num);
+    for item in &SETTINGS.get().topics {
+        let key = util::get_last_block_num_by_topic(&item.topic);
+        if let Ok(v) = db::get_last_status(conn, &key) {
+            SYNC_LAG
+                .with_label_values(&[&item.topic])
+                .set(head_block_num - v.val);
+        }
+    }
+    Ok(())
+}
+
+fn update_pools() {
+    DB_POOL_CONNECTIONS.reset();
+    DB_POOL_MAX_CONNECTIONS.reset();
+    for (name, pool) in POOLS.lock().unwrap().iter() {
+        let state = pool.state();
+        let idle = i64::from(state.idle_connections);
+        DB_POOL_CONNECTIONS
+            .with_label_values(&[name, "idle"])
+            .set(idle);
+        DB_POOL_CONNECTIONS
+            .with_label_values(&[name, "in_use"])
+            .set(i64::from(state.connections) - idle);
+        DB_POOL_MAX_CONNECTIONS
+            .with_label_values(&[name])
+            .set(i64::from(pool.max_size()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        transactions_ingested("a7b751cc0e2f6c5be01ce95bc80b02d071022af4", 2);
+        transaction_rejected("invalid_signature");
+        content_fetched(Some(404));
+        content_fetched(None);
+        webhook_delivered("t", "default", Duration::from_millis(300), Some(200));
+        webhook_delivered("t", "default", Duration::from_millis(300), Some(500));
+
+        let text = render(None).unwrap();
+        assert!(text.contains(
+            "atom_transactions_ingested_total{topic=\"a7b751cc0e2f6c5be01ce95bc80b02d071022af4\"} 2"
+        ));
+        assert!(text.contains("atom_transactions_rejected_total{reason=\"invalid_signature\"} 1"));
+        assert!(text.contains("atom_content_fetches_total{status=\"404\"} 1"));
+        assert!(text.contains("atom_content_fetches_total{status=\"error\"} 1"));
+        assert!(text.contains(
+            "atom_webhook_delivery_seconds_bucket{subscription=\"default\",topic=\"t\",le=\"0.5\"} 2"
+        ));
+        assert!(text.contains(
+            "atom_webhook_failures_total{status=\"500\",subscription=\"default\",topic=\"t\"} 1"
+        ));
+    }
+}
@@ -0,0 +1,547 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::SETTINGS;
+use crate::db;
+use crate::db::models::Post;
+use crate::db::PgPool;
+use crate::fetcher::ContentResolver;
+use crate::processor;
+use crate::settings::Settings;
+use crate::webhook;
+
+const DEFAULT_FETCH_WORKERS: usize = 8;
+const DEFAULT_WEBHOOK_WORKERS: usize = 4;
+const DEFAULT_MAX_PER_HOST: usize = 2;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+// jobs waiting for a worker, a producer blocks when the queue is full
+const QUEUE_CAPACITY: usize = 100;
+const WORKER_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref HOST_LIMITER: HostLimiter = HostLimiter::default();
+}
+
+/// Timeout of one content or webhook request, from connecting to the last
+/// byte of the response.
+pub fn request_timeout() -> Duration {
+    Duration::from_secs(
+        SETTINGS
+            .get()
+            .atom
+            .request_timeout_secs
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS),
+    )
+}
+
+/// Run `f` when the host of `url` has a free request slot, otherwise fail
+/// with `HostBusy` right away so the worker can move on to other hosts and
+/// the job is queued again in a later round.
+pub fn with_host_limit<T, F: FnOnce() -> Result<T>>(url: &str, f: F) -> Result<T> {
+    let max_per_host = SETTINGS
+        .get()
+        .atom
+        .max_requests_per_host
+        .unwrap_or(DEFAULT_MAX_PER_HOST);
+    let host = host_of(url);
+    let _permit = match HOST_LIMITER.try_acquire(&host, max_per_host) {
+        Some(v) => v,
+        None => return Err(anyhow::Error::new(HostBusy { host })),
+    };
+    f()
+}
+
+/// The request was not sent, its host already has `max_requests_per_host`
+/// requests running.
+#[derive(Debug)]
+pub struct HostBusy {
+    pub host: String,
+}
+
+impl fmt::Display for HostBusy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "host {} is at its request limit", self.host)
+    }
+}
+
+impl std::error::Error for HostBusy {}
+
+pub fn is_host_busy(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HostBusy>().is_some()
+}
+
+/// The lowercase `host[:port]` of `url`, or the whole `url` when it has no
+/// authority.
+pub fn host_of(url: &str) -> String {
+    let rest = match url.find("://") {
+        Some(offset) => &url[offset + 3..],
+        None => return url.to_lowercase(),
+    };
+    let authority = match rest.find(&['/', '?', '#'][..]) {
+        Some(offset) => &rest[..offset],
+        None => rest,
+    };
+    let host = match authority.rfind('@') {
+        Some(offset) => &authority[offset + 1..],
+        None => authority,
+    };
+    host.to_lowercase()
+}
+
+/// Bounds the concurrent requests to each host. It never blocks, so a slow
+/// host holds at most `max_per_host` workers.
+#[derive(Default)]
+pub struct HostLimiter {
+    active: Mutex<HashMap<String, usize>>,
+}
+
+pub struct HostPermit<'a> {
+    limiter: &'a HostLimiter,
+    host: String,
+}
+
+impl HostLimiter {
+    /// A slot of `host`, `None` when it already has `max_per_host` requests
+    /// running. The slot is released when the permit is dropped.
+    pub fn try_acquire(&self, host: &str, max_per_host: usize) -> Option<HostPermit<'_>> {
+        let max_per_host = std::cmp::max(max_per_host, 1);
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(host.to_string()).or_insert(0);
+        if *count >= max_per_host {
+            return None;
+        }
+        *count += 1;
+        Some(HostPermit {
+            limiter: self,
+            host: host.to_string(),
+        })
+    }
+}
+
+impl<'a> Drop for HostPermit<'a> {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.host);
+            }
+        }
+    }
+}
+
+/// Jobs handled by a set of worker threads, resized with `resize`. A job
+/// whose key is queued or running is not submitted again, so a producer can
+/// poll the database for pending work without duplicating the work in flight.
+pub struct WorkQueue<T> {
+    sender: SyncSender<(String, T)>,
+    workers: Arc<Workers<T>>,
+}
+
+struct Workers<T> {
+    name: String,
+    receiver: Mutex<Receiver<(String, T)>>,
+    pending: (Mutex<HashSet<String>>, Condvar),
+    deferred: AtomicUsize,
+    // (wanted, running) worker threads
+    size: Mutex<(usize, usize)>,
+    spawned: AtomicUsize,
+    handler: Box<dyn Fn(T) -> bool + Send + Sync>,
+}
+
+impl<T: Send + 'static> WorkQueue<T> {
+    /// `handler` returns false when it left the job, or part of it, to a
+    /// later round because a host was busy.
+    pub fn start<F>(name: &str, workers: usize, handler: F) -> WorkQueue<T>
+    where
+        F: Fn(T) -> bool + Send + Sync + 'static,
+    {
+        let (sender, receiver) = sync_channel::<(String, T)>(QUEUE_CAPACITY);
+        let queue = WorkQueue {
+            sender,
+            workers: Arc::new(Workers {
+                name: name.to_string(),
+                receiver: Mutex::new(receiver),
+                pending: (Mutex::new(HashSet::new()), Condvar::new()),
+                deferred: AtomicUsize::new(0),
+                size: Mutex::new((0, 0)),
+                spawned: AtomicUsize::new(0),
+                handler: Box::new(handler),
+            }),
+        };
+        queue.resize(workers);
+        queue
+    }
+
+    /// Run `workers` threads. New ones start right away, surplus ones stop
+    /// once their current job is done.
+    pub fn resize(&self, workers: usize) {
+        let workers = std::cmp::max(workers, 1);
+        let mut size = self.workers.size.lock().unwrap();
+        if size.0 != 0 && size.0 != workers {
+            info!(
+                "{} workers changed from {} to {}",
+                self.workers.name, size.0, workers
+            );
+        }
+        size.0 = workers;
+        while size.1 < size.0 {
+            let idx = self.workers.spawned.fetch_add(1, Ordering::SeqCst);
+            let shared = self.workers.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", self.workers.name, idx))
+                .spawn(move || run_worker(&shared))
+                .expect("spawn worker thread failed");
+            size.1 += 1;
+        }
+    }
+
+    /// Queue `job` unless a job with the same `key` is queued or running,
+    /// blocks while the queue is full.
+    pub fn submit(&self, key: &str, job: T) -> bool {
+        {
+            let mut pending = (self.workers.pending.0).lock().unwrap();
+            if !pending.insert(key.to_string()) {
+                return false;
+            }
+        }
+        if self.sender.send((key.to_string(), job)).is_err() {
+            (self.workers.pending.0).lock().unwrap().remove(key);
+            return false;
+        }
+        true
+    }
+
+    /// Jobs queued or running.
+    pub fn in_flight(&self) -> usize {
+        (self.workers.pending.0).lock().unwrap().len()
+    }
+
+    /// Jobs deferred since the last call.
+    pub fn take_deferred(&self) -> usize {
+        self.workers.deferred.swap(0, Ordering::SeqCst)
+    }
+
+    /// Block until every submitted job is done.
+    pub fn wait_idle(&self) {
+        let (lock, done) = &self.workers.pending;
+        let mut pending = lock.lock().unwrap();
+        while !pending.is_empty() {
+            pending = done.wait(pending).unwrap();
+        }
+    }
+}
+
+fn run_worker<T>(workers: &Workers<T>) {
+    loop {
+        let job = {
+            let receiver = workers.receiver.lock().unwrap();
+            let mut size = workers.size.lock().unwrap();
+            if size.1 > size.0 {
+                size.1 -= 1;
+                return;
+            }
+            drop(size);
+            // wake up now and then to notice a resize while idle
+            receiver.recv_timeout(WORKER_IDLE_CHECK)
+        };
+        let (key, job) = match job {
+            Ok(v) => v,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // the queue is dropped
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        // a panicking job must not take the worker down or stay pending
+        match panic::catch_unwind(AssertUnwindSafe(|| (workers.handler)(job))) {
+            Ok(true) => {}
+            Ok(false) => {
+                workers.deferred.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(_) => error!("job key = {} panicked", key),
+        }
+        let (lock, done) = &workers.pending;
+        lock.lock().unwrap().remove(&key);
+        done.notify_all();
+    }
+}
+
+pub fn get_fetch_workers(settings: &Settings) -> usize {
+    settings.atom.fetch_workers.unwrap_or(DEFAULT_FETCH_WORKERS)
+}
+
+pub fn get_webhook_workers(settings: &Settings) -> usize {
+    settings
+        .atom
+        .webhook_workers
+        .unwrap_or(DEFAULT_WEBHOOK_WORKERS)
+}
+
+/// Workers fetching, verifying and saving post contents. A job is every post
+/// of one file_hash, handled in order so each of them is processed. The
+/// caller resizes the queue to `get_fetch_workers` after a reload, the
+/// database pool follows on its own.
+pub fn start_fetch_queue() -> WorkQueue<Vec<Post>> {
+    let pool = Mutex::new(db::ConfiguredPool::with_size("fetch_workers", |settings| {
+        get_fetch_workers(settings) as u32
+    }));
+    let resolver = ContentResolver::from_settings();
+    WorkQueue::start(
+        "fetch",
+        get_fetch_workers(&SETTINGS.get()),
+        move |posts: Vec<Post>| {
+            let pool = pool.lock().unwrap().pool().clone();
+            let conn = match pool.get() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("get database connection failed: {}", e);
+                    return true;
+                }
+            };
+            let mut done = true;
+            for post in posts {
+                done &= processor::fetch_post_content(&conn, &resolver, &post);
+            }
+            done
+        },
+    )
+}
+
+/// Workers delivering webhooks. The notifies of one subscription are sent in
+/// order by one worker, different subscriptions are sent concurrently.
+pub fn start_webhook_queue() -> WorkQueue<Vec<i32>> {
+    let pool = Mutex::new(db::ConfiguredPool::with_size(
+        "webhook_workers",
+        |settings| get_webhook_workers(settings) as u32,
+    ));
+    WorkQueue::start(
+        "webhook",
+        get_webhook_workers(&SETTINGS.get()),
+        move |notify_ids: Vec<i32>| {
+            let pool = pool.lock().unwrap().pool().clone();
+            let conn = match pool.get() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("get database connection failed: {}", e);
+                    return true;
+                }
+            };
+            for notify_id in notify_ids {
+                match webhook::check_and_send_webhook(&conn, notify_id) {
+                    Ok(_) => {}
+                    // keep the order, the rest are sent in a later round
+                    Err(ref e) if is_host_busy(e) => {
+                        debug!("notify id = {} deferred: {}", notify_id, e);
+                        return false;
+                    }
+                    Err(e) => error!("check_and_send_webhook id = {} failed: {}", notify_id, e),
+                }
+            }
+            true
+        },
+    )
+}
+
+/// Fetch the contents of every post waiting for them, returns when all are
+/// done. Posts deferred by a busy host are queued again once the round ends.
+pub fn fetch_pending(pool: &PgPool) {
+    let queue = start_fetch_queue();
+    loop {
+        let total = enqueue_posts(pool, &queue);
+        info!("fetching contents of {} file_hashes", total);
+        queue.wait_idle();
+        if queue.take_deferred() == 0 {
+            break;
+        }
+    }
+}
+
+/// Queue the posts waiting for their content grouped by file_hash, returns
+/// how many groups are new.
+pub fn enqueue_posts(pool: &PgPool, queue: &WorkQueue<Vec<Post>>) -> usize {
+    let conn = match pool.get() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("get database connection failed: {}", e);
+            return 0;
+        }
+    };
+    let posts = match processor::get_posts_to_fetch(&conn) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("get posts failed: {:?}", e);
+            return 0;
+        }
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, Vec<Post>> = HashMap::new();
+    for post in posts {
+        if !groups.contains_key(&post.file_hash) {
+            keys.push(post.file_hash.clone());
+        }
+        groups
+            .entry(post.file_hash.clone())
+            .or_insert_with(Vec::new)
+            .push(post);
+    }
+    keys.into_iter()
+        .filter(|key| {
+            let posts = groups.remove(key).unwrap_or_default();
+            queue.submit(key, posts)
+        })
+        .count()
+}
+
+/// Queue the due notifies grouped by subscription, returns how many groups
+/// are new.
+pub fn enqueue_notifies(pool: &PgPool, queue: &WorkQueue<Vec<i32>>) -> usize {
+    let conn = match pool.get() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("get database connection failed: {}", e);
+            return 0;
+        }
+    };
+    let notifies = match db::get_unnotified_list(&conn) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("get_unnotified_list failed: {}", e);
+            return 0;
+        }
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, Vec<i32>> = HashMap::new();
+    for notify in notifies {
+        let key = format!("{}/{}", notify.topic.trim(), notify.subscription);
+        if !groups.contains_key(&key) {
+            keys.push(key.clone());
+        }
+        groups.entry(key).or_insert_with(Vec::new).push(notify.id);
+    }
+    keys.into_iter()
+        .filter(|key| {
+            let notify_ids = groups.remove(key).unwrap_or_default();
+            queue.submit(key, notify_ids)
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn url_host() {
+        assert_eq!(host_of("https://Example.com/post.md"), "example.com");
+        assert_eq!(host_of("http://user@127.0.0.1:8080?a=b"), "127.0.0.1:8080");
+        assert_eq!(host_of("http://example.com"), "example.com");
+        assert_eq!(host_of("data:,text"), "data:,text");
+    }
+
+    #[test]
+    fn host_limiter_bounds_each_host() {
+        let limiter = HostLimiter::default();
+        let first = limiter.try_acquire("a.com", 2);
+        let second = limiter.try_acquire("a.com", 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.try_acquire("a.com", 2).is_none());
+        // the other host is never held up by a.com
+        assert!(limiter.try_acquire("b.com", 2).is_some());
+
+        drop(first);
+        let third = limiter.try_acquire("a.com", 2);
+        assert!(third.is_some());
+        drop(second);
+        drop(third);
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn work_queue_runs_jobs_concurrently() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let queue = {
+            let done = done.clone();
+            WorkQueue::start("test", 4, move |ms: u64| {
+                thread::sleep(Duration::from_millis(ms));
+                done.fetch_add(1, Ordering::SeqCst);
+                true
+            })
+        };
+
+        let start = Instant::now();
+        for idx in 0..4 {
+            assert!(queue.submit(&idx.to_string(), 100));
+        }
+        // a key which is queued or running is not submitted again
+        assert!(!queue.submit("0", 100));
+        queue.wait_idle();
+
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(queue.in_flight(), 0);
+        assert!(queue.submit("0", 0));
+        queue.wait_idle();
+    }
+
+    #[test]
+    fn work_queue_survives_panic() {
+        let queue = WorkQueue::start("test", 1, |fail: bool| {
+            if fail {
+                panic!("job failed");
+            }
+            true
+        });
+        assert!(queue.submit("a", true));
+        queue.wait_idle();
+        assert!(queue.submit("a", false));
+        queue.wait_idle();
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(queue.take_deferred(), 0);
+    }
+
+    #[test]
+    fn work_queue_resizes() {
+        let queue = WorkQueue::start("test", 1, |ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            true
+        });
+        queue.resize(4);
+        let start = Instant::now();
+        for idx in 0..4 {
+            assert!(queue.submit(&idx.to_string(), 100));
+        }
+        queue.wait_idle();
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // idle surplus workers stop once they take their turn at the queue
+        queue.resize(2);
+        let start = Instant::now();
+        while queue.workers.size.lock().unwrap().1 > 2 {
+            assert!(start.elapsed() < WORKER_IDLE_CHECK * 10);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*queue.workers.size.lock().unwrap(), (2, 2));
+        assert!(queue.submit("a", 0));
+        queue.wait_idle();
+    }
+
+    #[test]
+    fn work_queue_counts_deferred() {
+        let queue = WorkQueue::start("test", 2, |defer: bool| !defer);
+        assert!(queue.submit("a", true));
+        assert!(queue.submit("b", false));
+        assert!(queue.submit("c", true));
+        queue.wait_idle();
+        assert_eq!(queue.take_deferred(), 2);
+        assert_eq!(queue.take_deferred(), 0);
+    }
+}
@@ -1,31 +1,48 @@
 extern crate impl2001_rs;
 
 use anyhow::{anyhow, Result};
-use chrono::prelude::Utc;
+use chrono::prelude::{NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use std::fs;
-use std::io::{sink, Write};
+use std::io::Write;
 use std::path::Path;
 
 use crate::crypto_util;
 use crate::impl2001_rs::pip::pip2001::Pip2001;
 use crate::impl2001_rs::pip::pip2001::Pip2001MessageType;
 use crate::impl2001_rs::pip::InputObject;
-use crate::prs_utility_rust::utility;
-use atom_syndication::{Feed, Generator, Person};
+use crate::impl2001_rs::pip::Pip;
 
 use super::SETTINGS;
 use crate::db;
-use crate::db::models::{Post, PostPartial};
-use crate::frontmatter;
+use crate::db::models::{Content, Post, PostMeta, PostPartial};
+use crate::diff::{self, Diff, Granularity, TooLarge};
+use crate::feed::{self, Feed, Format};
+use crate::fetcher::{ContentResolver, Resolved};
+use crate::frontmatter::{self, MarkdownAttrs};
 use crate::prs;
-
+use crate::webhook::{self, EventType};
+
+// quarantined posts are fetched again after this many seconds
+const QUARANTINE_RETRY_SECS: i64 = 3600;
+// the longest update chain followed to find when a post was first published
+const MAX_UPDATE_CHAIN_LEN: usize = 100;
+// contents parsed per query by backfill_post_meta
+const BACKFILL_BATCH_SIZE: i64 = 500;
+// text search configuration when atom.search_config is not set
+const DEFAULT_SEARCH_CONFIG: &str = "simple";
+// posts queued to fetch per round when atom.fetch_batch_size is not set
+const DEFAULT_FETCH_BATCH_SIZE: i64 = 1000;
+
+/// `trx_created_at` is when the transaction was read from the chain, the
+/// time recorded for the allow/deny it carries.
 pub fn process_pip2001_message<'a>(
     conn: &PgConnection,
     pipobject: &Pip2001,
     tx_id: &'a str,
     user_pubaddr: &'a str,
     trx_table_num: i64,
+    trx_created_at: NaiveDateTime,
     encryption: &str,
 ) -> bool {
     match pipobject.msg_type {
@@ -48,14 +65,20 @@ pub fn process_pip2001_message<'a>(
                     &pipobject.data
                 );
             }
-            let now = Utc::now().naive_utc();
             for user_pubaddr in users_list.split(',') {
                 debug!(
                     "tx_id = {} user = {} user_action = {:?}",
                     tx_id, user_pubaddr, users_action
                 );
-                db::save_user(&conn, &user_pubaddr, &users_action, &tx_id, &topic, now)
-                    .expect("save user failed");
+                db::save_user(
+                    &conn,
+                    &user_pubaddr,
+                    &users_action,
+                    &tx_id,
+                    &topic,
+                    trx_created_at,
+                )
+                .expect("save user failed");
                 db::update_last_status(&conn, "tx_num", trx_table_num)
                     .expect("update last_tx_num failed");
             }
@@ -65,6 +88,7 @@ pub fn process_pip2001_message<'a>(
             let hash_alg = &pipobject.data["hash_alg"];
             let topic = &pipobject.data["topic"];
             let url: &str;
+            let uris_json: String;
             let uris = &pipobject.meta["uris"];
             match uris {
                 InputObject::String(_s) => {
@@ -76,6 +100,7 @@ pub fn process_pip2001_message<'a>(
                 }
                 InputObject::VecOfString(v) => {
                     url = &v[0];
+                    uris_json = serde_json::to_string(v).expect("meta.uris to json str failed");
                 }
             }
 
@@ -94,6 +119,7 @@ pub fn process_pip2001_message<'a>(
                 &hash_alg,
                 &topic,
                 &url,
+                &uris_json,
                 encryption,
                 now,
             )
@@ -113,11 +139,57 @@ pub fn process_pip2001_message<'a>(
     true
 }
 
+/// Replay processed PUBLISH_MANAGEMENT transactions in block order to rebuild
+/// the per-topic `users` state and its `user_transactions` history.
+pub fn rebuild_users(conn: &PgConnection) -> Result<()> {
+    let mut p: Pip2001 = Pip2001::new();
+    let trxs = db::get_trxs(conn, true)?;
+    for trx in trxs {
+        match trx.verify_signature() {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                error!(
+                    "block_num = {}, trx verify_signature failed: {}",
+                    trx.block_num, e
+                );
+                continue;
+            }
+        }
+
+        let json_post_str = trx.to_post_json_str();
+        if let Ok(Some(pipobject)) = p.from_json(&json_post_str) {
+            match pipobject.msg_type {
+                Pip2001MessageType::PUBLISH_MANAGEMENT => {}
+                _ => continue,
+            }
+            let data: prs::Pip2001ActionData = serde_json::from_str(&trx.data)?;
+            debug!(
+                "rebuild users from block_num = {} trx_id = {}",
+                trx.block_num, trx.trx_id
+            );
+            process_pip2001_message(
+                conn,
+                &pipobject,
+                &data.id,
+                &trx.user_address,
+                i64::from(trx.id),
+                trx.created_at,
+                &data.get_encryption(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Link the post replaced by `post` into its revision history. The replaced
+/// post and its content are kept, it is only hidden from lists and feeds.
 pub fn process_post_updated(connection: &PgConnection, post: &Post) -> bool {
     // 被更新的 publish_tx_id
     let updated_publish_tx_id = post.updated_tx_id.trim();
 
-    if updated_publish_tx_id.len() == 0 {
+    if updated_publish_tx_id.is_empty() {
         return true;
     }
 
@@ -136,26 +208,31 @@ pub fn process_post_updated(connection: &PgConnection, post: &Post) -> bool {
                     &post.publish_tx_id, updated_post.user_address, post.user_address
                 );
                 return false;
-            } else {
-                // delete old content
-                debug!("delete content, file_hash = {}", updated_post.file_hash);
-                if let Err(e) = db::delete_content(connection, &updated_post.file_hash) {
+            }
+            if let Some(superseded_by) = &updated_post.superseded_by {
+                if superseded_by.trim() != post.publish_tx_id.trim() {
+                    // the first update wins, a fork of the history is not followed
                     error!(
-                        "delete content failed, file_hash = {}, error = {}",
-                        updated_post.file_hash, e
+                        "update post failed, publish_tx_id: {}, {} is already updated by {}",
+                        &post.publish_tx_id, updated_publish_tx_id, superseded_by
                     );
                     return false;
                 }
+                return true;
+            }
 
-                // delete old post
-                debug!("delete post, file_hash = {}", updated_post.file_hash);
-                if let Err(e) = db::delete_post(connection, &updated_post.file_hash) {
-                    error!(
-                        "delete old content failed, file_hash = {}, error = {}",
-                        updated_post.file_hash, e
-                    );
-                    return false;
-                }
+            debug!(
+                "supersede post, publish_tx_id = {} by {}",
+                updated_publish_tx_id, post.publish_tx_id
+            );
+            if let Err(e) =
+                db::supersede_post(connection, updated_publish_tx_id, post.publish_tx_id.trim())
+            {
+                error!(
+                    "supersede post failed, publish_tx_id = {}, error = {}",
+                    updated_publish_tx_id, e
+                );
+                return false;
             }
         }
         Err(e) => {
@@ -170,147 +247,172 @@ pub fn process_post_updated(connection: &PgConnection, post: &Post) -> bool {
     true
 }
 
-pub fn fetchcontent(connection: &PgConnection) {
-    let result_posts = db::get_posts(connection, false, 1000);
-    match result_posts {
-        Ok(posts) => {
-            for post in posts {
-                debug!("fetch file_hash = {} url = {}", post.file_hash, post.url);
-                let response = fetch_markdown(post.url.clone());
-                match response {
-                    Ok(data) => {
-                        let html;
-                        if !post.encryption.is_empty() {
-                            let enc_post: prs::EncPost =
-                                match serde_json::from_slice(&data.as_bytes()) {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        error!(
-                                        "parse encryption post failed, post.url = {}, error = {}",
-                                        post.url, e
-                                    );
-                                        continue;
-                                    }
-                                };
-
-                            if let Some(topic_conf) = SETTINGS.get_topic(&post.topic) {
-                                html = match decrypt_aes_256_cbc(
-                                    &topic_conf.encryption_key,
-                                    &topic_conf.iv_prefix,
-                                    &enc_post.session,
-                                    &enc_post.content,
-                                ) {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        error!(
-                                            "decrypt enc post file_hash = {} failed: {:?}",
-                                            post.file_hash, e
-                                        );
-                                        continue;
-                                    }
-                                }
-                            } else {
-                                error!("can not find topic = {} from toml config", &post.topic);
-                                continue;
-                            }
-                        } else {
-                            html = data;
-                        }
-                        let hex = utility::hash_text(&html, &post.hash_alg)
-                            .ok()
-                            .expect(&format!(
-                                "utility::hash_text failed, hash_alg = {} html = {}",
-                                &post.hash_alg, &html
-                            ));
-                        // just check and output error message
-                        if hex != post.file_hash {
-                            error!(
-                                "hex != file_hash, hash_alg = {} hex = {} file_hash = {} url = {}",
-                                &post.hash_alg, hex, post.file_hash, post.url
-                            );
-                        }
-
-                        let content = db::get_content(connection, &post.file_hash);
-                        match content {
-                            Ok(_) => {
-                                debug!("content already exists, file_hash = {}", &post.file_hash);
-                            }
-                            Err(e) => {
-                                if e == diesel::NotFound {
-                                    if let Err(e) = db::save_content(
-                                        connection,
-                                        &post.file_hash,
-                                        &post.url,
-                                        &html,
-                                    ) {
-                                        error!(
-                                            "save_content file_hash = {} url = {} failed: {:?}",
-                                            &post.file_hash, &post.url, e
-                                        );
-                                        continue;
-                                    }
-                                } else {
-                                    error!("get_content failed: {}", e);
-                                }
-                            }
-                        }
-
-                        db::update_post_status(connection, &post.file_hash, true, true)
-                            .expect("update_post_status failed");
+/// Posts waiting for their content: not fetched yet, or quarantined long
+/// enough ago to retry in case the host fixed the file.
+pub fn get_posts_to_fetch(connection: &PgConnection) -> Result<Vec<Post>, diesel::result::Error> {
+    let retry_before = Utc::now().naive_utc() - chrono::Duration::seconds(QUARANTINE_RETRY_SECS);
+    let batch_size = SETTINGS
+        .get()
+        .atom
+        .fetch_batch_size
+        .unwrap_or(DEFAULT_FETCH_BATCH_SIZE);
+    let mut posts = db::get_posts(connection, false, batch_size)?;
+    posts.extend(db::get_quarantined_posts(
+        connection,
+        retry_before,
+        batch_size,
+    )?);
+    Ok(posts)
+}
 
-                        if !process_post_updated(connection, &post) {
-                            error!(
-                                "post/content update failed, post.file_hash = {}, skip",
-                                post.file_hash
-                            );
-                            continue;
-                        }
-                    }
-                    Err(e) => {
-                        if format!("{}", e).contains("status code: 404") {
-                            // delete posts
-                            debug!("post.file_hash = {} fetch 404, delete it", &post.file_hash);
-                            db::delete_post(connection, &post.file_hash)
-                                .expect("update post.deleted failed");
-                            db::update_notify_status(connection, &post.publish_tx_id, true)
-                                .expect("update deleted post notify status failed");
-                        } else {
-                            error!("fetch_markdown {} failed: {:?}", &post.url, e);
-                        }
-                        continue;
-                    }
+/// Fetch, verify and save the content of `post`. Returns false when it was
+/// deferred because a content host was busy.
+pub fn fetch_post_content(
+    connection: &PgConnection,
+    resolver: &ContentResolver,
+    post: &Post,
+) -> bool {
+    let uris = post.get_uris();
+    debug!("fetch file_hash = {} uris = {:?}", post.file_hash, uris);
+    let resolved = resolver.resolve(&uris, &post.file_hash, &post.hash_alg, |data| {
+        decode_content(post, data)
+    });
+    let (url, html) = match resolved {
+        Resolved::Verified { uri, body } => (uri, body),
+        Resolved::Mismatch { uri, hash, .. } => {
+            if is_strict_verify(&post.topic) {
+                warn!(
+                    "quarantine post, hash_alg = {} hex = {} file_hash = {} url = {}",
+                    &post.hash_alg, hash, post.file_hash, uri
+                );
+                if let Err(e) = db::quarantine_post(connection, &post.file_hash, &hash) {
+                    error!(
+                        "quarantine_post file_hash = {} failed: {}",
+                        &post.file_hash, e
+                    );
                 }
+                webhook::save_post_event(
+                    connection,
+                    post,
+                    EventType::ContentVerificationFailed,
+                    json!({
+                        "file_hash": post.file_hash,
+                        "observed_hash": hash,
+                        "uri": uri,
+                    }),
+                );
+                return true;
+            }
+            // never saved, so it is not served either
+            error!(
+                "hex != file_hash, hash_alg = {} hex = {} file_hash = {} url = {}",
+                &post.hash_alg, hash, post.file_hash, uri
+            );
+            if let Err(e) = db::update_post_status(connection, &post.file_hash, true, false) {
+                error!(
+                    "update_post_status file_hash = {} failed: {}",
+                    &post.file_hash, e
+                );
             }
+            return true;
+        }
+        Resolved::NotFound => {
+            // delete posts
+            debug!("post.file_hash = {} fetch 404, delete it", &post.file_hash);
+            db::delete_post(connection, &post.file_hash).expect("update post.deleted failed");
+            db::update_notifies_status_by_data_id(connection, &post.publish_tx_id, true)
+                .expect("update deleted post notify status failed");
+            webhook::save_post_event(
+                connection,
+                post,
+                EventType::PostDeleted,
+                json!({ "file_hash": post.file_hash }),
+            );
+            return true;
+        }
+        Resolved::Deferred => {
+            debug!("fetch file_hash = {} deferred", &post.file_hash);
+            return false;
+        }
+        Resolved::Failed(e) => {
+            error!("fetch {:?} failed: {:?}", &uris, e);
+            return true;
         }
-        Err(e) => error!("get posts failed: {:?}", e),
-    }
-}
-
-pub fn fetch_markdown(url: String) -> Result<String> {
-    let mut easy = prs::get_curl_easy()?;
-    easy.url(&url)?;
-    let _redirect = easy.follow_location(true);
-    let mut data = Vec::new();
-    {
-        let mut transfer = easy.transfer();
-        transfer.write_function(|new_data| {
-            data.extend_from_slice(new_data);
-            Ok(new_data.len())
-        })?;
-        transfer.perform()?;
     };
 
-    let html = String::from_utf8(data).expect("body is not valid UTF8!");
-    let result = easy.response_code();
-    match result {
-        Ok(respcode) => {
-            if respcode == 200 {
-                Ok(html)
+    let content = db::get_content(connection, &post.file_hash);
+    match content {
+        Ok(_) => {
+            debug!("content already exists, file_hash = {}", &post.file_hash);
+        }
+        Err(e) => {
+            if e == diesel::NotFound {
+                if let Err(e) = db::save_content(connection, &post.file_hash, &url, &html) {
+                    error!(
+                        "save_content file_hash = {} url = {} failed: {:?}",
+                        &post.file_hash, &url, e
+                    );
+                    return true;
+                }
             } else {
-                Err(anyhow!("url = {} error status code: {:?}", url, respcode,))
+                error!("get_content failed: {}", e);
             }
         }
-        Err(e) => Err(anyhow!("url = {} error = {}", url, e)),
+    }
+
+    if let Err(e) = save_post_meta(connection, &post.file_hash, &html) {
+        error!(
+            "save_post_meta file_hash = {} failed: {}",
+            &post.file_hash, e
+        );
+    }
+
+    db::update_post_status(connection, &post.file_hash, true, true)
+        .expect("update_post_status failed");
+
+    if !process_post_updated(connection, post) {
+        error!(
+            "post/content update failed, post.file_hash = {}, skip",
+            post.file_hash
+        );
+    }
+    true
+}
+
+fn is_strict_verify(topic: &str) -> bool {
+    match SETTINGS.get().get_topic(topic) {
+        Some(topic_conf) => topic_conf.strict_verify.unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Turn a fetched body into the markdown text that `file_hash` was computed
+/// from, decrypting it with the topic key for encrypted posts.
+fn decode_content(post: &Post, data: String) -> Result<String> {
+    if post.encryption.is_empty() {
+        return Ok(data);
+    }
+
+    let enc_post: prs::EncPost = serde_json::from_slice(&data.as_bytes())
+        .map_err(|e| anyhow!("parse encryption post failed, error = {}", e))?;
+    match SETTINGS.get().get_topic(&post.topic) {
+        Some(topic_conf) => decrypt_aes_256_cbc(
+            &topic_conf.encryption_key,
+            &topic_conf.iv_prefix,
+            &enc_post.session,
+            &enc_post.content,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "decrypt enc post file_hash = {} failed: {:?}",
+                post.file_hash,
+                e
+            )
+        }),
+        None => Err(anyhow!(
+            "can not find topic = {} from toml config",
+            &post.topic
+        )),
     }
 }
 
@@ -329,29 +431,32 @@ fn decrypt_aes_256_cbc(
 }
 
 pub fn generate_atom_xml(connection: &PgConnection) -> Result<()> {
-    let xml_output_dir = &SETTINGS.atom.xml_output_dir;
+    let settings = SETTINGS.get();
+    let xml_output_dir = &settings.atom.xml_output_dir;
     fs::create_dir_all(&xml_output_dir).expect("create xml_output_dir failed");
 
-    for item in &SETTINGS.topics {
+    for item in &settings.topics {
         let topic = &item.topic;
         debug!("generate atom for topic = {}", topic);
         let posts_result = db::get_allow_posts(&connection, topic);
         match posts_result {
             Ok(posts) => {
-                let atomstring = atom(&connection, posts);
-
-                let fpath = Path::new(&xml_output_dir).join(topic);
-                let mut file = match fs::File::create(&fpath) {
-                    Ok(file) => file,
-                    Err(e) => {
-                        return Err(anyhow!(
-                            "create file failed: {}, fpath = {}",
-                            fpath.as_os_str().to_string_lossy(),
-                            e
-                        ))
-                    }
-                };
-                file.write_all(atomstring.as_bytes())?;
+                let feed = build_feed(&connection, topic, posts);
+
+                for format in Format::all() {
+                    let fpath = Path::new(&xml_output_dir).join(format.file_name(topic));
+                    let mut file = match fs::File::create(&fpath) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            return Err(anyhow!(
+                                "create file failed: {}, fpath = {}",
+                                fpath.as_os_str().to_string_lossy(),
+                                e
+                            ))
+                        }
+                    };
+                    file.write_all(feed.render(format).as_bytes())?;
+                }
             }
             Err(e) => error!("get_allow_posts failed: {}", e),
         }
@@ -360,99 +465,347 @@ pub fn generate_atom_xml(connection: &PgConnection) -> Result<()> {
     Ok(())
 }
 
-pub fn atom(connection: &PgConnection, posts: Vec<PostPartial>) -> String {
-    use atom_syndication::Content;
-    use atom_syndication::Entry;
-
-    let mut generator = Generator::default();
-    generator.set_value("PRESSone Atom Generator");
-
-    let mut feed = Feed::default();
-    feed.set_generator(generator);
-    let mut entries = Vec::new();
+/// The feed of `posts`, in no particular format yet. Webhooks are left to
+/// the webhook workers of syncserver, rendering a feed never sends them.
+pub fn build_feed(connection: &PgConnection, topic: &str, posts: Vec<PostPartial>) -> Feed {
+    let feed_conf = SETTINGS
+        .get()
+        .get_topic(topic)
+        .and_then(|v| v.feed)
+        .unwrap_or_default();
+    let mut items = Vec::new();
 
     for post in posts {
-        debug!("generate atom for post file_hash = {} ", post.file_hash);
+        debug!("generate feed for post file_hash = {} ", post.file_hash);
         let result_content = db::get_content(connection, &post.file_hash);
         match result_content {
             Ok(post_content) => {
-                let markdown_attrs = frontmatter::parse(&post_content.content);
+                let markdown_attrs = match get_markdown_attrs(connection, &post_content) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!(
+                            "parse frontmatter of file_hash = {} failed: {}",
+                            post.file_hash, e
+                        );
+                        MarkdownAttrs::default()
+                    }
+                };
                 debug!(
-                    "post content title = {} author = {} published = {}",
+                    "post content title = {} author = {} published = {:?}",
                     markdown_attrs.title, markdown_attrs.author, markdown_attrs.published
                 );
-                let mut feed_content = Content::default();
-                feed_content.set_content_type("text/markdown".to_string());
-                feed_content.set_value(format!("<![CDATA[{}]]>", post_content.content));
-
-                let mut person = Person::default();
-                person.set_name(&markdown_attrs.author);
-                let mut entry = Entry::default();
-
-                entry.set_id(&post.publish_tx_id);
-                entry.set_title(&markdown_attrs.title);
-                entry.set_published(markdown_attrs.published);
-                entry.set_authors(vec![person]);
-                entry.set_content(feed_content);
-                entries.push(entry);
-                // check and send webhook notify
-                if let Err(e) = check_and_send_webhook(connection, &post.publish_tx_id) {
-                    error!("check_and_send_webhook failed: {}", e);
-                }
+                let first_published_at = get_first_published_at(connection, &post);
+                items.push(feed::Item::new(
+                    &feed_conf,
+                    &post,
+                    &post_content,
+                    &markdown_attrs,
+                    first_published_at,
+                ));
             }
             Err(e) => error!("get content failed: {:?}", e),
         }
     }
 
-    let mut feed = Feed::default();
-    feed.set_entries(entries);
+    Feed::new(topic, &feed_conf, items)
+}
+
+/// Parse the frontmatter of a content once and keep it in post_meta. A
+/// frontmatter that fails to parse is saved with its error and empty fields.
+pub fn save_post_meta(
+    connection: &PgConnection,
+    file_hash: &str,
+    content: &str,
+) -> Result<PostMeta, diesel::result::Error> {
+    let post_meta = match frontmatter::parse(content) {
+        Ok(attrs) => db::save_post_meta(connection, file_hash, &attrs, None)?,
+        Err(e) => {
+            warn!(
+                "parse frontmatter of file_hash = {} failed: {}",
+                file_hash, e
+            );
+            db::save_post_meta(
+                connection,
+                file_hash,
+                &MarkdownAttrs::default(),
+                Some(&e.to_string()),
+            )?
+        }
+    };
+    db::save_post_search(connection, file_hash, &get_search_config())?;
 
-    feed.write_to(sink()).expect("feed.write_to failed");
-    feed.to_string()
+    Ok(post_meta)
 }
 
-pub fn check_and_send_webhook(conn: &PgConnection, data_id: &str) -> Result<()> {
-    let notify_result = db::get_notify_by_data_id(conn, data_id);
-    match notify_result {
-        Ok(notify) => {
-            if notify.success || notify.retries >= 3 {
-                debug!(
-                    "block_num = {} trx_id = {} notify webhook success or retries >= 3, skip ...",
-                    notify.block_num, notify.trx_id
-                );
-                return Ok(());
+/// The postgresql text search configuration to index and search posts with.
+pub fn get_search_config() -> String {
+    SETTINGS
+        .get()
+        .atom
+        .search_config
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_SEARCH_CONFIG))
+}
+
+/// Check the configured search_config once at startup, an unknown one fails
+/// the indexing of every post.
+pub fn check_search_config(connection: &PgConnection) -> Result<()> {
+    let search_config = get_search_config();
+    db::check_search_config(connection, &search_config).map_err(|e| {
+        anyhow!(
+            "atom.search_config = {} is not a text search configuration of the database: {}",
+            search_config,
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// The frontmatter of a content from post_meta, parsed and saved first when
+/// it is not there yet.
+pub fn get_markdown_attrs(connection: &PgConnection, content: &Content) -> Result<MarkdownAttrs> {
+    let post_meta = match db::get_post_meta(connection, &content.file_hash) {
+        Ok(v) => v,
+        Err(diesel::NotFound) => save_post_meta(connection, &content.file_hash, &content.content)?,
+        Err(e) => return Err(e.into()),
+    };
+    match post_meta.parse_error {
+        Some(e) => Err(anyhow!(e)),
+        None => Ok(post_meta.to_attrs()),
+    }
+}
+
+/// Parse the frontmatter and index every saved content again, for contents
+/// saved before post_meta existed or after the parser or search_config changed.
+pub fn backfill_post_meta(connection: &PgConnection) -> Result<usize> {
+    let mut total = 0;
+    let mut after = String::new();
+    loop {
+        let contents = db::get_contents_after(connection, &after, BACKFILL_BATCH_SIZE)?;
+        if contents.is_empty() {
+            break;
+        }
+        for content in &contents {
+            save_post_meta(connection, &content.file_hash, &content.content)?;
+            total += 1;
+        }
+        after = contents[contents.len() - 1].file_hash.clone();
+        info!("backfilled post_meta of {} contents", total);
+    }
+
+    Ok(total)
+}
+
+/// When the first revision of `post` was saved, following its update chain
+/// back through the posts it replaced.
+fn get_first_published_at(connection: &PgConnection, post: &PostPartial) -> NaiveDateTime {
+    let mut published_at = post.updated_at;
+    let mut updated_tx_id = post.updated_tx_id.trim().to_string();
+    // the chain comes from users, do not follow a loop forever
+    for _ in 0..MAX_UPDATE_CHAIN_LEN {
+        if updated_tx_id.is_empty() {
+            break;
+        }
+        match db::get_post_by_publish_tx_id(connection, &updated_tx_id) {
+            Ok(updated_post) => {
+                published_at = std::cmp::min(published_at, updated_post.updated_at);
+                updated_tx_id = updated_post.updated_tx_id.trim().to_string();
             }
-            let payload = prs::NotifyPayload {
-                block: prs::NotifyBlock {
-                    data_id: notify.data_id.clone(),
-                    block_num: notify.block_num,
-                    trx_id: notify.trx_id,
-                },
-            };
-            if let Some(notify_url) = SETTINGS.get_webhook_by_topic(&notify.topic) {
-                debug!(
-                    "notify data_id = {} topic = {} success = {}",
-                    notify.data_id, notify.topic, notify.success
-                );
-                debug!("send notify payload to {}", notify_url);
-                match prs::notify_webhook(&payload, &notify_url) {
-                    Ok(status_code) => {
-                        let success = status_code == 200;
-                        db::update_notify_status(conn, &notify.data_id, success)?;
-                    }
-                    Err(e) => error!(
-                        "block_num = {}, url = {}, notify_webhook failed: {}",
-                        notify.block_num, notify_url, e
-                    ),
-                }
-            } else {
-                warn!(
-                    "can not find webhook url for topic = {}, skip ...",
-                    notify.topic
-                );
+            Err(_) => break,
+        }
+    }
+
+    published_at
+}
+
+/// Every post in the update chain of `post`, the first revision first and
+/// the latest last, `post` included.
+pub fn get_update_chain(
+    connection: &PgConnection,
+    post: Post,
+) -> Result<Vec<Post>, diesel::result::Error> {
+    let mut seen = vec![post.publish_tx_id.trim().to_string()];
+
+    let mut older = Vec::new();
+    let mut updated_tx_id = post.updated_tx_id.trim().to_string();
+    // the chain comes from users, do not follow a loop forever
+    while !updated_tx_id.is_empty() && older.len() < MAX_UPDATE_CHAIN_LEN {
+        if seen.contains(&updated_tx_id) {
+            break;
+        }
+        let updated_post = match db::get_post_by_publish_tx_id(connection, &updated_tx_id) {
+            Ok(v) => v,
+            Err(diesel::result::Error::NotFound) => break,
+            Err(e) => return Err(e),
+        };
+        // only the author can update a post
+        if updated_post.user_address != post.user_address {
+            break;
+        }
+        seen.push(updated_tx_id);
+        updated_tx_id = updated_post.updated_tx_id.trim().to_string();
+        older.push(updated_post);
+    }
+
+    let mut newer = Vec::new();
+    let mut publish_tx_id = post.publish_tx_id.trim().to_string();
+    let mut superseded_by = post.superseded_by.clone();
+    while newer.len() < MAX_UPDATE_CHAIN_LEN {
+        // follow the recorded link, or the update which is not processed yet
+        let next_post = match &superseded_by {
+            Some(v) => db::get_post_by_publish_tx_id(connection, v.trim()),
+            None => db::get_post_by_updated_tx_id(connection, &publish_tx_id, &post.user_address),
+        };
+        let next_post = match next_post {
+            Ok(v) => v,
+            Err(diesel::result::Error::NotFound) => break,
+            Err(e) => return Err(e),
+        };
+        publish_tx_id = next_post.publish_tx_id.trim().to_string();
+        if seen.contains(&publish_tx_id) {
+            break;
+        }
+        seen.push(publish_tx_id.clone());
+        superseded_by = next_post.superseded_by.clone();
+        newer.push(next_post);
+    }
+
+    let mut chain = Vec::with_capacity(older.len() + newer.len() + 1);
+    chain.extend(older.into_iter().rev());
+    chain.push(post);
+    chain.extend(newer);
+    Ok(chain)
+}
+
+/// One revision of a post in its update chain, numbered from 1.
+pub struct Revision {
+    pub number: usize,
+    pub post: Post,
+    pub content: Content,
+    // the post is deleted or its user is not allowed, content is empty
+    pub withheld: bool,
+}
+
+impl Revision {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "revision": self.number,
+            "publish_tx_id": self.post.publish_tx_id.trim(),
+            "file_hash": self.post.file_hash.trim(),
+            "updated_at": self.post.updated_at,
+            "withheld": self.withheld,
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("{}#{}", self.post.publish_tx_id.trim(), self.number)
+    }
+}
+
+/// Revisions `from` and `to` of the update chain of `publish_tx_id` with
+/// their content. `to` defaults to the revision of `publish_tx_id` and `from`
+/// to the one before `to`. `NotFound` when a revision or its content is missing.
+/// The content of a withheld revision is left empty, as the post detail does.
+pub fn get_revision_pair(
+    connection: &PgConnection,
+    publish_tx_id: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<(Revision, Revision), diesel::result::Error> {
+    let post = db::get_post_by_publish_tx_id(connection, publish_tx_id)?;
+    let chain = get_update_chain(connection, post)?;
+    let own = chain
+        .iter()
+        .position(|v| v.publish_tx_id.trim() == publish_tx_id.trim())
+        .map(|v| v + 1)
+        .unwrap_or(chain.len());
+    let to = to.unwrap_or(own);
+    let from = from.unwrap_or_else(|| to.saturating_sub(1));
+    if from == 0 || to == 0 || from > chain.len() || to > chain.len() {
+        return Err(diesel::result::Error::NotFound);
+    }
+
+    let mut posts: Vec<Option<Post>> = chain.into_iter().map(Some).collect();
+    let from_post = posts[from - 1]
+        .take()
+        .ok_or(diesel::result::Error::NotFound)?;
+    let to_post = match posts[to - 1].take() {
+        Some(v) => v,
+        // `from` and `to` are the same revision
+        None => db::get_post_by_publish_tx_id(connection, &from_post.publish_tx_id)?,
+    };
+    let revision = |number: usize, post: Post| -> Result<Revision, diesel::result::Error> {
+        let mut content = db::get_content(connection, &post.file_hash)?;
+        let withheld = is_content_withheld(connection, &post)?;
+        if withheld {
+            content.content = String::new();
+        }
+        Ok(Revision {
+            number,
+            post,
+            content,
+            withheld,
+        })
+    };
+    Ok((revision(from, from_post)?, revision(to, to_post)?))
+}
+
+/// The feeds leave out deleted posts and posts of users who are not allowed,
+/// so their content is withheld from the other endpoints too.
+pub fn is_content_withheld(
+    connection: &PgConnection,
+    post: &Post,
+) -> Result<bool, diesel::result::Error> {
+    if post.deleted {
+        return Ok(true);
+    }
+    match db::get_user(connection, &post.topic, &post.user_address) {
+        Ok(user) => Ok(user.status.trim() != "allow"),
+        Err(diesel::NotFound) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// The diff as JSON, with the changes and stats of `diff::Diff`.
+pub fn revision_diff_json(
+    from: &Revision,
+    to: &Revision,
+    granularity: Granularity,
+) -> Result<serde_json::Value, TooLarge> {
+    let result = Diff::new(&from.content.content, &to.content.content, granularity)?;
+    Ok(json!({
+        "from": from.to_json(),
+        "to": to.to_json(),
+        "granularity": granularity.as_str(),
+        "changes": result.changes,
+        "stats": result.stats,
+    }))
+}
+
+/// The diff as text, a unified diff of lines or a word diff.
+pub fn revision_diff_text(
+    from: &Revision,
+    to: &Revision,
+    granularity: Granularity,
+) -> Result<String, TooLarge> {
+    match granularity {
+        Granularity::Line => diff::unified(
+            &from.name(),
+            &to.name(),
+            &from.content.content,
+            &to.content.content,
+        ),
+        Granularity::Word => {
+            let result = Diff::new(&from.content.content, &to.content.content, granularity)?;
+            if result.is_empty() {
+                return Ok(String::new());
             }
+            Ok(format!(
+                "--- {}\n+++ {}\n{}",
+                from.name(),
+                to.name(),
+                result.to_word_diff()
+            ))
         }
-        Err(e) => error!("get notify by data_id = {} failed: {:?}", data_id, e),
     }
-    Ok(())
 }
@@ -10,8 +10,79 @@ use std::io::Read;
 use std::time::Duration;
 
 use super::SETTINGS;
-use crate::settings;
+use crate::pipeline;
+use crate::replay::ReplayChainSource;
 use crate::url::URL;
+use crate::webhook::EventType;
+
+/// Where transactions and chain info come from. `HttpChainSource` talks to
+/// the PRS chain api, `ReplayChainSource` replays a recorded JSON-lines dump.
+pub trait ChainSource {
+    fn fetch_transactions_by_topic(
+        &mut self,
+        topic: &str,
+        block_num: i64,
+        count: usize,
+    ) -> Result<Vec<Transaction>>;
+
+    /// The block_num of the first transaction of `topic`. `blocknum` is
+    /// exclusive, so reading the topic from its start uses this minus one.
+    fn get_start_block_num_by_topic(&mut self, topic: &str) -> Result<u64>;
+
+    fn get_info(&mut self) -> Result<ChainInfo>;
+}
+
+// chain api requests give up after this many seconds when
+// atom.chain_timeout_secs is not set
+const DEFAULT_CHAIN_TIMEOUT_SECS: u64 = 60;
+
+pub struct HttpChainSource {
+    easy: Easy,
+}
+
+impl HttpChainSource {
+    pub fn new() -> Result<HttpChainSource> {
+        Ok(HttpChainSource {
+            easy: get_curl_easy()?,
+        })
+    }
+
+    /// The kept alive handle, with the timeout of the current settings.
+    fn easy(&mut self) -> Result<&mut Easy> {
+        let timeout = chain_timeout();
+        self.easy.connect_timeout(timeout)?;
+        self.easy.timeout(timeout)?;
+        Ok(&mut self.easy)
+    }
+}
+
+impl ChainSource for HttpChainSource {
+    fn fetch_transactions_by_topic(
+        &mut self,
+        topic: &str,
+        block_num: i64,
+        count: usize,
+    ) -> Result<Vec<Transaction>> {
+        fetch_transactions_by_topic(self.easy()?, topic, block_num, count)
+    }
+
+    fn get_start_block_num_by_topic(&mut self, topic: &str) -> Result<u64> {
+        get_start_block_num_by_topic(topic)
+    }
+
+    fn get_info(&mut self) -> Result<ChainInfo> {
+        get_info(self.easy()?)
+    }
+}
+
+/// Replay from `atom.chain_replay_file` when it is configured, otherwise read
+/// from the chain api.
+pub fn new_chain_source() -> Result<Box<dyn ChainSource>> {
+    match &SETTINGS.get().atom.chain_replay_file {
+        Some(path) => Ok(Box::new(ReplayChainSource::open(path)?)),
+        None => Ok(Box::new(HttpChainSource::new()?)),
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChainInfo {
@@ -153,7 +224,20 @@ impl Transaction {
     }
 
     pub fn has_invalid_topic(&self) -> bool {
-        settings::Settings::contains_topic(&SETTINGS, &self.get_topic())
+        SETTINGS.get().contains_topic(&self.get_topic())
+    }
+
+    fn new_notify_payload(&self, event: EventType, data: Value) -> NotifyPayload {
+        NotifyPayload {
+            event: event.as_str().to_string(),
+            block: NotifyBlock {
+                data_id: self.data.id.clone(),
+                block_num: self.block_num,
+                trx_id: self.trx_id.clone(),
+            },
+            data,
+            metadata: None,
+        }
     }
 
     pub fn get_notify_payload(&self) -> Result<Option<NotifyPayload>> {
@@ -178,16 +262,29 @@ impl Transaction {
                 );
                 match pipobject.msg_type {
                     Pip2001MessageType::PUBLISH => {
-                        let payload = NotifyPayload {
-                            block: NotifyBlock {
-                                data_id: self.data.id.clone(),
-                                block_num: self.block_num,
-                                trx_id: self.trx_id.clone(),
-                            },
+                        let (event, data) = match pipobject.data.get("updated_tx_id") {
+                            Some(v) if !v.is_empty() => {
+                                (EventType::PostUpdated, json!({ "updated_tx_id": v }))
+                            }
+                            _ => (EventType::PostPublished, json!({})),
                         };
-                        return Ok(Some(payload));
+                        return Ok(Some(self.new_notify_payload(event, data)));
+                    }
+                    Pip2001MessageType::PUBLISH_MANAGEMENT => {
+                        let (event, users) = if let Some(v) = pipobject.data.get("allow") {
+                            (EventType::UserAllowed, v)
+                        } else if let Some(v) = pipobject.data.get("deny") {
+                            (EventType::UserDenied, v)
+                        } else {
+                            return Err(anyhow!(
+                                "can not find allow or deny from action data = {:?}",
+                                &self.data
+                            ));
+                        };
+                        let users: Vec<&str> = users.split(',').collect();
+                        let data = json!({ "topic": self.get_topic(), "users": users });
+                        return Ok(Some(self.new_notify_payload(event, data)));
                     }
-                    Pip2001MessageType::PUBLISH_MANAGEMENT => return Ok(None),
                     _ => {
                         return Err(anyhow!("unsupport action data = {:?}", &self.data));
                     }
@@ -315,7 +412,13 @@ pub fn fetch_transactions_by_topic(
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NotifyPayload {
+    pub event: String,
     pub block: NotifyBlock,
+    // event specific data, e.g. `updated_tx_id` of post_updated
+    pub data: Value,
+    // frontmatter of the post, only when the topic asks for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -336,10 +439,24 @@ pub struct EncPost {
 
 pub fn get_curl_easy() -> Result<Easy> {
     // keep alive
-    let timeout = 60;
+    get_curl_easy_with_timeout(chain_timeout())
+}
+
+fn chain_timeout() -> Duration {
+    Duration::from_secs(
+        SETTINGS
+            .get()
+            .atom
+            .chain_timeout_secs
+            .unwrap_or(DEFAULT_CHAIN_TIMEOUT_SECS),
+    )
+}
+
+/// A handle giving up on a request, connecting included, after `timeout`.
+pub fn get_curl_easy_with_timeout(timeout: Duration) -> Result<Easy> {
     let mut easy = Easy::new();
-    easy.connect_timeout(Duration::from_secs(timeout))?;
-    easy.timeout(Duration::from_secs(timeout))?;
+    easy.connect_timeout(timeout)?;
+    easy.timeout(timeout)?;
     easy.accept_encoding("gzip")?;
 
     Ok(easy)
@@ -441,20 +558,20 @@ pub fn get_info(easy: &mut Easy) -> Result<ChainInfo> {
     Err(anyhow!("error body: {:?}", &response_content))
 }
 
-pub fn notify_webhook(payload: &NotifyPayload, url: &str) -> Result<u32> {
+/// POST `payload` to `url` with `Content-Type: application/json` plus the
+/// extra `headers`, returns the status code and response body. The caller
+/// takes a request slot of the host with `pipeline::with_host_limit`.
+pub fn notify_webhook(url: &str, payload: &str, headers: &[String]) -> Result<(u32, String)> {
     debug!("notify webhook url = {}", url);
-    let mut easy = get_curl_easy().expect("get curl easy failed");
-    easy.url(&url)
-        .expect(&format!("easy.url failed, url = {}", url));
-    let mut headers = List::new();
-    headers.append("Content-Type: application/json")?;
-    let err_msg = format!("easy.http_headers failed, headers = {:?}", &headers);
-    easy.http_headers(headers).expect(&err_msg);
+    let mut easy = get_curl_easy_with_timeout(pipeline::request_timeout())?;
+    easy.url(&url)?;
+    let mut header_list = List::new();
+    header_list.append("Content-Type: application/json")?;
+    for header in headers {
+        header_list.append(header)?;
+    }
+    easy.http_headers(header_list)?;
     easy.post(true)?;
-    let payload = serde_json::to_string(&payload).expect(&format!(
-        "serde_json::to_string failed, payload = {:?}",
-        payload
-    ));
     debug!(
         "curl -X POST -H 'Content-Type: application/json' -d '{}' {}",
         payload, url
@@ -474,5 +591,6 @@ pub fn notify_webhook(payload: &NotifyPayload, url: &str) -> Result<u32> {
     }
 
     let status_code = easy.response_code()?;
-    Ok(status_code)
+    let body = String::from_utf8_lossy(&response_content).to_string();
+    Ok((status_code, body))
 }
@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Result};
+use pulldown_cmark::{html, Options, Parser};
+use std::str::FromStr;
+
+use crate::frontmatter;
+
+// void elements ammonia may leave in its output, they must be closed in xhtml
+const VOID_ELEMENTS: &[&str] = &["area", "br", "col", "hr", "img", "wbr"];
+
+/// How post content is written in atom `<content>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentFormat {
+    // the raw markdown, type="text/markdown"
+    Markdown,
+    Html,
+    Xhtml,
+}
+
+impl FromStr for ContentFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ContentFormat> {
+        match s {
+            "markdown" => Ok(ContentFormat::Markdown),
+            "html" => Ok(ContentFormat::Html),
+            "xhtml" => Ok(ContentFormat::Xhtml),
+            _ => Err(anyhow!("unknown content_format: {}", s)),
+        }
+    }
+}
+
+/// Render the markdown after the frontmatter to HTML, sanitized against the
+/// ammonia allow-list so scripts, event handlers and unknown tags are dropped.
+pub fn markdown_to_html(mdtext: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let parser = Parser::new_ext(frontmatter::body(mdtext), options);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::Builder::default().clean(&unsafe_html).to_string()
+}
+
+/// Make sanitized HTML well-formed XML: close void elements and replace the
+/// named entities XML does not know.
+pub fn html_to_xhtml(html: &str) -> String {
+    let html = html.replace("&nbsp;", "&#160;");
+    let mut xhtml = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+
+    while let Some(start) = rest.find('<') {
+        xhtml.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match find_tag_end(rest) {
+            Some(v) => v,
+            None => break,
+        };
+        let tag = &rest[..end];
+        let name: String = tag[1..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        if VOID_ELEMENTS.contains(&name.as_str()) && !tag.ends_with('/') {
+            xhtml.push_str(tag);
+            xhtml.push_str(" />");
+        } else {
+            xhtml.push_str(tag);
+            xhtml.push('>');
+        }
+        rest = &rest[end + 1..];
+    }
+    xhtml.push_str(rest);
+
+    xhtml
+}
+
+/// HTML escape a search snippet and wrap the text between the `start` and
+/// `stop` markers of ts_headline in `<mark>`.
+pub fn highlight_snippet(snippet: &str, start: char, stop: char) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c if c == start => html.push_str("<mark>"),
+            c if c == stop => html.push_str("</mark>"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Index of the `>` closing the tag at the start of `s`, skipping quoted
+/// attribute values which may contain `>`.
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_strips_frontmatter() {
+        let html = markdown_to_html("---\ntitle: Hello\n---\n\n# Hello\n\nsome *text*\n");
+        assert!(!html.contains("title:"));
+        assert!(html.contains("<h1>Hello</h1>"));
+        assert!(html.contains("<em>text</em>"));
+    }
+
+    #[test]
+    fn render_sanitizes() {
+        let html = markdown_to_html(
+            "<script>alert(1)</script>\n\n<a href=\"javascript:alert(1)\" onclick=\"x()\">a</a>\n\n<iframe src=\"https://example.com\"></iframe>\n",
+        );
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert(1)</"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("<iframe"));
+    }
+
+    #[test]
+    fn highlight_escapes_snippet() {
+        assert_eq!(
+            highlight_snippet("<script>\u{2}rust\u{3} & 'go'", '\u{2}', '\u{3}'),
+            "&lt;script&gt;<mark>rust</mark> &amp; &#39;go&#39;"
+        );
+    }
+
+    #[test]
+    fn xhtml_closes_void_elements() {
+        assert_eq!(
+            html_to_xhtml(r#"<p>a<br>b&nbsp;<img src="x.png" alt="1 > 0"></p><hr>"#),
+            r#"<p>a<br />b&#160;<img src="x.png" alt="1 > 0" /></p><hr />"#
+        );
+        assert_eq!(html_to_xhtml("<br />"), "<br />");
+        assert_eq!(html_to_xhtml("1 < 2"), "1 < 2");
+    }
+}
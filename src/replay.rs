@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::prs::{ChainInfo, ChainSource, Transaction};
+use crate::settings::MAX_SYNC_PAGE_SIZE;
+use crate::sync;
+
+// a dump reads as many transactions per request as the chain api returns
+const DUMP_PAGE_SIZE: usize = MAX_SYNC_PAGE_SIZE;
+
+/// A chain source backed by a JSON-lines dump, one `prs::Transaction` per
+/// line. Everything in the dump is treated as irreversible.
+pub struct ReplayChainSource {
+    // transactions by topic in block order, the topic is parsed once at load
+    topics: HashMap<String, Vec<Transaction>>,
+    head_block_num: Option<i64>,
+}
+
+impl ReplayChainSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplayChainSource> {
+        let file = fs::File::open(path.as_ref()).map_err(|e| {
+            anyhow!(
+                "open chain replay file {} failed: {}",
+                path.as_ref().to_string_lossy(),
+                e
+            )
+        })?;
+
+        let mut transactions = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let trx: Transaction = serde_json::from_str(&line)
+                .map_err(|e| anyhow!("parse line {} failed: {}", index + 1, e))?;
+            transactions.push(trx);
+        }
+
+        Ok(ReplayChainSource::from(transactions))
+    }
+}
+
+impl From<Vec<Transaction>> for ReplayChainSource {
+    fn from(mut transactions: Vec<Transaction>) -> ReplayChainSource {
+        // stable, so transactions of the same block keep their dump order
+        transactions.sort_by_key(|trx| trx.block_num);
+        let head_block_num = transactions.last().map(|trx| trx.block_num);
+        let mut topics: HashMap<String, Vec<Transaction>> = HashMap::new();
+        for trx in transactions {
+            topics.entry(trx.get_topic()).or_default().push(trx);
+        }
+        ReplayChainSource {
+            topics,
+            head_block_num,
+        }
+    }
+}
+
+impl ChainSource for ReplayChainSource {
+    fn fetch_transactions_by_topic(
+        &mut self,
+        topic: &str,
+        block_num: i64,
+        count: usize,
+    ) -> Result<Vec<Transaction>> {
+        let transactions = match self.topics.get(topic) {
+            Some(v) => v,
+            None => return Ok(Vec::new()),
+        };
+        // never equal, so this is the index of the first block after block_num
+        let start = match transactions
+            .binary_search_by(|trx| trx.block_num.cmp(&block_num).then(Ordering::Less))
+        {
+            Ok(index) | Err(index) => index,
+        };
+        Ok(transactions[start..].iter().take(count).cloned().collect())
+    }
+
+    fn get_start_block_num_by_topic(&mut self, topic: &str) -> Result<u64> {
+        match self.topics.get(topic).and_then(|v| v.first()) {
+            Some(trx) if trx.block_num > 0 => Ok(trx.block_num as u64),
+            _ => Err(anyhow!("get_start_block_num for topic: {} failed", topic)),
+        }
+    }
+
+    fn get_info(&mut self) -> Result<ChainInfo> {
+        match self.head_block_num {
+            Some(block_num) => Ok(ChainInfo {
+                errors: None,
+                success: true,
+                head_block_num: block_num,
+                last_irreversible_block_num: block_num,
+            }),
+            None => Err(anyhow!("chain replay file is empty")),
+        }
+    }
+}
+
+/// Record all transactions of `topic` from `source` into a JSON-lines dump
+/// that `ReplayChainSource` can read back.
+pub fn dump(source: &mut dyn ChainSource, topic: &str, path: &str) -> Result<usize> {
+    let mut file = fs::File::create(path)?;
+    let mut block_num = source.get_start_block_num_by_topic(topic)? as i64 - 1;
+    let mut total = 0;
+    loop {
+        let (transactions, cursor) = sync::fetch_page(source, topic, block_num, DUMP_PAGE_SIZE)?;
+        if transactions.is_empty() {
+            return Ok(total);
+        }
+        // the part of a block after the cursor is written with the next page
+        for trx in transactions.iter().filter(|trx| trx.block_num <= cursor) {
+            writeln!(file, "{}", serde_json::to_string(trx)?)?;
+            total += 1;
+        }
+        block_num = cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prs::Pip2001ActionData;
+
+    fn new_trx(block_num: i64, topic: &str) -> Transaction {
+        let id = format!("{:064x}", block_num);
+        let data = Pip2001ActionData {
+            id: id.clone(),
+            data: json!({ "file_hash": id, "topic": topic }).to_string(),
+            hash: id.clone(),
+            meta: json!({ "uris": ["https://example.com/post.md"] }).to_string(),
+            _type: "PIP:2001".to_string(),
+            caller: "".to_string(),
+            signature: "".to_string(),
+            user_address: "74fb01e4d7ea240560978d98f66136c6211d3d61".to_string(),
+            unpacked_data: serde_json::Value::Null,
+            unpacked_meta: serde_json::Value::Null,
+        };
+        Transaction {
+            block_num,
+            data_type: "PIP:2001".to_string(),
+            data,
+            trx_id: id.clone(),
+            signature: "".to_string(),
+            hash: id,
+            user_address: "74fb01e4d7ea240560978d98f66136c6211d3d61".to_string(),
+        }
+    }
+
+    #[test]
+    fn replay_pages_by_topic() {
+        let mut source = ReplayChainSource::from(vec![
+            new_trx(30, "a"),
+            new_trx(10, "a"),
+            new_trx(20, "b"),
+            new_trx(40, "a"),
+        ]);
+
+        assert_eq!(source.get_start_block_num_by_topic("a").unwrap(), 10);
+        assert_eq!(source.get_start_block_num_by_topic("b").unwrap(), 20);
+        assert!(source.get_start_block_num_by_topic("c").is_err());
+
+        let page: Vec<i64> = source
+            .fetch_transactions_by_topic("a", 9, 2)
+            .unwrap()
+            .iter()
+            .map(|trx| trx.block_num)
+            .collect();
+        assert_eq!(page, vec![10, 30]);
+
+        let page: Vec<i64> = source
+            .fetch_transactions_by_topic("a", 30, 2)
+            .unwrap()
+            .iter()
+            .map(|trx| trx.block_num)
+            .collect();
+        assert_eq!(page, vec![40]);
+        assert!(source
+            .fetch_transactions_by_topic("a", 40, 2)
+            .unwrap()
+            .is_empty());
+
+        let info = source.get_info().unwrap();
+        assert_eq!(info.head_block_num, 40);
+        assert_eq!(info.last_irreversible_block_num, 40);
+    }
+
+    #[test]
+    fn dump_and_open() {
+        let path = std::env::temp_dir().join(format!("atom-replay-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut source = ReplayChainSource::from(vec![new_trx(10, "a"), new_trx(20, "a")]);
+        assert_eq!(dump(&mut source, "a", path).unwrap(), 2);
+
+        let mut replay = ReplayChainSource::open(path).unwrap();
+        let trxs = replay.fetch_transactions_by_topic("a", 0, 20).unwrap();
+        assert_eq!(trxs.len(), 2);
+        assert_eq!(trxs[1].trx_id, format!("{:064x}", 20));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dump_keeps_blocks_split_across_pages() {
+        let path =
+            std::env::temp_dir().join(format!("atom-replay-split-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // pages of DUMP_PAGE_SIZE end in the middle of a block
+        let transactions: Vec<Transaction> = (0..DUMP_PAGE_SIZE as i64 * 2 + 1)
+            .map(|idx| {
+                let mut trx = new_trx(10 + idx / 3, "a");
+                trx.trx_id = format!("{:064x}", idx);
+                trx
+            })
+            .collect();
+        let mut source = ReplayChainSource::from(transactions.clone());
+        assert_eq!(dump(&mut source, "a", path).unwrap(), transactions.len());
+
+        let mut replay = ReplayChainSource::open(path).unwrap();
+        let trxs = replay
+            .fetch_transactions_by_topic("a", 0, transactions.len() + 1)
+            .unwrap();
+        let trx_ids: Vec<&str> = trxs.iter().map(|trx| trx.trx_id.as_str()).collect();
+        let expected: Vec<&str> = transactions.iter().map(|trx| trx.trx_id.as_str()).collect();
+        assert_eq!(trx_ids, expected);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,5 +1,37 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+
+// the chain api returns at most this many transactions per request
+pub const MAX_SYNC_PAGE_SIZE: usize = 100;
+
+/// The running settings. `reload` swaps in a new version as a whole, so a
+/// reader holding the result of `get` sees one consistent version.
+pub struct SharedSettings {
+    current: RwLock<Arc<Settings>>,
+}
+
+impl SharedSettings {
+    pub fn load() -> Result<SharedSettings> {
+        Ok(SharedSettings {
+            current: RwLock::new(Arc::new(Settings::load()?)),
+        })
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read the settings again, invalid ones are rejected and the running
+    /// ones are kept.
+    pub fn reload(&self) -> Result<Arc<Settings>> {
+        let settings = Arc::new(Settings::load()?);
+        *self.current.write().unwrap() = settings.clone();
+        Ok(settings)
+    }
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
@@ -12,14 +44,61 @@ impl Settings {
         let mut settings = config::Config::default();
         settings
             // Add in `./Settings.toml`
-            .merge(config::File::with_name("Settings"))
-            .unwrap()
+            .merge(config::File::with_name("Settings"))?
             .merge(config::Environment::with_prefix("ATOM"))?;
         let s: Settings = settings.try_into()?;
+        s.validate()?;
 
         Ok(s)
     }
 
+    /// Reject values which would stall or break the sync loops.
+    pub fn validate(&self) -> Result<()> {
+        let atom = &self.atom;
+        check_positive("atom.sync_interval_secs", atom.sync_interval_secs)?;
+        check_positive("atom.process_interval_secs", atom.process_interval_secs)?;
+        check_positive("atom.sync_page_size", atom.sync_page_size)?;
+        check_positive("atom.fetch_batch_size", atom.fetch_batch_size)?;
+        check_positive("atom.db_pool_size", atom.db_pool_size)?;
+        check_positive("atom.chain_timeout_secs", atom.chain_timeout_secs)?;
+        check_positive("atom.fetch_workers", atom.fetch_workers)?;
+        check_positive("atom.webhook_workers", atom.webhook_workers)?;
+        check_positive("atom.max_requests_per_host", atom.max_requests_per_host)?;
+        check_positive("atom.request_timeout_secs", atom.request_timeout_secs)?;
+        if let Some(v) = atom.sync_page_size {
+            if v > MAX_SYNC_PAGE_SIZE {
+                return Err(anyhow!(
+                    "atom.sync_page_size must be at most {}, got {}",
+                    MAX_SYNC_PAGE_SIZE,
+                    v
+                ));
+            }
+        }
+
+        if let Some(v) = &atom.admin_token {
+            if v.trim().is_empty() {
+                return Err(anyhow!("atom.admin_token is empty"));
+            }
+        }
+
+        let mut topics = HashSet::new();
+        for item in &self.topics {
+            if !topics.insert(&item.topic) {
+                return Err(anyhow!("topic {} is configured twice", item.topic));
+            }
+            check_positive(
+                &format!("webhook_max_retries of topic {}", item.topic),
+                item.webhook_max_retries,
+            )?;
+            check_positive(
+                &format!("webhook_backoff_secs of topic {}", item.topic),
+                item.webhook_backoff_secs,
+            )?;
+        }
+
+        Ok(())
+    }
+
     pub fn contains_topic(&self, topic: &str) -> bool {
         if let Some(_) = self.get_topic(topic) {
             return true;
@@ -38,6 +117,13 @@ impl Settings {
         None
     }
 
+    pub fn get_webhooks_by_topic(&self, topic: &str) -> Vec<WebhookConf> {
+        match self.get_topic(topic) {
+            Some(item) => item.get_webhooks(),
+            None => Vec::new(),
+        }
+    }
+
     pub fn get_webhook_by_topic(&self, topic: &str) -> Option<String> {
         if let Some(item) = self.get_topic(topic) {
             match item.webhook {
@@ -57,6 +143,41 @@ pub struct AtomConf {
     pub bind_address: String,
     pub sentry_dsn: Option<String>,
     pub xml_output_dir: String,
+    // replay transactions from this JSON-lines dump instead of the chain api
+    pub chain_replay_file: Option<String>,
+    // http gateway for ipfs:// and ipns:// uris, default http://127.0.0.1:8080
+    pub ipfs_gateway: Option<String>,
+    // directory file:// uris are read from, file:// uris are rejected when it
+    // is not set
+    pub file_root: Option<String>,
+    // postgresql text search configuration of /search, default simple
+    pub search_config: Option<String>,
+    // threads fetching post contents, default 8
+    pub fetch_workers: Option<usize>,
+    // threads delivering webhooks, default 4
+    pub webhook_workers: Option<usize>,
+    // concurrent content and webhook requests to one host, default 2
+    pub max_requests_per_host: Option<usize>,
+    // timeout of one content or webhook request in seconds, default 30
+    pub request_timeout_secs: Option<u64>,
+    // pause between two rounds of reading new transactions of a topic, default 5
+    pub sync_interval_secs: Option<u64>,
+    // pause between two rounds of processing transactions, queueing contents
+    // to fetch and queueing webhooks, default 10
+    pub process_interval_secs: Option<u64>,
+    // transactions read from the chain api per request, default 20, at most 100
+    pub sync_page_size: Option<usize>,
+    // posts queued to fetch per round, default 1000
+    pub fetch_batch_size: Option<i64>,
+    // database connections of each pool, default 2
+    pub db_pool_size: Option<u32>,
+    // timeout of one chain api request in seconds, default 60
+    pub chain_timeout_secs: Option<u64>,
+    // bearer token of the /admin api, which is disabled when it is not set
+    pub admin_token: Option<String>,
+    // address of /healthz, /readyz and /metrics of syncserver, which are not
+    // served when it is not set; web serves them on bind_address
+    pub metrics_bind_address: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -65,4 +186,147 @@ pub struct TopicConf {
     pub webhook: Option<String>,
     pub encryption_key: String,
     pub iv_prefix: String,
+    // only save transactions at or below last_irreversible_block_num,
+    // newer ones are held in pending_transactions until they are finalized
+    pub irreversible_only: Option<bool>,
+    // stop reading new transactions of the topic until it is set back to false
+    pub paused: Option<bool>,
+    // quarantine posts whose content does not hash to file_hash, they are
+    // retried later; otherwise they are only marked failed. Such content is
+    // never saved either way
+    pub strict_verify: Option<bool>,
+    // sign webhook payloads with HMAC-SHA256, sent as `X-Atom-Signature`
+    pub webhook_secret: Option<String>,
+    // give up and move a notify to the dead-letter state after this many attempts
+    pub webhook_max_retries: Option<i32>,
+    // delay before the first retry, doubled for every following one
+    pub webhook_backoff_secs: Option<i64>,
+    // only deliver these event types, default all of them
+    pub webhook_events: Option<Vec<String>>,
+    // add the post frontmatter to post events
+    pub webhook_include_metadata: Option<bool>,
+    // more webhook subscriptions, each one is delivered and retried on its own
+    pub webhooks: Option<Vec<WebhookConf>>,
+    // feed level metadata of the atom output
+    pub feed: Option<FeedConf>,
+}
+
+impl TopicConf {
+    /// All webhook subscriptions of the topic. The top level `webhook` keys
+    /// are kept working as a subscription named `default`.
+    pub fn get_webhooks(&self) -> Vec<WebhookConf> {
+        let mut webhooks = Vec::new();
+        if let Some(url) = &self.webhook {
+            webhooks.push(WebhookConf {
+                name: String::from(DEFAULT_WEBHOOK_NAME),
+                url: url.clone(),
+                secret: self.webhook_secret.clone(),
+                headers: None,
+                events: self.webhook_events.clone(),
+                include_metadata: self.webhook_include_metadata,
+            });
+        }
+        if let Some(v) = &self.webhooks {
+            webhooks.extend(v.iter().cloned());
+        }
+
+        webhooks
+    }
+
+    pub fn get_webhook(&self, name: &str) -> Option<WebhookConf> {
+        self.get_webhooks().into_iter().find(|v| v.name == name)
+    }
+}
+
+pub const DEFAULT_WEBHOOK_NAME: &str = "default";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConf {
+    // unique in the topic, delivery state is kept per name
+    pub name: String,
+    pub url: String,
+    // sign payloads with HMAC-SHA256, sent as `X-Atom-Signature`
+    pub secret: Option<String>,
+    // extra request headers, e.g. `Authorization: Bearer xxx`
+    pub headers: Option<Vec<String>>,
+    // only deliver these event types, default all of them
+    pub events: Option<Vec<String>>,
+    // add the post frontmatter to post events
+    pub include_metadata: Option<bool>,
+}
+
+impl WebhookConf {
+    /// A subscription without `events` subscribes to every event.
+    pub fn is_subscribed(&self, event: &str) -> bool {
+        match &self.events {
+            Some(events) => events.iter().any(|v| v == event),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FeedConf {
+    // default `urn:prs:topic:<topic>`
+    pub id: Option<String>,
+    // default the topic
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    // the site the feed belongs to, written as rel="alternate"
+    pub link: Option<String>,
+    // where the feed itself is served, written as rel="self"
+    pub self_link: Option<String>,
+    pub icon: Option<String>,
+    // author uri is this prefix followed by the user address
+    pub author_uri_prefix: Option<String>,
+    // atom <content> as markdown (default), html or xhtml rendered from it
+    pub content_format: Option<String>,
+}
+
+fn check_positive<T: PartialOrd + Default + Display>(name: &str, value: Option<T>) -> Result<()> {
+    match value {
+        Some(v) if v <= T::default() => Err(anyhow!("{} must be greater than 0, got {}", name, v)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(atom_extra: &str) -> Settings {
+        toml::from_str(&format!(
+            r#"
+[atom]
+db_url = "postgresql://localhost/atom"
+prs_base_url = "https://prs-bp[1-3].press.one/api/chain"
+bind_address = "0.0.0.0:8080"
+xml_output_dir = "output"
+{}
+
+[[topics]]
+topic = "a7b751cc0e2f6c5be01ce95bc80b02d071022af4"
+encryption_key = ""
+iv_prefix = ""
+"#,
+            atom_extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_settings() {
+        assert!(parse("").validate().is_ok());
+        assert!(parse("sync_page_size = 100\ndb_pool_size = 4")
+            .validate()
+            .is_ok());
+        assert!(parse("sync_interval_secs = 0").validate().is_err());
+        assert!(parse("sync_page_size = 101").validate().is_err());
+        assert!(parse("fetch_batch_size = -1").validate().is_err());
+        assert!(parse("admin_token = \"\"").validate().is_err());
+
+        let mut settings = parse("");
+        settings.topics.push(settings.topics[0].clone());
+        assert!(settings.validate().is_err());
+    }
 }
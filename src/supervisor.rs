@@ -0,0 +1,246 @@
+use anyhow::Result;
+use chrono::prelude::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::SETTINGS;
+use crate::db;
+use crate::db::models::NewTopicWorker;
+use crate::metrics;
+use crate::prs;
+use crate::prs::ChainSource;
+use crate::sync;
+use crate::util;
+
+// watched for changes, the same file settings::Settings::load reads
+const SETTINGS_FILE: &str = "Settings.toml";
+// pause between two rounds of reading new transactions of a topic when
+// atom.sync_interval_secs is not set
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorkerState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+impl WorkerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkerState::Running => "running",
+            WorkerState::Paused => "paused",
+            WorkerState::Stopped => "stopped",
+        }
+    }
+}
+
+pub fn get_sync_interval() -> Duration {
+    Duration::from_secs(
+        SETTINGS
+            .get()
+            .atom
+            .sync_interval_secs
+            .unwrap_or(DEFAULT_SYNC_INTERVAL_SECS),
+    )
+}
+
+struct TopicWorker {
+    stop: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+}
+
+/// Runs a sync worker for every configured topic: workers of new topics are
+/// started and workers of removed topics are stopped, so topics are added and
+/// removed by editing `Settings.toml` without a restart.
+#[derive(Default)]
+pub struct Supervisor {
+    workers: HashMap<String, TopicWorker>,
+    settings_modified: Option<SystemTime>,
+}
+
+impl Supervisor {
+    /// Start and stop workers to match the topics of the settings, reloaded
+    /// first when `Settings.toml` changed since the last round.
+    pub fn reconcile(&mut self) {
+        self.reload_if_modified();
+        let settings = SETTINGS.get();
+
+        // a worker which has exited is started again if its topic is back
+        self.workers
+            .retain(|_, worker| !worker.done.load(Ordering::SeqCst));
+
+        for item in &settings.topics {
+            match self.workers.get(&item.topic) {
+                Some(worker) => {
+                    // the topic was added back before its worker stopped
+                    worker.stop.store(false, Ordering::SeqCst);
+                }
+                None => {
+                    info!("start sync worker of topic: {}", item.topic);
+                    self.workers
+                        .insert(item.topic.clone(), start_worker(&item.topic));
+                }
+            }
+        }
+
+        for (topic, worker) in &self.workers {
+            let configured = settings.topics.iter().any(|v| &v.topic == topic);
+            if !configured && !worker.stop.swap(true, Ordering::SeqCst) {
+                info!("stop sync worker of topic: {}", topic);
+            }
+        }
+    }
+
+    fn reload_if_modified(&mut self) {
+        let modified = fs::metadata(SETTINGS_FILE).and_then(|v| v.modified()).ok();
+        if modified.is_none() || modified == self.settings_modified {
+            return;
+        }
+        let first_round = self.settings_modified.is_none();
+        self.settings_modified = modified;
+        if first_round {
+            return;
+        }
+
+        match SETTINGS.reload() {
+            Ok(_) => info!("{} changed, settings reloaded", SETTINGS_FILE),
+            Err(e) => error!(
+                "{} changed, reload settings failed, keep the running ones: {}",
+                SETTINGS_FILE, e
+            ),
+        }
+    }
+}
+
+/// Marks the worker as exited, also when it panics.
+struct DoneGuard(Arc<AtomicBool>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+fn start_worker(topic: &str) -> TopicWorker {
+    let stop = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let worker = TopicWorker {
+        stop: stop.clone(),
+        done: done.clone(),
+    };
+
+    let topic = topic.to_string();
+    let spawned = thread::Builder::new()
+        .name(format!("sync-{}", topic))
+        .spawn(move || {
+            let _done = DoneGuard(done);
+            run_worker(&topic, &stop);
+        });
+    if let Err(e) = spawned {
+        error!("spawn sync worker thread failed: {}", e);
+        worker.done.store(true, Ordering::SeqCst);
+    }
+    worker
+}
+
+/// What the worker reports to `topic_workers`.
+#[derive(Default)]
+struct Status {
+    block_num: Option<i64>,
+    last_synced_at: Option<NaiveDateTime>,
+    last_error: Option<String>,
+}
+
+fn run_worker(topic: &str, stop: &AtomicBool) {
+    let last_block_num_key = util::get_last_block_num_by_topic(topic);
+    let mut source = match prs::new_chain_source() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("create chain source for topic: {} failed: {}", topic, e);
+            return;
+        }
+    };
+    let pool_name = format!("sync_{}", topic);
+    let mut db_conn_pool = db::ConfiguredPool::from_settings(&pool_name);
+    let mut status = Status::default();
+
+    while !stop.load(Ordering::SeqCst) {
+        let paused = SETTINGS
+            .get()
+            .get_topic(topic)
+            .and_then(|v| v.paused)
+            .unwrap_or(false);
+        if let Ok(db_conn) = db_conn_pool.pool().get() {
+            let state = if paused {
+                WorkerState::Paused
+            } else {
+                match sync_topic(&db_conn, source.as_mut(), topic, &last_block_num_key) {
+                    Ok(block_num) => {
+                        status.block_num = Some(block_num);
+                        status.last_synced_at = Some(Utc::now().naive_utc());
+                        status.last_error = None;
+                        info!("sync transactions for topic: {} done. sleep...", topic);
+                    }
+                    Err(e) => {
+                        error!("sync_transactions for topic: {} failed: {}", topic, e);
+                        status.last_error = Some(e.to_string());
+                    }
+                }
+                WorkerState::Running
+            };
+            save_status(&db_conn, topic, state, &status);
+        } else {
+            error!("get database connection failed");
+        }
+        thread::sleep(get_sync_interval());
+    }
+
+    info!("sync worker of topic: {} stopped", topic);
+    if let Ok(db_conn) = db_conn_pool.pool().get() {
+        save_status(&db_conn, topic, WorkerState::Stopped, &status);
+    }
+    metrics::unregister_pool(&pool_name);
+}
+
+/// Read the new transactions of `topic`, returns the block it is synced to.
+fn sync_topic(
+    conn: &PgConnection,
+    source: &mut dyn ChainSource,
+    topic: &str,
+    last_block_num_key: &str,
+) -> Result<i64> {
+    let start_block_num = match db::get_last_status(conn, last_block_num_key) {
+        Ok(v) => v.val,
+        Err(_) => source.get_start_block_num_by_topic(topic)? as i64 - 1,
+    };
+    let irreversible_only = SETTINGS
+        .get()
+        .get_topic(topic)
+        .and_then(|v| v.irreversible_only)
+        .unwrap_or(false);
+    sync::sync_transactions(conn, source, topic, start_block_num, irreversible_only)?;
+
+    Ok(db::get_last_status(conn, last_block_num_key)
+        .map(|v| v.val)
+        .unwrap_or(start_block_num))
+}
+
+fn save_status(conn: &PgConnection, topic: &str, state: WorkerState, status: &Status) {
+    let worker = NewTopicWorker {
+        topic,
+        state: state.as_str(),
+        block_num: status.block_num,
+        last_synced_at: status.last_synced_at,
+        last_error: status.last_error.as_deref(),
+        updated_at: Utc::now().naive_utc(),
+    };
+    if let Err(e) = db::save_topic_worker(conn, &worker) {
+        error!("save state of topic: {} worker failed: {}", topic, e);
+    }
+}
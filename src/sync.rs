@@ -0,0 +1,543 @@
+use anyhow::{anyhow, Result};
+use diesel::pg::PgConnection;
+use diesel::Connection;
+
+use super::SETTINGS;
+use crate::db;
+use crate::metrics;
+use crate::prs;
+use crate::prs::ChainSource;
+use crate::settings::MAX_SYNC_PAGE_SIZE;
+use crate::util;
+use crate::webhook;
+
+// transactions per chain api request when atom.sync_page_size is not set
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+pub fn sync_transactions(
+    conn: &PgConnection,
+    source: &mut dyn ChainSource,
+    topic: &str,
+    start_block_num: i64,
+    irreversible_only: bool,
+) -> Result<()> {
+    let mut start_block_num = start_block_num;
+    let page_size = SETTINGS
+        .get()
+        .atom
+        .sync_page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE);
+    let last_irreversible_block_num = if irreversible_only {
+        Some(source.get_info()?.last_irreversible_block_num)
+    } else {
+        None
+    };
+
+    loop {
+        let (transactions, cursor) = fetch_page(source, topic, start_block_num, page_size)?;
+        if transactions.is_empty() {
+            break;
+        }
+
+        save_page(
+            conn,
+            topic,
+            &transactions,
+            start_block_num,
+            cursor,
+            last_irreversible_block_num,
+        )?;
+        start_block_num = cursor;
+    }
+
+    if let Some(lib) = last_irreversible_block_num {
+        promote_pending_transactions(conn, source, topic, page_size, lib)?;
+    }
+
+    Ok(())
+}
+
+/// Read the transactions after `start_block_num` and the block_num to resume
+/// from after them. A full page inside a single block is read again with a
+/// larger page, up to the most the chain api returns, since resuming after
+/// that block would skip the rest of it.
+pub fn fetch_page(
+    source: &mut dyn ChainSource,
+    topic: &str,
+    start_block_num: i64,
+    page_size: usize,
+) -> Result<(Vec<prs::Transaction>, i64)> {
+    let mut page_size = page_size;
+    loop {
+        let transactions = source.fetch_transactions_by_topic(topic, start_block_num, page_size)?;
+        if transactions.is_empty() {
+            return Ok((transactions, start_block_num));
+        }
+        match page_cursor(&transactions, page_size) {
+            Some(cursor) => return Ok((transactions, cursor)),
+            None if page_size < MAX_SYNC_PAGE_SIZE => {
+                page_size = std::cmp::min(page_size * 2, MAX_SYNC_PAGE_SIZE);
+            }
+            None => {
+                return Err(anyhow!(
+                    "block_num = {} has more than {} transactions of topic = {}, can not page through it",
+                    transactions[0].block_num,
+                    page_size,
+                    topic
+                ))
+            }
+        }
+    }
+}
+
+/// The block_num to resume from after this page. `blocknum` is exclusive, so
+/// a full page that ends in the middle of a block stops before that block and
+/// leaves its transactions for the next page. `None` when a full page lies
+/// inside one block, there is no cursor that neither repeats nor skips it.
+fn page_cursor(transactions: &[prs::Transaction], page_size: usize) -> Option<i64> {
+    let first_block_num = transactions[0].block_num;
+    let last_block_num = transactions[transactions.len() - 1].block_num;
+    if transactions.len() < page_size {
+        Some(last_block_num)
+    } else if first_block_num != last_block_num {
+        Some(last_block_num - 1)
+    } else {
+        None
+    }
+}
+
+/// Write the trx rows, notify rows and `{topic}_block_num` cursor of one page
+/// in a single database transaction, so a crash never leaves them out of step.
+/// The page is dropped when the cursor is no longer `start_block_num`, the
+/// block it was read from, so a rewind done meanwhile is not undone.
+fn save_page(
+    conn: &PgConnection,
+    topic: &str,
+    transactions: &[prs::Transaction],
+    start_block_num: i64,
+    cursor: i64,
+    last_irreversible_block_num: Option<i64>,
+) -> Result<()> {
+    let saved = conn.transaction::<_, anyhow::Error, _>(|| {
+        let mut saved = 0;
+        for trx in transactions.iter().filter(|trx| trx.block_num <= cursor) {
+            debug!(
+                "got block_num = {} topic = {}, new transaction: {:?}",
+                trx.block_num, &topic, &trx
+            );
+
+            match last_irreversible_block_num {
+                Some(lib) if trx.block_num > lib => {
+                    db::save_pending_trx(conn, trx)?;
+                    fail_point("save_pending_trx")?;
+                }
+                _ => {
+                    save_trx_and_notify(conn, trx)?;
+                    saved += 1;
+                }
+            }
+        }
+
+        let key = util::get_last_block_num_by_topic(topic);
+        if !db::compare_and_set_last_status(conn, &key, start_block_num, cursor)? {
+            return Err(anyhow!(
+                "cursor of topic = {} moved away from block_num = {} while syncing, drop the page",
+                topic,
+                start_block_num
+            ));
+        }
+        fail_point("update_last_status")?;
+
+        Ok(saved)
+    })?;
+    metrics::transactions_ingested(topic, saved);
+
+    Ok(())
+}
+
+fn save_trx_and_notify(conn: &PgConnection, trx: &prs::Transaction) -> Result<()> {
+    db::save_trx(&conn, &trx)?;
+    fail_point("save_trx")?;
+
+    let payload = match trx.get_notify_payload() {
+        Ok(v) => match v {
+            Some(vv) => vv,
+            None => return Ok(()),
+        },
+        Err(e) => {
+            error!("get_notify_payload failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    let data_id = payload.block.data_id;
+    webhook::save_event(
+        &conn,
+        &data_id,
+        payload.block.block_num,
+        &payload.block.trx_id,
+        &trx.get_topic(),
+        &payload.event,
+        &payload.data.to_string(),
+    )?;
+    fail_point("save_notify")?;
+
+    Ok(())
+}
+
+/// Move pending transactions that are now irreversible into `transactions`.
+/// A pending trx that can no longer be found on chain was dropped by a fork,
+/// so it is removed and the cursor is rewound to pick up whatever replaced it.
+fn promote_pending_transactions(
+    conn: &PgConnection,
+    source: &mut dyn ChainSource,
+    topic: &str,
+    page_size: usize,
+    last_irreversible_block_num: i64,
+) -> Result<()> {
+    let pending_trxs = db::get_pending_trxs(conn, topic, last_irreversible_block_num)?;
+    for pending_trx in pending_trxs {
+        let found = find_trx_on_chain(
+            source,
+            topic,
+            &pending_trx.trx_id,
+            pending_trx.block_num,
+            page_size,
+            last_irreversible_block_num,
+        )?;
+
+        let promoted = conn.transaction::<_, anyhow::Error, _>(|| {
+            match found {
+                Some(ref trx) if trx.block_num <= last_irreversible_block_num => {
+                    debug!(
+                        "promote pending trx_id = {} block_num = {}",
+                        trx.trx_id, trx.block_num
+                    );
+                    save_trx_and_notify(conn, trx)?;
+                    db::delete_pending_trx(conn, &pending_trx.trx_id)?;
+                    return Ok(true);
+                }
+                Some(ref trx) => {
+                    // moved to a block which is not final yet, wait for it
+                    debug!(
+                        "pending trx_id = {} moved from block_num = {} to {}",
+                        trx.trx_id, pending_trx.block_num, trx.block_num
+                    );
+                    db::save_pending_trx(conn, trx)?;
+                }
+                None => {
+                    warn!(
+                        "pending trx_id = {} block_num = {} disappeared from chain, roll back",
+                        pending_trx.trx_id, pending_trx.block_num
+                    );
+                    db::delete_pending_trx(conn, &pending_trx.trx_id)?;
+                    let key = util::get_last_block_num_by_topic(topic);
+                    if let Ok(last_status) = db::get_last_status(conn, &key) {
+                        if last_status.val >= pending_trx.block_num {
+                            db::compare_and_set_last_status(
+                                conn,
+                                &key,
+                                last_status.val,
+                                pending_trx.block_num - 1,
+                            )?;
+                        }
+                    }
+                }
+            }
+            Ok(false)
+        })?;
+        if promoted {
+            metrics::transactions_ingested(topic, 1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Look for `trx_id` from `block_num` on. Pages are read until the trx is
+/// found or a page reaches past `last_irreversible_block_num`, a trx which is
+/// not in any block up to it is not final yet or was dropped.
+fn find_trx_on_chain(
+    source: &mut dyn ChainSource,
+    topic: &str,
+    trx_id: &str,
+    block_num: i64,
+    page_size: usize,
+    last_irreversible_block_num: i64,
+) -> Result<Option<prs::Transaction>> {
+    // `blocknum` is exclusive, so start one block before the pending trx
+    let mut start_block_num = block_num - 1;
+    loop {
+        let (transactions, cursor) = fetch_page(source, topic, start_block_num, page_size)?;
+        if transactions.is_empty() {
+            return Ok(None);
+        }
+        let last_block_num = transactions[transactions.len() - 1].block_num;
+        if let Some(trx) = transactions.into_iter().find(|trx| trx.trx_id == trx_id) {
+            return Ok(Some(trx));
+        }
+        if last_block_num > last_irreversible_block_num {
+            return Ok(None);
+        }
+        start_block_num = cursor;
+    }
+}
+
+#[cfg(not(test))]
+fn fail_point(_name: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    static FAIL_AT: std::cell::RefCell<Option<&'static str>> = std::cell::RefCell::new(None);
+}
+
+#[cfg(test)]
+fn fail_point(name: &str) -> Result<()> {
+    if FAIL_AT.with(|f| *f.borrow() == Some(name)) {
+        return Err(anyhow::anyhow!("injected failure at {}", name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::ReplayChainSource;
+    use crate::SETTINGS;
+
+    // these tests need a migrated database, run them with:
+    // DATABASE_URL=postgresql://... cargo test -- --ignored
+    const TOPIC: &str = "b7b751cc0e2f6c5be01ce95bc80b02d071022af4";
+    const USER_ADDRESS: &str = "74fb01e4d7ea240560978d98f66136c6211d3d61";
+
+    fn get_conn() -> db::PgPooledConnection {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        db::init_pool(&database_url)
+            .expect("create database pool failed")
+            .get()
+            .expect("get database connection failed")
+    }
+
+    fn new_trx(block_num: i64, id: &str) -> prs::Transaction {
+        let data = prs::Pip2001ActionData {
+            id: id.to_string(),
+            data: json!({ "file_hash": id, "topic": TOPIC }).to_string(),
+            hash: id.to_string(),
+            meta: json!({ "uris": [format!("https://example.com/{}.md", id)] }).to_string(),
+            _type: "PIP:2001".to_string(),
+            caller: "".to_string(),
+            signature: "".to_string(),
+            user_address: USER_ADDRESS.to_string(),
+            unpacked_data: serde_json::Value::Null,
+            unpacked_meta: serde_json::Value::Null,
+        };
+        prs::Transaction {
+            block_num,
+            data_type: "PIP:2001".to_string(),
+            data,
+            trx_id: id.to_string(),
+            signature: "".to_string(),
+            hash: id.to_string(),
+            user_address: USER_ADDRESS.to_string(),
+        }
+    }
+
+    fn inject(name: Option<&'static str>) {
+        FAIL_AT.with(|f| *f.borrow_mut() = name);
+    }
+
+    #[test]
+    fn page_cursor_stops_before_partial_block() {
+        let page = vec![new_trx(10, "a"), new_trx(11, "b"), new_trx(11, "c")];
+        assert_eq!(page_cursor(&page, 3), Some(10));
+        assert_eq!(page_cursor(&page, 20), Some(11));
+
+        let one_block = vec![new_trx(12, "d"), new_trx(12, "e")];
+        assert_eq!(page_cursor(&one_block, 2), None);
+        assert_eq!(page_cursor(&one_block, 3), Some(12));
+    }
+
+    #[test]
+    fn fetch_page_grows_inside_a_block() {
+        let transactions: Vec<prs::Transaction> = (0..5)
+            .map(|idx| new_trx(12, &format!("{:064x}", idx)))
+            .chain(vec![new_trx(13, &format!("{:064x}", 5))])
+            .collect();
+        let mut source = ReplayChainSource::from(transactions);
+
+        let (page, cursor) = fetch_page(&mut source, TOPIC, 11, 2).unwrap();
+        assert_eq!(page.len(), 6);
+        assert_eq!(cursor, 13);
+
+        let crowded: Vec<prs::Transaction> = (0..MAX_SYNC_PAGE_SIZE + 1)
+            .map(|idx| new_trx(12, &format!("{:064x}", idx)))
+            .collect();
+        let mut source = ReplayChainSource::from(crowded);
+        assert!(fetch_page(&mut source, TOPIC, 11, 2).is_err());
+    }
+
+    #[test]
+    fn find_trx_pages_up_to_irreversible() {
+        let transactions: Vec<prs::Transaction> = (0..10)
+            .map(|idx| new_trx(100 + idx, &format!("{:064x}", idx)))
+            .collect();
+        let mut source = ReplayChainSource::from(transactions);
+        let trx_id = format!("{:064x}", 8);
+
+        let found = find_trx_on_chain(&mut source, TOPIC, &trx_id, 101, 2, 109).unwrap();
+        assert_eq!(found.unwrap().block_num, 108);
+        // the page which reaches past block 103 ends the search
+        assert!(find_trx_on_chain(&mut source, TOPIC, &trx_id, 101, 2, 103)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[ignore]
+    fn save_page_is_atomic() {
+        let conn = get_conn();
+        let key = util::get_last_block_num_by_topic(TOPIC);
+
+        for (idx, step) in ["save_trx", "save_notify", "update_last_status"]
+            .iter()
+            .enumerate()
+        {
+            let cursor_before = db::get_last_status(&conn, &key).map(|v| v.val).ok();
+            let block_num = 1_000_000 + idx as i64;
+            let start_block_num = cursor_before.unwrap_or(block_num - 1);
+            let trx_id = format!("{:064x}", block_num);
+            let page = vec![new_trx(block_num, &trx_id)];
+
+            inject(Some(step));
+            assert!(save_page(&conn, TOPIC, &page, start_block_num, block_num, None).is_err());
+            inject(None);
+
+            assert!(
+                db::get_trx_by_trx_id(&conn, &trx_id).is_err(),
+                "step = {}",
+                step
+            );
+            assert!(
+                db::get_notifies_by_data_id(&conn, &trx_id)
+                    .unwrap()
+                    .is_empty(),
+                "step = {}",
+                step
+            );
+            assert_eq!(
+                db::get_last_status(&conn, &key).map(|v| v.val).ok(),
+                cursor_before,
+                "step = {}",
+                step
+            );
+
+            // resume after the failure, twice, without duplicating rows
+            save_page(&conn, TOPIC, &page, start_block_num, block_num, None)
+                .expect("save_page failed");
+            save_page(&conn, TOPIC, &page, block_num, block_num, None).expect("save_page failed");
+            assert_eq!(
+                db::get_trx_by_trx_id(&conn, &trx_id)
+                    .expect("trx not saved")
+                    .block_num,
+                block_num
+            );
+            assert_eq!(
+                db::get_notifies_by_data_id(&conn, &trx_id).unwrap().len(),
+                SETTINGS.get().get_webhooks_by_topic(TOPIC).len()
+            );
+            assert_eq!(
+                db::get_last_status(&conn, &key)
+                    .expect("cursor not saved")
+                    .val,
+                block_num
+            );
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn sync_from_replay() {
+        let conn = get_conn();
+        let topic = "c7b751cc0e2f6c5be01ce95bc80b02d071022af4";
+        let key = util::get_last_block_num_by_topic(topic);
+        // 3 transactions per block, so pages end in the middle of a block
+        let transactions: Vec<prs::Transaction> = (0..45)
+            .map(|idx| {
+                let mut trx = new_trx(3_000_000 + idx / 3, &format!("{:064x}", 3_000_000 + idx));
+                trx.data.data = json!({ "file_hash": trx.trx_id, "topic": topic }).to_string();
+                trx
+            })
+            .collect();
+        let mut source = ReplayChainSource::from(transactions.clone());
+
+        let start_block_num = source.get_start_block_num_by_topic(topic).unwrap() as i64 - 1;
+        sync_transactions(&conn, &mut source, topic, start_block_num, false)
+            .expect("sync_transactions failed");
+
+        for trx in &transactions {
+            assert_eq!(
+                db::get_trx_by_trx_id(&conn, &trx.trx_id)
+                    .expect("trx not saved")
+                    .block_num,
+                trx.block_num
+            );
+        }
+        assert_eq!(db::get_last_status(&conn, &key).unwrap().val, 3_000_014);
+    }
+
+    #[test]
+    #[ignore]
+    fn save_page_pending_is_atomic() {
+        let conn = get_conn();
+        let key = util::get_last_block_num_by_topic(TOPIC);
+        let cursor_before = db::get_last_status(&conn, &key).map(|v| v.val).ok();
+        let block_num = 2_000_000;
+        let start_block_num = cursor_before.unwrap_or(block_num - 1);
+        let trx_id = format!("{:064x}", block_num);
+        let page = vec![new_trx(block_num, &trx_id)];
+        let lib = Some(block_num - 1);
+
+        inject(Some("save_pending_trx"));
+        assert!(save_page(&conn, TOPIC, &page, start_block_num, block_num, lib).is_err());
+        inject(None);
+        assert!(db::get_pending_trxs(&conn, TOPIC, block_num)
+            .expect("get_pending_trxs failed")
+            .iter()
+            .all(|v| v.trx_id != trx_id));
+        assert_eq!(
+            db::get_last_status(&conn, &key).map(|v| v.val).ok(),
+            cursor_before
+        );
+
+        save_page(&conn, TOPIC, &page, start_block_num, block_num, lib).expect("save_page failed");
+        assert!(db::get_trx_by_trx_id(&conn, &trx_id).is_err());
+        assert!(db::get_pending_trxs(&conn, TOPIC, block_num)
+            .expect("get_pending_trxs failed")
+            .iter()
+            .any(|v| v.trx_id == trx_id));
+        db::delete_pending_trx(&conn, &trx_id).expect("delete_pending_trx failed");
+    }
+
+    #[test]
+    #[ignore]
+    fn save_page_keeps_a_rewound_cursor() {
+        let conn = get_conn();
+        let topic = "d7b751cc0e2f6c5be01ce95bc80b02d071022af4";
+        let key = util::get_last_block_num_by_topic(topic);
+        let block_num = 4_000_000;
+        let trx_id = format!("{:064x}", block_num);
+        let page = vec![new_trx(block_num, &trx_id)];
+
+        // the worker read block_num - 1, then the cursor is rewound
+        db::update_last_status(&conn, &key, block_num - 1).expect("update_last_status failed");
+        db::update_last_status(&conn, &key, block_num - 10).expect("update_last_status failed");
+        assert!(save_page(&conn, topic, &page, block_num - 1, block_num, None).is_err());
+        assert!(db::get_trx_by_trx_id(&conn, &trx_id).is_err());
+        assert_eq!(
+            db::get_last_status(&conn, &key).unwrap().val,
+            block_num - 10
+        );
+    }
+}
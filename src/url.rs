@@ -8,7 +8,7 @@ pub struct URL {
 
 impl URL {
     pub fn new() -> URL {
-        URL::from(&SETTINGS.atom.prs_base_url)
+        URL::from(&SETTINGS.get().atom.prs_base_url)
     }
 
     pub fn from(url: &str) -> URL {
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::Utc;
+use diesel::pg::PgConnection;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json::Value;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::SETTINGS;
+use crate::db;
+use crate::db::models::{Notify, Post};
+use crate::metrics;
+use crate::pipeline;
+use crate::processor;
+use crate::prs;
+
+const DEFAULT_MAX_RETRIES: i32 = 8;
+const DEFAULT_BACKOFF_SECS: i64 = 30;
+// never wait longer than a day between two attempts
+const MAX_BACKOFF_SECS: i64 = 24 * 3600;
+
+pub const SIGNATURE_HEADER: &str = "X-Atom-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    PostPublished,
+    PostUpdated,
+    PostDeleted,
+    UserAllowed,
+    UserDenied,
+    ContentVerificationFailed,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::PostPublished => "post_published",
+            EventType::PostUpdated => "post_updated",
+            EventType::PostDeleted => "post_deleted",
+            EventType::UserAllowed => "user_allowed",
+            EventType::UserDenied => "user_denied",
+            EventType::ContentVerificationFailed => "content_verification_failed",
+        }
+    }
+}
+
+impl FromStr for EventType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<EventType> {
+        match s {
+            "post_published" => Ok(EventType::PostPublished),
+            "post_updated" => Ok(EventType::PostUpdated),
+            "post_deleted" => Ok(EventType::PostDeleted),
+            "user_allowed" => Ok(EventType::UserAllowed),
+            "user_denied" => Ok(EventType::UserDenied),
+            "content_verification_failed" => Ok(EventType::ContentVerificationFailed),
+            _ => Err(anyhow!("unknown event type: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: i32,
+    pub backoff_secs: i64,
+}
+
+impl RetryPolicy {
+    pub fn for_topic(topic: &str) -> RetryPolicy {
+        let topic_conf = SETTINGS.get().get_topic(topic);
+        RetryPolicy {
+            max_retries: topic_conf
+                .as_ref()
+                .and_then(|v| v.webhook_max_retries)
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+            backoff_secs: topic_conf
+                .as_ref()
+                .and_then(|v| v.webhook_backoff_secs)
+                .unwrap_or(DEFAULT_BACKOFF_SECS),
+        }
+    }
+
+    /// Delay before the next attempt after `attempts` failed ones, or `None`
+    /// when the notify should go to the dead-letter state.
+    pub fn next_delay(&self, attempts: i32) -> Option<chrono::Duration> {
+        if attempts >= self.max_retries {
+            return None;
+        }
+        let exp = std::cmp::min(attempts.max(1) - 1, 30) as u32;
+        let secs = self.backoff_secs.saturating_mul(2i64.pow(exp));
+        Some(chrono::Duration::seconds(std::cmp::min(
+            secs,
+            MAX_BACKOFF_SECS,
+        )))
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `payload`.
+pub fn sign(secret: &str, payload: &str) -> Result<String> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(payload.as_bytes())?;
+    Ok(hex::encode(signer.sign_to_vec()?))
+}
+
+pub fn check_and_send_webhook(conn: &PgConnection, notify_id: i32) -> Result<()> {
+    let notify = match db::get_notify(conn, notify_id) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("get notify by id = {} failed: {:?}", notify_id, e);
+            return Ok(());
+        }
+    };
+
+    if notify.success || notify.dead {
+        debug!(
+            "block_num = {} trx_id = {} notify webhook success or dead, skip ...",
+            notify.block_num, notify.trx_id
+        );
+        return Ok(());
+    }
+    if let Some(next_attempt_at) = notify.next_attempt_at {
+        if next_attempt_at > Utc::now().naive_utc() {
+            return Ok(());
+        }
+    }
+
+    let webhook = match SETTINGS
+        .get()
+        .get_topic(&notify.topic)
+        .and_then(|v| v.get_webhook(&notify.subscription))
+    {
+        Some(v) => v,
+        None => {
+            // the subscription was removed from the config, keep the notify
+            // as a dead-letter so it can be replayed if it comes back
+            warn!(
+                "can not find webhook = {} of topic = {} from toml config, move notify id = {} to dead-letter",
+                notify.subscription, notify.topic, notify.id
+            );
+            db::update_notify_retry(conn, notify.id, None)?;
+            return Ok(());
+        }
+    };
+    let notify_url = webhook.url.clone();
+
+    let metadata = if webhook.include_metadata.unwrap_or(false) {
+        get_post_metadata(conn, &notify)
+    } else {
+        None
+    };
+    let payload = prs::NotifyPayload {
+        event: notify.event.clone(),
+        block: prs::NotifyBlock {
+            data_id: notify.data_id.clone(),
+            block_num: notify.block_num,
+            trx_id: notify.trx_id.clone(),
+        },
+        data: serde_json::from_str(&notify.extra).unwrap_or_else(|_| json!({})),
+        metadata,
+    };
+    let payload = serde_json::to_string(&payload)?;
+    let mut headers = webhook.headers.clone().unwrap_or_default();
+    if let Some(secret) = &webhook.secret {
+        headers.push(format!(
+            "{}: sha256={}",
+            SIGNATURE_HEADER,
+            sign(secret, &payload)?
+        ));
+    }
+
+    debug!(
+        "notify id = {} data_id = {} event = {} topic = {} webhook = {} retries = {}",
+        notify.id, notify.data_id, notify.event, notify.topic, notify.subscription, notify.retries
+    );
+    debug!("send notify payload to {}", notify_url);
+    let mut elapsed = Duration::default();
+    let result = pipeline::with_host_limit(&notify_url, || {
+        // timed once the host slot is taken, so it is the request alone
+        let start = Instant::now();
+        let result = prs::notify_webhook(&notify_url, &payload, &headers);
+        elapsed = start.elapsed();
+        result
+    });
+    // not sent, so it is not an attempt
+    if result.as_ref().err().map_or(false, pipeline::is_host_busy) {
+        return result.map(|_| ());
+    }
+    let duration_ms = elapsed.as_millis() as i64;
+    metrics::webhook_delivered(
+        &notify.topic,
+        &notify.subscription,
+        elapsed,
+        result.as_ref().ok().map(|v| v.0),
+    );
+
+    let attempt = notify.retries + 1;
+    let (status_code, response_body, err) = match result {
+        Ok((status_code, body)) => (Some(status_code as i32), body, None),
+        Err(e) => {
+            error!(
+                "block_num = {}, url = {}, notify_webhook failed: {}",
+                notify.block_num, notify_url, e
+            );
+            (None, String::new(), Some(e.to_string()))
+        }
+    };
+    db::save_webhook_log(
+        conn,
+        &notify,
+        &notify_url,
+        attempt,
+        status_code,
+        &response_body,
+        err.as_deref(),
+        duration_ms,
+    )?;
+
+    // a curl failure and a non-2xx response are both a failed attempt
+    let success = match status_code {
+        Some(v) => (200..300).contains(&v),
+        None => false,
+    };
+    if success {
+        db::update_notify_status(conn, notify.id, true)?;
+        return Ok(());
+    }
+
+    let policy = RetryPolicy::for_topic(&notify.topic);
+    match policy.next_delay(attempt) {
+        Some(delay) => {
+            db::update_notify_retry(conn, notify.id, Some(Utc::now().naive_utc() + delay))?;
+        }
+        None => {
+            warn!(
+                "notify id = {} failed {} times, move to dead-letter",
+                notify.id, attempt
+            );
+            db::update_notify_retry(conn, notify.id, None)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The frontmatter of the post an event is about, if its content is saved.
+fn get_post_metadata(conn: &PgConnection, notify: &Notify) -> Option<Value> {
+    match notify.event.parse() {
+        Ok(EventType::UserAllowed) | Ok(EventType::UserDenied) | Err(_) => return None,
+        _ => {}
+    }
+
+    let post = db::get_post_by_publish_tx_id(conn, &notify.data_id).ok()?;
+    let content = db::get_content(conn, &post.file_hash).ok()?;
+    match processor::get_markdown_attrs(conn, &content) {
+        Ok(attrs) => Some(attrs.to_json()),
+        Err(e) => {
+            warn!(
+                "parse frontmatter of file_hash = {} failed: {}",
+                post.file_hash, e
+            );
+            None
+        }
+    }
+}
+
+/// Save an event once for every webhook subscription of `topic` that wants
+/// it, so each subscription is delivered and retried on its own.
+pub fn save_event(
+    conn: &PgConnection,
+    data_id: &str,
+    block_num: i64,
+    trx_id: &str,
+    topic: &str,
+    event: &str,
+    extra: &str,
+) -> Result<(), diesel::result::Error> {
+    for webhook in SETTINGS.get().get_webhooks_by_topic(topic) {
+        if !webhook.is_subscribed(event) {
+            continue;
+        }
+        db::save_notify(
+            conn,
+            data_id,
+            block_num,
+            trx_id,
+            topic,
+            event,
+            extra,
+            &webhook.name,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Save an event about `post`, reusing the block of its publish transaction.
+pub fn save_post_event(conn: &PgConnection, post: &Post, event: EventType, data: Value) {
+    let (block_num, trx_id) = match db::get_notifies_by_data_id(conn, &post.publish_tx_id) {
+        Ok(ref v) if !v.is_empty() => (v[0].block_num, v[0].trx_id.clone()),
+        _ => (0, String::new()),
+    };
+    if let Err(e) = save_event(
+        conn,
+        &post.publish_tx_id,
+        block_num,
+        &trx_id,
+        post.topic.trim(),
+        event.as_str(),
+        &data.to_string(),
+    ) {
+        error!(
+            "save {} notify for publish_tx_id = {} failed: {}",
+            event.as_str(),
+            post.publish_tx_id,
+            e
+        );
+    }
+}
+
+/// Print dead-letter notifies, with their delivery log, as JSON lines.
+pub fn list_dead_letters(conn: &PgConnection, topic: Option<&str>) -> Result<()> {
+    for notify in db::get_dead_notifies(conn, topic)? {
+        let logs = db::get_webhook_logs(conn, notify.id)?;
+        println!("{}", json!({ "notify": notify, "logs": logs }));
+    }
+    Ok(())
+}
+
+/// Move a dead-letter notify back to the queue and deliver it right away.
+pub fn replay_dead_letter(conn: &PgConnection, notify_id: i32) -> Result<()> {
+    db::reset_notify(conn, notify_id)?;
+    check_and_send_webhook(conn, notify_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::WebhookConf;
+
+    #[test]
+    fn hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn event_subscription() {
+        for event in &[
+            EventType::PostPublished,
+            EventType::PostUpdated,
+            EventType::PostDeleted,
+            EventType::UserAllowed,
+            EventType::UserDenied,
+            EventType::ContentVerificationFailed,
+        ] {
+            assert_eq!(event.as_str().parse::<EventType>().ok(), Some(*event));
+        }
+        assert!("unknown".parse::<EventType>().is_err());
+
+        let mut webhook = WebhookConf {
+            name: String::from("indexer"),
+            url: String::from("http://127.0.0.1:8000/notify"),
+            secret: None,
+            headers: None,
+            events: Some(vec![String::from("post_published")]),
+            include_metadata: None,
+        };
+        assert!(webhook.is_subscribed("post_published"));
+        assert!(!webhook.is_subscribed("post_deleted"));
+        webhook.events = None;
+        assert!(webhook.is_subscribed("post_deleted"));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 4,
+            backoff_secs: 30,
+        };
+        assert_eq!(policy.next_delay(1), Some(chrono::Duration::seconds(30)));
+        assert_eq!(policy.next_delay(2), Some(chrono::Duration::seconds(60)));
+        assert_eq!(policy.next_delay(3), Some(chrono::Duration::seconds(120)));
+        assert_eq!(policy.next_delay(4), None);
+
+        let policy = RetryPolicy {
+            max_retries: 100,
+            backoff_secs: 30,
+        };
+        assert_eq!(
+            policy.next_delay(99),
+            Some(chrono::Duration::seconds(MAX_BACKOFF_SECS))
+        );
+    }
+}